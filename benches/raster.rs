@@ -0,0 +1,111 @@
+//! Times the rasterizer alone and the renders it is part of, compare the two builds with
+//! `cargo bench --bench raster` and `cargo bench --bench raster --features simd`.
+//! `coverage` is `GL::rasterize` over the faces of the head with a plot that only counts,
+//! the renders print the median of the whole render and of its `raster` stage, which
+//! includes the fragment shaders.
+
+use std::time::{Duration, Instant};
+
+use trrs::{
+    assets,
+    camera::CameraOptions,
+    geometry::{embed, Vec3f},
+    gl::GL,
+    post::PostProcess,
+    shaders,
+    shaders::RenderOptions,
+};
+
+const RUNS: usize = 5;
+const SIZE: u32 = 1600;
+
+fn median(mut times: Vec<Duration>) -> Duration {
+    times.sort();
+    times[times.len() / 2]
+}
+
+/// The coverage and depth tests without any shading.
+fn coverage() -> Duration {
+    let model = assets::model("obj/african_head/african_head.obj");
+    let mut gl = GL::new(Vec3f::from([1.0, 1.0, 1.0]), SIZE, SIZE);
+    gl.viewport(0, 0, SIZE as i32, SIZE as i32);
+    gl.lookat(
+        Vec3f::from([0.0, 0.0, 3.0]),
+        Vec3f::from([0.0, 0.0, 0.0]),
+        Vec3f::from([0.0, 1.0, 0.0]),
+    );
+    gl.projection(-1.0 / 3.0);
+    let m = gl.viewport * gl.projection * gl.model_view;
+    let faces: Vec<_> = model
+        .indices
+        .iter()
+        .map(|face| face.map(|i| m * embed::<_, 4, 3>(&model.vertex(i).position, 1.0)))
+        .collect();
+    let mut times = Vec::new();
+    for _ in 0..RUNS {
+        let mut zbuf = gl.depth_buffer();
+        let mut plotted = 0u64;
+        let start = Instant::now();
+        for pts in faces.iter() {
+            gl.rasterize(*pts, &mut zbuf, |_, _, _, _| plotted += 1);
+        }
+        times.push(start.elapsed());
+        std::hint::black_box(plotted);
+    }
+    median(times)
+}
+
+fn main() {
+    let mode = if cfg!(feature = "simd") {
+        "simd"
+    } else {
+        "scalar"
+    };
+    let options = RenderOptions {
+        width: SIZE,
+        height: SIZE,
+        ..RenderOptions::default()
+    };
+    let ssao = PostProcess {
+        ssao: Some(Default::default()),
+        ..PostProcess::default()
+    };
+    println!(
+        "{} rasterizer, {}x{}, median of {} runs",
+        mode, SIZE, SIZE, RUNS
+    );
+    println!(
+        "{:<18} {:>23.1} ms",
+        "coverage",
+        coverage().as_secs_f64() * 1000.0
+    );
+    for (name, post) in [
+        ("gouraud", PostProcess::default()),
+        ("shadowmapping", PostProcess::default()),
+        ("ambientocclusion", ssao),
+    ] {
+        let render = shaders::by_name(name).unwrap();
+        // loads the model into the cache
+        render(post, CameraOptions::default(), &options);
+        let (mut totals, mut rasters) = (Vec::new(), Vec::new());
+        for _ in 0..RUNS {
+            let frame = render(post, CameraOptions::default(), &options);
+            totals.push(frame.stats.stages.iter().map(|(_, t)| *t).sum());
+            rasters.push(
+                frame
+                    .stats
+                    .stages
+                    .iter()
+                    .filter(|(stage, _)| *stage == "raster")
+                    .map(|(_, t)| *t)
+                    .sum(),
+            );
+        }
+        println!(
+            "{:<18} total {:>8.1} ms  raster {:>8.1} ms",
+            name,
+            median(totals).as_secs_f64() * 1000.0,
+            median(rasters).as_secs_f64() * 1000.0
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use std::f64::consts::PI;
+
+use crate::{
+    camera::CameraOptions,
+    post::PostProcess,
+    quat::Quat,
+    shaders::{Frame, RenderFn, RenderOptions},
+    turntable::Turntable,
+};
+
+/// Camera pose of a keyframed animation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keyframe {
+    /// Orbit angles in radians
+    pub azimuth: f64,
+    pub elevation: f64,
+    pub zoom: f64,
+}
+
+impl Keyframe {
+    /// `azimuth,elevation[,zoom]` with the angles in degrees.
+    pub fn parse(s: &str) -> Option<Self> {
+        let v: Vec<f64> = s
+            .split(',')
+            .map(|c| c.trim().parse().ok())
+            .collect::<Option<_>>()?;
+        let (azimuth, elevation, zoom) = match v[..] {
+            [azimuth, elevation] => (azimuth, elevation, 1.0),
+            [azimuth, elevation, zoom] => (azimuth, elevation, zoom),
+            _ => return None,
+        };
+        (v.iter().all(|x| x.is_finite()) && zoom > 0.0).then(|| Self {
+            azimuth: azimuth.to_radians(),
+            elevation: elevation.to_radians(),
+            zoom,
+        })
+    }
+}
+
+/// What moves during an animation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Motion {
+    /// The camera turns once around the model, like `Turntable`
+    Turntable,
+    /// The light turns once around the vertical axis
+    LightOrbit,
+    /// The camera goes through the keyframes at equal intervals and back to the first
+    Keyframes(Vec<Keyframe>),
+}
+
+impl Motion {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "turntable" => Some(Motion::Turntable),
+            "light" => Some(Motion::LightOrbit),
+            _ => None,
+        }
+    }
+}
+
+/// A looping animation of the `/shaders/*` renders.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Animation {
+    pub motion: Motion,
+    /// Seconds per loop
+    pub period: f64,
+}
+
+impl Default for Animation {
+    fn default() -> Self {
+        Self {
+            motion: Motion::Turntable,
+            period: 6.0,
+        }
+    }
+}
+
+impl Animation {
+    /// The camera and render options at `t` seconds, the requested ones are the start.
+    pub fn at(
+        &self,
+        t: f64,
+        camera: CameraOptions,
+        options: &RenderOptions,
+    ) -> (CameraOptions, RenderOptions) {
+        let phase = (t / self.period).rem_euclid(1.0);
+        let mut options = options.clone();
+        let camera = match &self.motion {
+            Motion::Turntable => Turntable::default().camera_at(camera, 2.0 * PI * phase),
+            Motion::LightOrbit => {
+                let turn = Quat::from_euler(0.0, 2.0 * PI * phase, 0.0);
+                options.light_dir = turn.rotate(options.light_dir);
+                camera
+            }
+            Motion::Keyframes(keys) => {
+                let n = keys.len();
+                let (i, s) = (
+                    (phase * n as f64).floor() as usize,
+                    (phase * n as f64).fract(),
+                );
+                let (a, b) = (keys[i % n], keys[(i + 1) % n]);
+                // eased in and out of every keyframe
+                let s = s * s * (3.0 - 2.0 * s);
+                let lerp = |x: f64, y: f64| x + (y - x) * s;
+                CameraOptions {
+                    azimuth: Some(lerp(a.azimuth, b.azimuth)),
+                    elevation: Some(lerp(a.elevation, b.elevation)),
+                    zoom: Some(a.zoom * (b.zoom / a.zoom).powf(s)),
+                    ..camera
+                }
+            }
+        };
+        (camera, options)
+    }
+
+    /// `render` at `t` seconds into the animation.
+    pub fn render(
+        &self,
+        render: RenderFn,
+        t: f64,
+        post: PostProcess,
+        camera: CameraOptions,
+        options: &RenderOptions,
+    ) -> Frame {
+        let (camera, options) = self.at(t, camera, options);
+        render(post, camera, &options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_at() {
+        let options = RenderOptions::default();
+        let camera = CameraOptions::default();
+        let turntable = Animation::default();
+        let (start, _) = turntable.at(0.0, camera, &options);
+        let (half, _) = turntable.at(3.0, camera, &options);
+        let (end, _) = turntable.at(6.0, camera, &options);
+        assert_eq!(start, end);
+        assert_close(half.azimuth.unwrap(), PI);
+
+        let light = Animation {
+            motion: Motion::LightOrbit,
+            period: 4.0,
+        };
+        let (same, quarter) = light.at(1.0, camera, &options);
+        assert_eq!(same, camera);
+        assert_close(quarter.light_dir.norm(), 1.0);
+        assert_close(quarter.light_dir[1], options.light_dir[1]);
+        assert_ne!(quarter.light_dir, options.light_dir);
+
+        let keys = Animation {
+            motion: Motion::Keyframes(vec![
+                Keyframe::parse("0,0").unwrap(),
+                Keyframe::parse("90,30,2").unwrap(),
+            ]),
+            period: 2.0,
+        };
+        let (first, _) = keys.at(0.0, camera, &options);
+        let (second, _) = keys.at(1.0, camera, &options);
+        let (between, _) = keys.at(0.5, camera, &options);
+        assert_eq!(first.zoom, Some(1.0));
+        assert_close(second.azimuth.unwrap(), PI / 2.0);
+        assert_close(second.zoom.unwrap(), 2.0);
+        assert_close(between.elevation.unwrap(), f64::to_radians(15.0));
+        assert_eq!(Keyframe::parse("1"), None);
+        assert_eq!(Keyframe::parse("0,0,0"), None);
+    }
+}
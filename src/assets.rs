@@ -0,0 +1,442 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::Cursor,
+    mem::size_of,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::SystemTime,
+};
+
+use image::ImageFormat;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    geometry::{Vec2f, Vec3f, Vec3i},
+    model::{Model, ModelError, Vertex},
+};
+
+/// Directory holding the uploaded models, one sub directory per asset id.
+pub const UPLOAD_DIR: &str = "uploads";
+/// Disk budget of `UPLOAD_DIR`, the least recently uploaded models are removed past it.
+pub const UPLOAD_QUOTA: u64 = 1 << 30;
+/// Largest width and height of an uploaded texture.
+pub const MAX_TEXTURE: u32 = 8192;
+/// Memory budget of the global cache.
+pub const DEFAULT_CAPACITY: usize = 512 << 20;
+
+/// Texture suffixes looked up by `Model::new`, by the name of the upload field holding them.
+pub const TEXTURES: [(&str, &str, bool); 4] = [
+    ("diffuse", "_diffuse.tga", true),
+    ("nm", "_nm.tga", true),
+    ("spec", "_spec.tga", true),
+    ("glow", "_glow.tga", false),
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    /// Estimated memory held by the cached models
+    pub bytes: usize,
+    pub capacity: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    model: Arc<Model>,
+    bytes: usize,
+    last_used: u64,
+}
+
+/// Parsed models with their decoded textures, least recently used first out once the
+/// estimated size exceeds `capacity`. A model is reloaded when its file or one of its
+/// textures is modified.
+#[derive(Debug)]
+pub struct AssetCache {
+    entries: HashMap<(PathBuf, Option<SystemTime>), Entry>,
+    clock: u64,
+    pub stats: CacheStats,
+}
+
+impl AssetCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            clock: 0,
+            stats: CacheStats {
+                capacity,
+                ..CacheStats::default()
+            },
+        }
+    }
+
+    pub fn get(&mut self, path: &str) -> Option<Arc<Model>> {
+        let key = key(path);
+        self.clock += 1;
+        match self.entries.get_mut(&key) {
+            Some(entry) => {
+                entry.last_used = self.clock;
+                self.stats.hits += 1;
+                Some(Arc::clone(&entry.model))
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches `model` loaded from `path`, evicting the least recently used models to make
+    /// room. Models larger than the whole budget are not kept.
+    pub fn insert(&mut self, path: &str, model: Arc<Model>) {
+        let bytes = model_bytes(&model);
+        if bytes > self.stats.capacity {
+            return;
+        }
+        self.clock += 1;
+        // older versions of the same file are never hit again
+        let path_buf = PathBuf::from(path);
+        self.entries.retain(|(p, _), _| *p != path_buf);
+        while self.size() + bytes > self.stats.capacity {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone())
+            else {
+                break;
+            };
+            self.entries.remove(&oldest);
+            self.stats.evictions += 1;
+        }
+        self.entries.insert(
+            key(path),
+            Entry {
+                model,
+                bytes,
+                last_used: self.clock,
+            },
+        );
+        self.update_stats();
+    }
+
+    /// The cached model of `path`, loading it on a miss.
+    pub fn load(&mut self, path: &str) -> Result<Arc<Model>, ModelError> {
+        if let Some(model) = self.get(path) {
+            return Ok(model);
+        }
+        let model = Arc::new(Model::load(path)?);
+        self.insert(path, Arc::clone(&model));
+        Ok(model)
+    }
+
+    fn size(&self) -> usize {
+        self.entries.values().map(|e| e.bytes).sum()
+    }
+
+    fn update_stats(&mut self) {
+        self.stats.entries = self.entries.len();
+        self.stats.bytes = self.size();
+    }
+}
+
+/// The path and the version of the model.
+fn key(path: &str) -> (PathBuf, Option<SystemTime>) {
+    (PathBuf::from(path), version(path))
+}
+
+/// The latest modification time of the `.obj` file and its textures, `None` when none exist.
+pub fn version(path: &str) -> Option<SystemTime> {
+    let (name, _) = crate::util::splitext(path);
+    std::iter::once(path.to_string())
+        .chain(TEXTURES.iter().map(|(_, suffix, _)| name.clone() + suffix))
+        .filter_map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+        .max()
+}
+
+/// Estimated heap size of a model, dominated by its textures.
+pub fn model_bytes(model: &Model) -> usize {
+    model.verts.len() * size_of::<Vec3f>()
+        + model.norms.len() * size_of::<Vec3f>()
+        + model.uvs.len() * size_of::<Vec2f>()
+        + model
+            .faces
+            .iter()
+            .map(|f| f.len() * size_of::<Vec3i>())
+            .sum::<usize>()
+        + model.vertices.len() * size_of::<Vertex>()
+        + model.indices.len() * size_of::<[usize; 3]>()
+        + model.diffuse_map.len() * size_of::<f32>()
+        + model.normal_map.len()
+        + model.specular_map.len()
+        + model
+            .glow_map
+            .as_ref()
+            .map_or(0, |m| m.len() * size_of::<f32>())
+}
+
+fn cache() -> &'static Mutex<AssetCache> {
+    static CACHE: OnceLock<Mutex<AssetCache>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(AssetCache::new(DEFAULT_CAPACITY)))
+}
+
+/// The model of `path` from the global cache. The lock is not held while parsing, two
+/// requests missing the same model at once both load it.
+pub fn load(path: &str) -> Result<Arc<Model>, ModelError> {
+    if let Some(model) = cache().lock().unwrap().get(path) {
+        return Ok(model);
+    }
+    let model = Arc::new(Model::load(path)?);
+    cache().lock().unwrap().insert(path, Arc::clone(&model));
+    Ok(model)
+}
+
+/// The model of `path` as `load` does, for the bundled models and the paths already loaded
+/// by the caller. Panics when it cannot be loaded.
+pub fn model(path: &str) -> Arc<Model> {
+    load(path).unwrap_or_else(|err| panic!("{}", err))
+}
+
+pub fn stats() -> CacheStats {
+    cache().lock().unwrap().stats
+}
+
+/// Whether `id` looks like an id returned by `store`, so it can go into a path.
+pub fn is_id(id: &str) -> bool {
+    id.len() == 16
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+/// `.obj` file of the uploaded asset `id`.
+pub fn upload_path(id: &str) -> String {
+    format!("{}/{}/model.obj", UPLOAD_DIR, id)
+}
+
+/// One uploaded model: the `.obj` source and its textures by upload field name, see
+/// `TEXTURES`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Upload {
+    pub obj: Vec<u8>,
+    pub textures: HashMap<String, Vec<u8>>,
+}
+
+impl Upload {
+    /// Checks the files and writes them under `dir/<id>/` with the names `Model::new`
+    /// looks for, textures are converted to TGA. The id is derived from the content, so
+    /// uploading the same files again returns the same id. Past `quota` bytes in `dir`, the
+    /// oldest uploads are removed, see `evict`.
+    pub fn store(&self, dir: &Path, quota: u64) -> Result<String, Vec<String>> {
+        let mut errors = Vec::new();
+        if std::str::from_utf8(&self.obj).is_err() {
+            errors.push("obj: not a text file".to_string());
+        } else if !self
+            .obj
+            .split(|&b| b == b'\n')
+            .any(|line| line.starts_with(b"f "))
+        {
+            errors.push("obj: no faces".to_string());
+        }
+        for name in self.textures.keys() {
+            if !TEXTURES.iter().any(|(field, _, _)| field == name) {
+                errors.push(format!("{}: unknown texture", name));
+            }
+        }
+        let mut images = Vec::new();
+        for (field, suffix, required) in TEXTURES {
+            match self.textures.get(field) {
+                Some(bytes) => match decode(bytes) {
+                    Ok(img) => images.push((suffix, img)),
+                    Err(err) => errors.push(format!("{}: {}", field, err)),
+                },
+                None if required => errors.push(format!("{}: missing texture", field)),
+                None => {}
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(&self.obj);
+        for (field, _, _) in TEXTURES {
+            if let Some(bytes) = self.textures.get(field) {
+                hasher.update(field.as_bytes());
+                hasher.update(bytes);
+            }
+        }
+        let id: String = hasher.finalize()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        let target = dir.join(&id);
+        if target.join("model.obj").is_file() {
+            return Ok(id);
+        }
+        let io = |err: std::io::Error| vec![format!("cannot store the upload: {}", err)];
+        fs::create_dir_all(&target).map_err(io)?;
+        for (suffix, img) in images {
+            let mut bs = Vec::new();
+            img.write_to(&mut Cursor::new(&mut bs), ImageFormat::Tga)
+                .map_err(|err| vec![format!("cannot convert a texture: {}", err)])?;
+            fs::write(target.join(format!("model{}", suffix)), bs).map_err(io)?;
+        }
+        // written last, a model is only listed once all its textures are in place
+        let path = target.join("model.obj");
+        fs::write(&path, &self.obj).map_err(io)?;
+        if let Err(err) = Model::load(&path.to_string_lossy()) {
+            fs::remove_dir_all(&target).map_err(io)?;
+            // the path is ours, not the client's
+            return Err(vec![match err {
+                ModelError::Parse { line, message, .. } => {
+                    format!("obj: line {}: {}", line, message)
+                }
+                err => format!("obj: {}", err),
+            }]);
+        }
+        evict(dir, quota, &id).map_err(io)?;
+        Ok(id)
+    }
+}
+
+/// Removes the uploads in `dir` modified least recently until they take at most `quota`
+/// bytes, `keep` excepted. Returns the number of uploads removed.
+pub fn evict(dir: &Path, quota: u64, keep: &str) -> std::io::Result<usize> {
+    let mut uploads = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !is_id(&name) || !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let mut bytes = 0;
+        let mut modified = SystemTime::UNIX_EPOCH;
+        for file in fs::read_dir(entry.path())? {
+            let metadata = file?.metadata()?;
+            bytes += metadata.len();
+            modified = modified.max(metadata.modified()?);
+        }
+        uploads.push((modified, bytes, name));
+    }
+    let mut total: u64 = uploads.iter().map(|(_, bytes, _)| bytes).sum();
+    uploads.sort();
+    let mut removed = 0;
+    for (_, bytes, name) in uploads {
+        if total <= quota {
+            break;
+        }
+        if name != keep {
+            fs::remove_dir_all(dir.join(&name))?;
+            total -= bytes;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// TGA, the format of the bundled textures, has no signature to be recognized by. The
+/// size is bounded by `MAX_TEXTURE` before anything is allocated.
+fn decode(bytes: &[u8]) -> image::ImageResult<image::DynamicImage> {
+    let format = image::guess_format(bytes).unwrap_or(ImageFormat::Tga);
+    let mut reader = image::io::Reader::with_format(Cursor::new(bytes), format);
+    let mut limits = image::io::Limits::default();
+    limits.max_image_width = Some(MAX_TEXTURE);
+    limits.max_image_height = Some(MAX_TEXTURE);
+    limits.max_alloc = Some(4 * MAX_TEXTURE as u64 * MAX_TEXTURE as u64);
+    reader.limits(limits);
+    reader.decode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEAD: &str = "obj/african_head/african_head.obj";
+    const EYES: &str = "obj/african_head/african_head_eye_inner.obj";
+
+    #[test]
+    fn test_cache() {
+        let mut cache = AssetCache::new(usize::MAX);
+        let head = cache.load(HEAD).unwrap();
+        assert!(Arc::ptr_eq(&head, &cache.load(HEAD).unwrap()));
+        let eyes = cache.load(EYES).unwrap();
+        assert_eq!(
+            (cache.stats.hits, cache.stats.misses, cache.stats.entries),
+            (1, 2, 2)
+        );
+        assert_eq!(cache.stats.bytes, model_bytes(&head) + model_bytes(&eyes));
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let eyes = Arc::new(Model::new(EYES));
+        // room for two copies
+        let mut cache = AssetCache::new(2 * model_bytes(&eyes));
+        cache.insert("a.obj", Arc::clone(&eyes));
+        cache.insert("b.obj", Arc::clone(&eyes));
+        assert_eq!(cache.stats.evictions, 0);
+        cache.get("a.obj");
+        cache.insert("c.obj", Arc::clone(&eyes));
+        // b was used last before a
+        assert_eq!(cache.stats.evictions, 1);
+        assert!(cache.get("b.obj").is_none());
+        assert!(cache.get("a.obj").is_some());
+        // larger than the whole budget
+        let mut small = AssetCache::new(1);
+        small.insert(EYES, eyes);
+        assert_eq!(small.stats.entries, 0);
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = std::env::temp_dir().join(format!("trrs-assets-{}", std::process::id()));
+        let read = |suffix: &str| fs::read(format!("obj/african_head/african_head{}", suffix));
+        let mut upload = Upload {
+            obj: read(".obj").unwrap(),
+            textures: HashMap::new(),
+        };
+        upload
+            .textures
+            .insert("diffuse".to_string(), read("_diffuse.tga").unwrap());
+        upload
+            .textures
+            .insert("bump".to_string(), read("_nm.tga").unwrap());
+        assert_eq!(
+            upload.store(&dir, UPLOAD_QUOTA),
+            Err(vec![
+                "bump: unknown texture".to_string(),
+                "nm: missing texture".to_string(),
+                "spec: missing texture".to_string(),
+            ])
+        );
+        let bump = upload.textures.remove("bump").unwrap();
+        upload.textures.insert("nm".to_string(), bump);
+        upload
+            .textures
+            .insert("spec".to_string(), read("_spec.tga").unwrap());
+        let id = upload.store(&dir, UPLOAD_QUOTA).unwrap();
+        assert!(is_id(&id));
+        assert_eq!(upload.store(&dir, UPLOAD_QUOTA), Ok(id.clone()));
+        let model = Model::new(dir.join(&id).join("model.obj").to_str().unwrap());
+        assert_eq!(model.nfaces(), Model::new(HEAD).nfaces());
+        let obj = upload.obj.clone();
+        upload.obj = b"v 1 2\nf 1/1/1 1/1/1 1/1/1\n".to_vec();
+        assert_eq!(
+            upload.store(&dir, UPLOAD_QUOTA),
+            Err(vec!["obj: line 1: missing number".to_string()])
+        );
+        // a second upload past the quota removes the first one
+        upload.obj = obj;
+        upload.obj.extend_from_slice(b"\n# copy\n");
+        let copy = upload.store(&dir, 1).unwrap();
+        assert!(!dir.join(&id).exists());
+        assert!(dir.join(&copy).join("model.obj").is_file());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
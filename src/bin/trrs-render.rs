@@ -0,0 +1,105 @@
+use std::{fs, process::ExitCode};
+
+use trrs::{
+    assets,
+    output::{Format, Output},
+    params::{camera_options, from_args, post_process, render_options},
+    shaders,
+};
+
+const USAGE: &str = "\
+Usage: trrs-render --shader <name> --out <file> [options]
+
+Renders one image with the renders behind /shaders/<name>, without the server.
+
+Options:
+    --shader <name>      one of the shader names below
+    --out <file>         output file, the format follows the extension: png, jpg, webp, tga,
+                         ppm, exr (linear color and depth), f32 (raw depth) or any other
+                         image format the `image` crate writes
+    --format <name>      png, jpeg, webp, tga, ppm, exr or depth whatever the extension
+    --quality <1-100>    JPEG quality, default 90
+    --model <file.obj>   model to render instead of the default one of the shader
+    --width <px>         image width, default 800
+    --height <px>        image height, default 800
+    --light <x,y,z>      light direction, default 1,1,1
+    --help               print this message
+
+Camera and post processing take the query parameters of the server as options, e.g.
+    --view isometric --projection perspective --azimuth 30 --elevation 15 --fit
+    --tonemap aces --exposure 1.5 --bloom --ssao
+";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let params = match from_args(&args) {
+        Ok(params) => params,
+        Err(err) => return usage_error(&err),
+    };
+    if params.contains_key("help") {
+        print!("{}", usage());
+        return ExitCode::SUCCESS;
+    }
+    let Some(name) = params.get("shader") else {
+        return usage_error("missing --shader");
+    };
+    let Some(render) = shaders::by_name(name) else {
+        return usage_error(&format!(
+            "unknown shader `{}`, expected one of {}",
+            name,
+            shaders::names().join(", ")
+        ));
+    };
+    let Some(out) = params.get("out") else {
+        return usage_error("missing --out");
+    };
+    // `--format` wins over the extension, unknown extensions are left to the `image` crate
+    let format = params
+        .get("format")
+        .cloned()
+        .or_else(|| Format::from_path(out).map(|f| f.name().to_string()));
+    let output = match format
+        .as_deref()
+        .map(|f| Output::negotiate(Some(f), params.get("quality").map(String::as_str), None))
+        .transpose()
+    {
+        Ok(output) => output,
+        Err(err) => return usage_error(&err.to_string()),
+    };
+    let camera = match camera_options(&params) {
+        Ok(camera) => camera,
+        Err(err) => return usage_error(&err),
+    };
+    let post = match post_process(&params) {
+        Ok(post) => post,
+        Err(err) => return usage_error(&err),
+    };
+    let options = render_options(&params, true);
+    // loaded into the cache the render reads it from, with its textures
+    if let Some(model) = &options.model {
+        if let Err(err) = assets::load(model) {
+            eprintln!("trrs-render: cannot load the model: {}", err);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let frame = render(post, camera, &options);
+    let written = match output {
+        Some(output) => fs::write(out, output.encode(&frame)).map_err(|e| e.to_string()),
+        None => frame.image.save(out).map_err(|e| e.to_string()),
+    };
+    if let Err(err) = written {
+        eprintln!("trrs-render: cannot write `{}`: {}", out, err);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+fn usage() -> String {
+    format!("{}\nShaders: {}\n", USAGE, shaders::names().join(", "))
+}
+
+fn usage_error(message: &str) -> ExitCode {
+    eprintln!("trrs-render: {}\n\n{}", message, usage());
+    ExitCode::from(2)
+}
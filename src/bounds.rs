@@ -0,0 +1,104 @@
+use crate::{
+    geometry::Vec3f,
+    util::{maxf, minf},
+};
+
+/// Axis-aligned bounding box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3f,
+    pub max: Vec3f,
+}
+
+impl Aabb {
+    /// The smallest box holding all `points`, inverted (min > max) when there are none.
+    pub fn from_points<'a>(points: impl IntoIterator<Item = &'a Vec3f>) -> Self {
+        let mut min = Vec3f::from([f64::MAX; 3]);
+        let mut max = Vec3f::from([f64::MIN; 3]);
+        for p in points {
+            for i in 0..3 {
+                min[i] = minf(min[i], p[i]);
+                max[i] = maxf(max[i], p[i]);
+            }
+        }
+        Self { min, max }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..3).any(|i| self.min[i] > self.max[i])
+    }
+
+    pub fn center(&self) -> Vec3f {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn size(&self) -> Vec3f {
+        self.max - self.min
+    }
+
+    pub fn corners(&self) -> [Vec3f; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Vec3f::from([a[0], a[1], a[2]]),
+            Vec3f::from([b[0], a[1], a[2]]),
+            Vec3f::from([a[0], b[1], a[2]]),
+            Vec3f::from([b[0], b[1], a[2]]),
+            Vec3f::from([a[0], a[1], b[2]]),
+            Vec3f::from([b[0], a[1], b[2]]),
+            Vec3f::from([a[0], b[1], b[2]]),
+            Vec3f::from([b[0], b[1], b[2]]),
+        ]
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::from_points(&[self.min, self.max, other.min, other.max])
+    }
+}
+
+/// Bounding sphere, not the minimal one but centered on the box so it is cheap and stable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sphere {
+    pub center: Vec3f,
+    pub radius: f64,
+}
+
+impl Sphere {
+    pub fn from_points<'a>(points: impl IntoIterator<Item = &'a Vec3f> + Clone) -> Self {
+        let center = Aabb::from_points(points.clone()).center();
+        let radius = points
+            .into_iter()
+            .fold(0.0, |r, p| maxf(r, (*p - center).norm()));
+        Self { center, radius }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aabb() {
+        let pts = [
+            Vec3f::from([1.0, -2.0, 0.0]),
+            Vec3f::from([3.0, 2.0, 1.0]),
+            Vec3f::from([2.0, 0.0, -1.0]),
+        ];
+        let aabb = Aabb::from_points(&pts);
+        assert_eq!(aabb.min, Vec3f::from([1.0, -2.0, -1.0]));
+        assert_eq!(aabb.max, Vec3f::from([3.0, 2.0, 1.0]));
+        assert_eq!(aabb.center(), Vec3f::from([2.0, 0.0, 0.0]));
+        assert!(aabb
+            .corners()
+            .iter()
+            .all(|c| (0..3).all(|i| c[i] >= aabb.min[i] && c[i] <= aabb.max[i])));
+        assert!(Aabb::from_points(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_sphere() {
+        let pts = [Vec3f::from([-1.0, 0.0, 0.0]), Vec3f::from([3.0, 0.0, 0.0])];
+        let sphere = Sphere::from_points(&pts);
+        assert_eq!(sphere.center, Vec3f::from([1.0, 0.0, 0.0]));
+        assert_eq!(sphere.radius, 2.0);
+    }
+}
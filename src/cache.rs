@@ -0,0 +1,525 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::assets;
+
+/// Memory budget of the encoded renders.
+pub const DEFAULT_MEMORY: usize = 256 << 20;
+/// Disk budget, when a cache directory is configured.
+pub const DEFAULT_DISK: usize = 1 << 30;
+/// `max-age` of the cached responses in seconds, browsers revalidate with the ETag after it.
+pub const DEFAULT_MAX_AGE: u32 = 3600;
+
+/// Limits of the render cache, see `config::Config`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheConfig {
+    /// Bytes of encoded renders kept in memory, 0 keeps none
+    pub memory: usize,
+    /// Directory of the second tier, `None` keeps the renders in memory only
+    pub dir: Option<PathBuf>,
+    /// Bytes of encoded renders kept in `dir`
+    pub disk: usize,
+    pub max_age: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            memory: DEFAULT_MEMORY,
+            dir: None,
+            disk: DEFAULT_DISK,
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+}
+
+/// Content address of a render: a hash of the route, of everything its bytes depend on and
+/// of the versions of the models it reads. The bundled models of the renders are not listed,
+/// they do not change while the server runs.
+pub fn key(route: &str, inputs: &impl Debug, models: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    hasher.update(route);
+    hasher.update(format!("{:?}", inputs));
+    for model in models {
+        hasher.update(model);
+        hasher.update(format!("{:?}", assets::version(model)));
+    }
+    hasher.finalize()[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The strong ETag of the render addressed by `key`, identical renders are identical bytes.
+pub fn etag(key: &str) -> String {
+    format!("\"{}\"", key)
+}
+
+/// Whether an `If-None-Match` header names `etag`.
+pub fn none_match(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// An encoded render and the headers it is served with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cached {
+    pub content_type: String,
+    /// Headers describing the render, like `x-cull-stats`
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Cached {
+    pub fn new(content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            content_type: content_type.to_string(),
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    /// The content type and the headers as `name: value` lines, an empty line, then the body.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bs = format!("{}\n", self.content_type).into_bytes();
+        for (name, value) in &self.headers {
+            bs.extend(format!("{}: {}\n", name, value).into_bytes());
+        }
+        bs.push(b'\n');
+        bs.extend_from_slice(&self.body);
+        bs
+    }
+
+    fn from_bytes(bs: &[u8]) -> Option<Self> {
+        let end = bs.windows(2).position(|w| w == b"\n\n")?;
+        let head = std::str::from_utf8(&bs[..end]).ok()?;
+        let mut lines = head.lines();
+        let content_type = lines.next()?.to_string();
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_once(": ")?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            content_type,
+            headers,
+            body: bs[end + 2..].to_vec(),
+        })
+    }
+
+    fn bytes(&self) -> usize {
+        self.body.len()
+            + self.content_type.len()
+            + self
+                .headers
+                .iter()
+                .map(|(n, v)| n.len() + v.len())
+                .sum::<usize>()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    /// Misses of the memory found on disk
+    pub disk_hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub disk_evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+    pub capacity: usize,
+    pub disk_entries: usize,
+    pub disk_bytes: usize,
+    pub disk_capacity: usize,
+}
+
+/// Where `RenderCache::lookup` found a render.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lookup {
+    Memory(Arc<Cached>),
+    /// The file holding it, to read without holding the cache
+    Disk(PathBuf),
+    Miss,
+}
+
+/// The file operations of `RenderCache::stage`, done without holding the cache.
+#[derive(Debug)]
+pub struct DiskWrite {
+    path: PathBuf,
+    bytes: Vec<u8>,
+    /// Files of the renders evicted to make room
+    evicted: Vec<PathBuf>,
+}
+
+impl DiskWrite {
+    pub fn apply(self) -> std::io::Result<()> {
+        for old in &self.evicted {
+            let _ = fs::remove_file(old);
+        }
+        // renamed into place, a concurrent reader never sees half a file
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, &self.bytes).and_then(|_| fs::rename(&tmp, &self.path))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    bytes: usize,
+    last_used: u64,
+}
+
+/// Encoded renders by `key`, in memory and optionally on disk, least recently used first out
+/// of each once it exceeds its budget. A render evicted from memory is still served from disk.
+#[derive(Debug)]
+pub struct RenderCache {
+    memory: HashMap<String, (Arc<Cached>, Slot)>,
+    /// Files of the disk tier, named by their key
+    disk: HashMap<String, Slot>,
+    config: CacheConfig,
+    clock: u64,
+    pub stats: CacheStats,
+}
+
+impl RenderCache {
+    /// A cache with the limits of `config`, the renders already in its directory are kept.
+    pub fn new(config: CacheConfig) -> Self {
+        let mut disk = HashMap::new();
+        if let Some(dir) = &config.dir {
+            let _ = fs::create_dir_all(dir);
+            for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if is_key(&name) && metadata.is_file() {
+                    let bytes = metadata.len() as usize;
+                    disk.insert(
+                        name,
+                        Slot {
+                            bytes,
+                            last_used: 0,
+                        },
+                    );
+                }
+            }
+        }
+        let mut cache = Self {
+            memory: HashMap::new(),
+            disk,
+            stats: CacheStats {
+                capacity: config.memory,
+                disk_capacity: if config.dir.is_some() { config.disk } else { 0 },
+                ..CacheStats::default()
+            },
+            config,
+            clock: 0,
+        };
+        cache.update_stats();
+        cache
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<Arc<Cached>> {
+        match self.lookup(key) {
+            Lookup::Memory(value) => Some(value),
+            Lookup::Disk(path) => {
+                let value = fs::read(path).ok().and_then(|bs| Cached::from_bytes(&bs));
+                self.read(key, value)
+            }
+            Lookup::Miss => None,
+        }
+    }
+
+    /// Where the render of `key` is, without reading the disk. A render on disk is counted
+    /// once its file is `read`.
+    pub fn lookup(&mut self, key: &str) -> Lookup {
+        self.clock += 1;
+        if let Some((value, slot)) = self.memory.get_mut(key) {
+            slot.last_used = self.clock;
+            self.stats.hits += 1;
+            return Lookup::Memory(Arc::clone(value));
+        }
+        match self
+            .disk
+            .contains_key(key)
+            .then(|| self.path(key))
+            .flatten()
+        {
+            Some(path) => Lookup::Disk(path),
+            None => {
+                self.stats.misses += 1;
+                Lookup::Miss
+            }
+        }
+    }
+
+    /// Counts the `value` read from the file of a `Lookup::Disk`, `None` when it was gone or
+    /// damaged, and keeps it in memory.
+    pub fn read(&mut self, key: &str, value: Option<Cached>) -> Option<Arc<Cached>> {
+        let Some(value) = value else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.disk_hits += 1;
+        let value = Arc::new(value);
+        if let Some(slot) = self.disk.get_mut(key) {
+            slot.last_used = self.clock;
+        }
+        self.insert_memory(key, Arc::clone(&value));
+        self.update_stats();
+        Some(value)
+    }
+
+    /// Caches `value` in memory and on disk, evicting the least recently used renders to
+    /// make room. A render larger than the budget of a tier is not kept there.
+    pub fn insert(&mut self, key: &str, value: Arc<Cached>) {
+        if let Some(write) = self.stage(key, value) {
+            if write.apply().is_err() {
+                self.forget(key);
+            }
+        }
+    }
+
+    /// `insert` without touching the disk: the render is in memory and in the index of the
+    /// disk tier, the files are written and removed by `DiskWrite::apply`.
+    pub fn stage(&mut self, key: &str, value: Arc<Cached>) -> Option<DiskWrite> {
+        self.clock += 1;
+        let mut write = None;
+        if let Some(path) = self.path(key) {
+            let bytes = value.to_bytes();
+            if bytes.len() <= self.stats.disk_capacity {
+                let evicted = make_room(
+                    &mut self.disk,
+                    |s| *s,
+                    bytes.len(),
+                    self.stats.disk_capacity,
+                );
+                self.stats.disk_evictions += evicted.len() as u64;
+                let slot = Slot {
+                    bytes: bytes.len(),
+                    last_used: self.clock,
+                };
+                self.disk.insert(key.to_string(), slot);
+                write = Some(DiskWrite {
+                    path,
+                    bytes,
+                    evicted: evicted.iter().filter_map(|old| self.path(old)).collect(),
+                });
+            }
+        }
+        self.insert_memory(key, value);
+        self.update_stats();
+        write
+    }
+
+    /// Drops `key` from the disk tier, after its `DiskWrite` failed.
+    pub fn forget(&mut self, key: &str) {
+        self.disk.remove(key);
+        self.update_stats();
+    }
+
+    fn insert_memory(&mut self, key: &str, value: Arc<Cached>) {
+        let bytes = value.bytes();
+        if bytes > self.stats.capacity {
+            return;
+        }
+        self.memory.remove(key);
+        let evicted = make_room(&mut self.memory, |(_, s)| *s, bytes, self.stats.capacity);
+        self.stats.evictions += evicted.len() as u64;
+        let slot = Slot {
+            bytes,
+            last_used: self.clock,
+        };
+        self.memory.insert(key.to_string(), (value, slot));
+    }
+
+    /// File of `key` in the cache directory, keys are checked so they cannot leave it.
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        let dir = self.config.dir.as_ref()?;
+        is_key(key).then(|| dir.join(key))
+    }
+
+    fn update_stats(&mut self) {
+        self.stats.entries = self.memory.len();
+        self.stats.bytes = self.memory.values().map(|(_, s)| s.bytes).sum();
+        self.stats.disk_entries = self.disk.len();
+        self.stats.disk_bytes = self.disk.values().map(|s| s.bytes).sum();
+    }
+}
+
+/// Whether `s` looks like a key returned by `key`.
+fn is_key(s: &str) -> bool {
+    s.len() == 32
+        && s.bytes()
+            .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+/// Removes the least recently used entries until `bytes` more fit in `capacity`, returns
+/// their keys.
+fn make_room<T>(
+    entries: &mut HashMap<String, T>,
+    slot: fn(&T) -> Slot,
+    bytes: usize,
+    capacity: usize,
+) -> Vec<String> {
+    let mut size: usize = entries.values().map(|e| slot(e).bytes).sum();
+    let mut evicted = Vec::new();
+    while size + bytes > capacity {
+        let Some(oldest) = entries
+            .iter()
+            .min_by_key(|(_, e)| slot(e).last_used)
+            .map(|(k, _)| k.clone())
+        else {
+            break;
+        };
+        size -= slot(&entries.remove(&oldest).unwrap()).bytes;
+        evicted.push(oldest);
+    }
+    evicted
+}
+
+static CACHE: OnceLock<Mutex<RenderCache>> = OnceLock::new();
+
+fn cache() -> &'static Mutex<RenderCache> {
+    CACHE.get_or_init(|| Mutex::new(RenderCache::new(CacheConfig::default())))
+}
+
+/// Sets the limits of the global cache, before its first use.
+pub fn init(config: CacheConfig) -> Result<(), String> {
+    CACHE
+        .set(Mutex::new(RenderCache::new(config)))
+        .map_err(|_| "the render cache is already in use".to_string())
+}
+
+/// Where the render of `key` is, a render on disk is then `read` off the async runtime.
+pub fn lookup(key: &str) -> Lookup {
+    cache().lock().unwrap().lookup(key)
+}
+
+/// Reads the render of `key` from the `path` of a `Lookup::Disk`, the cache is not locked
+/// meanwhile.
+pub fn read(key: &str, path: &std::path::Path) -> Option<Arc<Cached>> {
+    let value = fs::read(path).ok().and_then(|bs| Cached::from_bytes(&bs));
+    cache().lock().unwrap().read(key, value)
+}
+
+/// Caches `value`, the file of the disk tier is written once the cache is unlocked.
+pub fn insert(key: &str, value: Arc<Cached>) {
+    let write = cache().lock().unwrap().stage(key, value);
+    if let Some(write) = write {
+        if let Err(err) = write.apply() {
+            log::warn!("cannot write the render cache: {}", err);
+            cache().lock().unwrap().forget(key);
+        }
+    }
+}
+
+pub fn max_age() -> u32 {
+    cache().lock().unwrap().config.max_age
+}
+
+pub fn stats() -> CacheStats {
+    cache().lock().unwrap().stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        let head = "obj/african_head/african_head.obj";
+        let a = key("/shaders/texture", &(800, 600), &[]);
+        assert!(is_key(&a));
+        assert_eq!(a, key("/shaders/texture", &(800, 600), &[]));
+        assert_ne!(a, key("/shaders/texture", &(800, 601), &[]));
+        assert_ne!(a, key("/shaders/gouraud", &(800, 600), &[]));
+        assert_ne!(a, key("/shaders/texture", &(800, 600), &[head]));
+
+        let etag = etag(&a);
+        assert!(none_match(&etag, &etag));
+        assert!(none_match(&format!("\"x\", W/{}", etag), &etag));
+        assert!(none_match("*", &etag));
+        assert!(!none_match("\"x\"", &etag));
+    }
+
+    #[test]
+    fn test_cache() {
+        let render = |n: usize| Arc::new(Cached::new("image/png", vec![0; n]));
+        let keys: Vec<String> = (0..4).map(|i| key("test", &i, &[])).collect();
+        let size = render(100).bytes();
+        let mut cache = RenderCache::new(CacheConfig {
+            memory: 2 * size,
+            ..CacheConfig::default()
+        });
+        cache.insert(&keys[0], render(100));
+        cache.insert(&keys[1], render(100));
+        cache.get(&keys[0]);
+        cache.insert(&keys[2], render(100));
+        // 1 was used last before 0
+        assert!(cache.get(&keys[1]).is_none());
+        assert_eq!(cache.get(&keys[0]), Some(render(100)));
+        assert_eq!(
+            (cache.stats.hits, cache.stats.misses, cache.stats.evictions),
+            (2, 1, 1)
+        );
+        assert_eq!((cache.stats.entries, cache.stats.bytes), (2, 2 * size));
+    }
+
+    #[test]
+    fn test_disk() {
+        let dir = std::env::temp_dir().join(format!("trrs-cache-{}", std::process::id()));
+        let config = CacheConfig {
+            memory: 0,
+            dir: Some(dir.clone()),
+            ..CacheConfig::default()
+        };
+        let mut value = Cached::new("image/png", vec![1, 2, 10, 10, 3]);
+        value
+            .headers
+            .push(("x-cull-stats".to_string(), "a=1".to_string()));
+        let a = key("test", &0, &[]);
+        let mut cache = RenderCache::new(config.clone());
+        cache.insert(&a, Arc::new(value.clone()));
+        assert_eq!(cache.stats.entries, 0);
+        assert_eq!(cache.get(&a).as_deref(), Some(&value));
+
+        // kept across restarts, and evicted once over the budget
+        let mut cache = RenderCache::new(CacheConfig {
+            disk: value.to_bytes().len(),
+            ..config
+        });
+        assert_eq!(cache.stats.disk_entries, 1);
+        assert_eq!(cache.lookup(&a), Lookup::Disk(dir.join(&a)));
+        // a file gone in between is a miss
+        assert_eq!(cache.read(&a, None), None);
+        assert_eq!(cache.get(&a).as_deref(), Some(&value));
+        assert_eq!((cache.stats.disk_hits, cache.stats.misses), (1, 1));
+        let b = key("test", &1, &[]);
+        // the index changes at once, the files once the write is applied
+        let write = cache.stage(&b, Arc::new(value.clone())).unwrap();
+        assert_eq!(cache.stats.disk_evictions, 1);
+        assert!(dir.join(&a).exists() && !dir.join(&b).exists());
+        write.apply().unwrap();
+        assert!(dir.join(&b).exists());
+        assert!(cache.get(&a).is_none());
+        assert!(!dir.join(&a).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
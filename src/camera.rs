@@ -1,17 +1,41 @@
-use std::{io::Cursor, mem::swap, path};
+use std::{io::Cursor, mem::swap};
 
 use image::{ImageBuffer, Rgba, RgbaImage};
 
 use crate::{
-    geometry::{Vec3f, Vec3i},
-    matrix::Mat,
+    bounds::Aabb,
+    geometry::{embed, Vec3f, Vec3i},
+    gl::GL,
+    matrix::Mat4f,
     model::Model,
-    util::RgbaImageExt,
+    util::{maxf, minf, RgbaImageExt},
 };
 
 const DEPTH: f64 = 255.0;
+/// Vertical field of view of the perspective views, in degrees
+const FOVY: f64 = 34.0;
 
 pub fn move_camera() -> Vec<u8> {
+    let (mut img, _) = move_camera_images();
+    let mut bs: Vec<u8> = Vec::new();
+    img.flip_vertical();
+    img.write_to(&mut Cursor::new(&mut bs), image::ImageOutputFormat::Png)
+        .unwrap();
+    bs
+}
+
+/// The depth buffer of `move_camera` as a grayscale image.
+pub fn move_camera_zbuf() -> Vec<u8> {
+    let (_, mut zimg) = move_camera_images();
+    let mut bs: Vec<u8> = Vec::new();
+    zimg.flip_vertical();
+    zimg.write_to(&mut Cursor::new(&mut bs), image::ImageOutputFormat::Png)
+        .unwrap();
+    bs
+}
+
+/// Returns the shaded image and its depth buffer, both bottom-up.
+fn move_camera_images() -> (RgbaImage, RgbaImage) {
     const W: u32 = 800;
     const H: u32 = 800;
 
@@ -28,19 +52,18 @@ pub fn move_camera() -> Vec<u8> {
     let mut zbuf: Vec<f64> = vec![std::f64::MIN; (W * H) as usize];
 
     let model_view = lookat(eye, center, up);
-    let mut projection: Mat<f64> = Mat::identity(4);
+    let projection = projection(-1.0 / (eye - center).norm());
     let viewport = viewport(
         W as i32 / 8,
         H as i32 / 8,
         W as i32 * 3 / 4,
         H as i32 * 3 / 4,
     );
-    projection[3][2] = -1.0 / (eye - center).norm();
 
-    println!("ModelView:\n {:}", model_view);
-    println!("Viewport:\n {:}", viewport);
-    println!("Projection:\n {:}", projection);
-    println!("Z:\n {:}", &(&viewport * &projection) * &model_view);
+    log::debug!("ModelView:\n {:}", model_view);
+    log::debug!("Viewport:\n {:}", viewport);
+    log::debug!("Projection:\n {:}", projection);
+    log::debug!("Z:\n {:}", viewport * projection * model_view);
 
     for i in 0..model.nfaces() {
         let face = model.face(i);
@@ -49,8 +72,7 @@ pub fn move_camera() -> Vec<u8> {
         let mut intensities = Vec::new();
         for j in 0..3 {
             let v = model.vert(face[j] as usize);
-            let vv: Vec<f64> = vec![v[0], v[1], v[2], 1.0];
-            let p: Vec<f64> = &(&(&viewport * &projection) * &model_view) * &vv;
+            let p = viewport * projection * model_view * embed::<_, 4, 3>(v, 1.0);
             screen_coords.push(Vec3f::from([p[0] / p[3], p[1] / p[3], p[2] / p[3]]).to_vec3i());
             world_coords.push(v);
             intensities.push(model.norm(i, j).dot(&light_dir));
@@ -74,32 +96,490 @@ pub fn move_camera() -> Vec<u8> {
             zimg.put_pixel(i, j, Rgba::from([c as u8, c as u8, c as u8, 255]));
         }
     }
-    zimg.flip_vertical();
-    zimg.save(path::Path::new("zbuf.png")).unwrap();
-
-    let mut bs: Vec<u8> = Vec::new();
-    img.flip_vertical();
-    img.write_to(&mut Cursor::new(&mut bs), image::ImageOutputFormat::Png)
-        .unwrap();
-    bs
+    (img, zimg)
 }
 
-pub fn lookat(eye: Vec3f, center: Vec3f, up: Vec3f) -> Mat<f64> {
+/// View matrix with the eye at the origin looking down -z.
+pub fn lookat(eye: Vec3f, center: Vec3f, up: Vec3f) -> Mat4f {
     let z = (eye - center).normalize();
     let x = (up ^ z).normalize();
     let y = (z ^ x).normalize();
-    let mut res: Mat<f64> = Mat::identity(4);
+    let mut res: Mat4f = Mat4f::identity();
     for i in 0..3 {
         res[0][i] = x[i];
         res[1][i] = y[i];
         res[2][i] = z[i];
-        res[i][3] = -center[i];
     }
+    res[0][3] = -x.dot(&eye);
+    res[1][3] = -y.dot(&eye);
+    res[2][3] = -z.dot(&eye);
     res
 }
 
-pub fn viewport(x: i32, y: i32, w: i32, h: i32) -> Mat<f64> {
-    let mut res: Mat<f64> = Mat::identity(4);
+/// The tinyrenderer projection, `coeff = -1/c` for a camera `c` units away from the look-at
+/// center. Closer points get a larger depth and nothing is clipped.
+pub fn projection(coeff: f64) -> Mat4f {
+    let mut res: Mat4f = Mat4f::identity();
+    // shift the center back to the origin, then divide by 1 + coeff * z
+    res[2][3] = -1.0 / coeff;
+    res[3][2] = coeff;
+    res[3][3] = 0.0;
+    res
+}
+
+/// Symmetric perspective, `fovy` is the vertical field of view in radians.
+pub fn perspective(fovy: f64, aspect: f64, near: f64, far: f64) -> Mat4f {
+    let top = near * (fovy / 2.0).tan();
+    let right = top * aspect;
+    frustum(-right, right, -top, top, near, far)
+}
+
+/// Off-center perspective, the edges are given on the near plane. Maps the near plane to
+/// NDC z = -1 and the far plane to 1.
+pub fn frustum(left: f64, right: f64, bottom: f64, top: f64, near: f64, far: f64) -> Mat4f {
+    Mat4f::from([
+        [
+            2.0 * near / (right - left),
+            0.0,
+            (right + left) / (right - left),
+            0.0,
+        ],
+        [
+            0.0,
+            2.0 * near / (top - bottom),
+            (top + bottom) / (top - bottom),
+            0.0,
+        ],
+        [
+            0.0,
+            0.0,
+            -(far + near) / (far - near),
+            -2.0 * far * near / (far - near),
+        ],
+        [0.0, 0.0, -1.0, 0.0],
+    ])
+}
+
+/// Parallel projection of the box, same depth range as `frustum`.
+pub fn orthographic(left: f64, right: f64, bottom: f64, top: f64, near: f64, far: f64) -> Mat4f {
+    Mat4f::from([
+        [
+            2.0 / (right - left),
+            0.0,
+            0.0,
+            -(right + left) / (right - left),
+        ],
+        [
+            0.0,
+            2.0 / (top - bottom),
+            0.0,
+            -(top + bottom) / (top - bottom),
+        ],
+        [0.0, 0.0, -2.0 / (far - near), -(far + near) / (far - near)],
+        [0.0, 0.0, 0.0, 1.0],
+    ])
+}
+
+/// Flip the NDC depth of `projection`, the near plane ends up at 1 and the far plane at 0.
+pub fn reverse_z(projection: &Mat4f) -> Mat4f {
+    let mut res = *projection;
+    for v in res[2].iter_mut() {
+        *v = -*v;
+    }
+    res
+}
+
+/// Shear for an oblique parallel projection, multiply `orthographic` by it. Lines going
+/// away from the viewer are drawn at `angle` (radians) and shortened by `depth_scale`;
+/// the plane `focus` units in front of the eye is left untouched.
+pub fn oblique(angle: f64, depth_scale: f64, focus: f64) -> Mat4f {
+    let (kx, ky) = (depth_scale * angle.cos(), depth_scale * angle.sin());
+    let mut res: Mat4f = Mat4f::identity();
+    // depth behind the focus plane is -(z + focus)
+    res[0][2] = -kx;
+    res[0][3] = -kx * focus;
+    res[1][2] = -ky;
+    res[1][3] = -ky * focus;
+    res
+}
+
+/// How the view volume is mapped to the screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// `fovy` is the vertical field of view in radians
+    Perspective { fovy: f64 },
+    /// `half_height` world units between the view center and the top edge, at any depth
+    Orthographic { half_height: f64 },
+    /// Orthographic with receding edges drawn at `angle` and shortened by `depth_scale`
+    Oblique {
+        half_height: f64,
+        angle: f64,
+        depth_scale: f64,
+    },
+}
+
+impl Projection {
+    /// The names of `from_name`, without the aliases.
+    pub const NAMES: [&'static str; 4] = ["perspective", "orthographic", "cabinet", "cavalier"];
+
+    /// `perspective`, `orthographic` (or `ortho`), `cabinet` (or `oblique`) and `cavalier`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let oblique = |depth_scale| Projection::Oblique {
+            half_height: 1.0,
+            angle: f64::to_radians(45.0),
+            depth_scale,
+        };
+        match name {
+            "perspective" => Some(Projection::Perspective {
+                fovy: f64::to_radians(FOVY),
+            }),
+            "orthographic" | "ortho" => Some(Projection::Orthographic { half_height: 1.0 }),
+            "cabinet" | "oblique" => Some(oblique(0.5)),
+            "cavalier" => Some(oblique(1.0)),
+            _ => None,
+        }
+    }
+}
+
+/// Canned technical views, looking at the center from a fixed direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum View {
+    Front,
+    Back,
+    Left,
+    Right,
+    Top,
+    Bottom,
+    Isometric,
+}
+
+impl View {
+    pub const ALL: [View; 7] = [
+        View::Front,
+        View::Back,
+        View::Left,
+        View::Right,
+        View::Top,
+        View::Bottom,
+        View::Isometric,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            View::Front => "front",
+            View::Back => "back",
+            View::Left => "left",
+            View::Right => "right",
+            View::Top => "top",
+            View::Bottom => "bottom",
+            View::Isometric => "isometric",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        View::ALL.into_iter().find(|v| v.name() == name)
+    }
+
+    /// Unit vector from the center towards the eye.
+    pub fn direction(self) -> Vec3f {
+        match self {
+            View::Front => Vec3f::from([0.0, 0.0, 1.0]),
+            View::Back => Vec3f::from([0.0, 0.0, -1.0]),
+            View::Left => Vec3f::from([-1.0, 0.0, 0.0]),
+            View::Right => Vec3f::from([1.0, 0.0, 0.0]),
+            View::Top => Vec3f::from([0.0, 1.0, 0.0]),
+            View::Bottom => Vec3f::from([0.0, -1.0, 0.0]),
+            View::Isometric => Vec3f::from([1.0, 1.0, 1.0]).normalize(),
+        }
+    }
+
+    pub fn up(self) -> Vec3f {
+        match self {
+            View::Top => Vec3f::from([0.0, 0.0, -1.0]),
+            View::Bottom => Vec3f::from([0.0, 0.0, 1.0]),
+            _ => Vec3f::from([0.0, 1.0, 0.0]),
+        }
+    }
+}
+
+/// A look-at camera together with its projection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera {
+    pub eye: Vec3f,
+    pub center: Vec3f,
+    pub up: Vec3f,
+    pub projection: Projection,
+    /// Distances from the eye to the clipping planes
+    pub near: f64,
+    pub far: f64,
+}
+
+impl Camera {
+    /// Perspective camera framing the unit cube like the original `-1/c` projection did.
+    pub fn new(eye: Vec3f, center: Vec3f, up: Vec3f) -> Self {
+        let distance = (eye - center).norm();
+        Self {
+            eye,
+            center,
+            up,
+            projection: Projection::Perspective {
+                fovy: 2.0 * (1.0 / distance).atan(),
+            },
+            near: distance * 0.1,
+            far: distance * 10.0,
+        }
+    }
+
+    /// Same distance from the center, seen from `view`.
+    pub fn with_view(self, view: View) -> Self {
+        Self {
+            eye: self.center + view.direction() * (self.eye - self.center).norm(),
+            up: view.up(),
+            ..self
+        }
+    }
+
+    /// Magnifies by `factor`: perspective moves the eye towards the center, parallel
+    /// projections shrink their size. The clipping planes follow the eye.
+    pub fn zoom(self, factor: f64) -> Self {
+        match self.projection {
+            Projection::Perspective { .. } => {
+                let distance = (self.eye - self.center).norm();
+                let shift = distance - distance / factor;
+                Self {
+                    eye: self.center + (self.eye - self.center) / factor,
+                    near: maxf(self.near - shift, self.near / factor),
+                    far: self.far - shift,
+                    ..self
+                }
+            }
+            Projection::Orthographic { half_height } => Self {
+                projection: Projection::Orthographic {
+                    half_height: half_height / factor,
+                },
+                ..self
+            },
+            Projection::Oblique {
+                half_height,
+                angle,
+                depth_scale,
+            } => Self {
+                projection: Projection::Oblique {
+                    half_height: half_height / factor,
+                    angle,
+                    depth_scale,
+                },
+                ..self
+            },
+        }
+    }
+
+    /// Keeps the view direction and the kind of projection, moves the camera and sets the
+    /// clipping planes so `bounds` fills a frame of `aspect` (width / height) as tightly as
+    /// the projection allows. Perspective keeps its field of view and changes the distance,
+    /// parallel projections change their size.
+    pub fn fit(self, bounds: &Aabb, aspect: f64) -> Self {
+        let radius = bounds.size().norm() / 2.0;
+        if bounds.is_empty() || radius == 0.0 {
+            return self;
+        }
+        let z = (self.eye - self.center).normalize();
+        let x = (self.up ^ z).normalize();
+        let y = (z ^ x).normalize();
+        let center = bounds.center();
+        // corners around the box center in camera axes, +z towards the eye
+        let corners = bounds.corners().map(|c| {
+            let d = c - center;
+            Vec3f::from([x.dot(&d), y.dot(&d), z.dot(&d)])
+        });
+        let front = corners.iter().fold(f64::MIN, |f, c| maxf(f, c[2]));
+        let back = corners.iter().fold(f64::MAX, |b, c| minf(b, c[2]));
+        let mut projection = self.projection;
+        let distance = match &mut projection {
+            Projection::Perspective { fovy } => {
+                let tan_y = (*fovy / 2.0).tan();
+                let tan_x = tan_y * aspect;
+                let distance = corners.iter().fold(0.0, |d, c| {
+                    maxf(d, c[2] + maxf(c[0].abs() / tan_x, c[1].abs() / tan_y))
+                });
+                // a box seen exactly edge on would touch the eye
+                maxf(distance, front + radius * 0.05)
+            }
+            Projection::Orthographic { half_height } => {
+                *half_height = corners
+                    .iter()
+                    .fold(0.0, |h, c| maxf(h, maxf(c[1].abs(), c[0].abs() / aspect)));
+                front + radius
+            }
+            Projection::Oblique {
+                half_height,
+                angle,
+                depth_scale,
+            } => {
+                // the shear moves points by their depth behind the center, see `oblique`
+                let (kx, ky) = (*depth_scale * angle.cos(), *depth_scale * angle.sin());
+                *half_height = corners.iter().fold(0.0, |h, c| {
+                    let (sx, sy) = (c[0] - kx * c[2], c[1] - ky * c[2]);
+                    maxf(h, maxf(sy.abs(), sx.abs() / aspect))
+                });
+                front + radius
+            }
+        };
+        let margin = radius * 0.01;
+        Self {
+            eye: center + z * distance,
+            center,
+            projection,
+            near: distance - front - margin,
+            far: distance - back + margin,
+            ..self
+        }
+    }
+
+    /// Set the model view and projection of `gl`, the aspect ratio is taken from its size.
+    pub fn apply(&self, gl: &mut GL) {
+        let distance = (self.eye - self.center).norm();
+        let aspect = gl.aspect();
+        let (near, far) = (self.near, self.far);
+        gl.lookat(self.eye, self.center, self.up);
+        match self.projection {
+            Projection::Perspective { fovy } => gl.perspective(fovy, aspect, near, far),
+            Projection::Orthographic { half_height } => {
+                let half_width = half_height * aspect;
+                gl.orthographic(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    near,
+                    far,
+                );
+            }
+            Projection::Oblique {
+                half_height,
+                angle,
+                depth_scale,
+            } => {
+                let half_width = half_height * aspect;
+                gl.orthographic(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    near,
+                    far,
+                );
+                gl.oblique(angle, depth_scale, distance);
+            }
+        }
+    }
+}
+
+/// Camera circling `target`, angles in radians. The azimuth turns around +y starting from
+/// +z towards +x, the elevation lifts the eye above the xz plane and a positive roll tilts
+/// the up vector to the right.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Orbit {
+    pub target: Vec3f,
+    pub distance: f64,
+    pub azimuth: f64,
+    pub elevation: f64,
+    pub roll: f64,
+}
+
+impl Orbit {
+    /// Unit vector from the target towards the eye.
+    pub fn direction(&self) -> Vec3f {
+        let (sin_az, cos_az) = self.azimuth.sin_cos();
+        let (sin_el, cos_el) = self.elevation.sin_cos();
+        Vec3f::from([cos_el * sin_az, sin_el, cos_el * cos_az])
+    }
+
+    pub fn eye(&self) -> Vec3f {
+        self.target + self.direction() * self.distance
+    }
+
+    pub fn up(&self) -> Vec3f {
+        // stays horizontal, so looking straight down or up is well defined
+        let right = Vec3f::from([self.azimuth.cos(), 0.0, -self.azimuth.sin()]);
+        let up = self.direction() ^ right;
+        up * self.roll.cos() + right * self.roll.sin()
+    }
+
+    pub fn camera(&self, projection: Projection) -> Camera {
+        Camera {
+            eye: self.eye(),
+            center: self.target,
+            up: self.up(),
+            projection,
+            near: self.distance * 0.1,
+            far: self.distance * 10.0,
+        }
+    }
+}
+
+/// Per request overrides of a render's camera.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CameraOptions {
+    pub view: Option<View>,
+    pub projection: Option<Projection>,
+    /// Orbit angles in radians, setting any of them frames the model automatically
+    pub azimuth: Option<f64>,
+    pub elevation: Option<f64>,
+    pub roll: Option<f64>,
+    /// Frame the model's bounds instead of keeping the render's distance and zoom
+    pub fit: bool,
+    /// Magnification applied last, 2 is twice as close
+    pub zoom: Option<f64>,
+}
+
+impl CameraOptions {
+    pub fn is_orbit(&self) -> bool {
+        self.azimuth.is_some() || self.elevation.is_some() || self.roll.is_some()
+    }
+
+    /// The render's own camera with the requested orbit or view and projection. Canned
+    /// views are orthographic unless a projection is asked for. Orbits always frame
+    /// `bounds` for a frame of `aspect` (width / height), the other cameras when `fit` is set.
+    pub fn resolve(
+        &self,
+        bounds: &Aabb,
+        eye: Vec3f,
+        center: Vec3f,
+        up: Vec3f,
+        aspect: f64,
+    ) -> Camera {
+        let mut camera = Camera::new(eye, center, up);
+        if self.is_orbit() {
+            let orbit = Orbit {
+                target: center,
+                distance: (eye - center).norm(),
+                azimuth: self.azimuth.unwrap_or(0.0),
+                elevation: self.elevation.unwrap_or(0.0),
+                roll: self.roll.unwrap_or(0.0),
+            };
+            camera = orbit.camera(Projection::Perspective {
+                fovy: f64::to_radians(FOVY),
+            });
+        } else if let Some(view) = self.view {
+            camera = camera.with_view(view);
+            camera.projection = Projection::Orthographic { half_height: 1.0 };
+        }
+        if let Some(projection) = self.projection {
+            camera.projection = projection;
+        }
+        if self.fit || self.is_orbit() {
+            camera = camera.fit(bounds, aspect);
+        }
+        if let Some(zoom) = self.zoom.filter(|z| z.is_finite() && *z > 0.0) {
+            camera = camera.zoom(zoom);
+        }
+        camera
+    }
+}
+
+pub fn viewport(x: i32, y: i32, w: i32, h: i32) -> Mat4f {
+    let mut res: Mat4f = Mat4f::identity();
     res[0][3] = x as f64 + w as f64 / 2.0;
     res[1][3] = y as f64 + h as f64 / 2.0;
     res[2][3] = DEPTH / 2.0;
@@ -203,3 +683,217 @@ pub fn triangle(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{embed, Vec4f};
+
+    fn ndc(m: &Mat4f, v: Vec3f) -> Vec3f {
+        let p = m * &embed::<_, 4, 3>(&v, 1.0);
+        Vec3f::from([p[0] / p[3], p[1] / p[3], p[2] / p[3]])
+    }
+
+    fn assert_close(a: Vec3f, b: Vec3f) {
+        assert!((a - b).norm() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_lookat_eye_at_origin() {
+        let eye = Vec3f::from([1.0, 1.0, 3.0]);
+        let mv = lookat(eye, Vec3f::ZERO, Vec3f::from([0.0, 1.0, 0.0]));
+        assert_close(ndc(&mv, eye), Vec3f::ZERO);
+        assert_close(ndc(&mv, Vec3f::ZERO), Vec3f::from([0.0, 0.0, -eye.norm()]));
+    }
+
+    #[test]
+    fn test_perspective_depth_range() {
+        let m = perspective(f64::to_radians(90.0), 2.0, 1.0, 10.0);
+        assert_close(
+            ndc(&m, Vec3f::from([2.0, 1.0, -1.0])),
+            Vec3f::from([1.0, 1.0, -1.0]),
+        );
+        assert_close(
+            ndc(&m, Vec3f::from([0.0, 0.0, -10.0])),
+            Vec3f::from([0.0, 0.0, 1.0]),
+        );
+        let r = reverse_z(&m);
+        assert_close(
+            ndc(&r, Vec3f::from([0.0, 0.0, -1.0])),
+            Vec3f::from([0.0, 0.0, 1.0]),
+        );
+    }
+
+    #[test]
+    fn test_orthographic() {
+        let m = orthographic(-2.0, 2.0, -1.0, 1.0, 1.0, 3.0);
+        assert_close(
+            ndc(&m, Vec3f::from([2.0, -1.0, -1.0])),
+            Vec3f::from([1.0, -1.0, -1.0]),
+        );
+        assert_close(
+            ndc(&m, Vec3f::from([0.0, 0.0, -3.0])),
+            Vec3f::from([0.0, 0.0, 1.0]),
+        );
+    }
+
+    #[test]
+    fn test_legacy_projection() {
+        // same result as the original matrix applied to a view centered on the look-at point
+        let c = 3.0;
+        let m = projection(-1.0 / c);
+        let v = Vec3f::from([0.5, 0.25, 0.5]);
+        let w = 1.0 - v[2] / c;
+        let expected = Vec3f::from([v[0] / w, v[1] / w, v[2] / w]);
+        assert_close(ndc(&m, v - Vec3f::from([0.0, 0.0, c])), expected);
+    }
+
+    #[test]
+    fn test_oblique() {
+        let m = oblique(0.0, 0.5, 2.0);
+        // the focus plane is untouched, points one unit behind it move half a unit right
+        assert_close(
+            ndc(&m, Vec3f::from([1.0, 1.0, -2.0])),
+            Vec3f::from([1.0, 1.0, -2.0]),
+        );
+        assert_close(
+            ndc(&m, Vec3f::from([0.0, 0.0, -3.0])),
+            Vec3f::from([0.5, 0.0, -3.0]),
+        );
+    }
+
+    #[test]
+    fn test_view_names() {
+        for view in View::ALL {
+            assert_eq!(View::from_name(view.name()), Some(view));
+            assert!((view.direction().norm() - 1.0).abs() < 1e-9);
+            assert!((view.up() ^ view.direction()).norm() > 0.5);
+        }
+        assert_eq!(View::from_name("side"), None);
+    }
+
+    #[test]
+    fn test_camera_options() {
+        let eye = Vec3f::from([1.0, 1.0, 3.0]);
+        let up = Vec3f::from([0.0, 1.0, 0.0]);
+        let bounds = Model::new("obj/african_head/african_head.obj").bbox();
+        let camera = CameraOptions::default().resolve(&bounds, eye, Vec3f::ZERO, up, 1.0);
+        assert_eq!(camera.eye, eye);
+        assert!(matches!(camera.projection, Projection::Perspective { .. }));
+
+        let options = CameraOptions {
+            view: Some(View::Top),
+            ..CameraOptions::default()
+        };
+        let camera = options.resolve(&bounds, eye, Vec3f::ZERO, up, 1.0);
+        assert_close(camera.eye, Vec3f::from([0.0, eye.norm(), 0.0]));
+        assert_eq!(
+            camera.projection,
+            Projection::Orthographic { half_height: 1.0 }
+        );
+
+        let fitted = CameraOptions {
+            fit: true,
+            ..options
+        }
+        .resolve(&bounds, eye, Vec3f::ZERO, up, 1.0);
+        assert_close(fitted.center, bounds.center());
+        assert_ne!(fitted.projection, camera.projection);
+
+        let zoomed = CameraOptions {
+            zoom: Some(2.0),
+            ..options
+        }
+        .resolve(&bounds, eye, Vec3f::ZERO, up, 1.0);
+        assert_eq!(
+            zoomed.projection,
+            Projection::Orthographic { half_height: 0.5 }
+        );
+        let camera = CameraOptions {
+            zoom: Some(2.0),
+            ..CameraOptions::default()
+        }
+        .resolve(&bounds, eye, Vec3f::ZERO, up, 1.0);
+        assert_close(camera.eye, eye / 2.0);
+        assert!(camera.near > 0.0 && camera.near < camera.far);
+    }
+
+    /// Projects the corners of `bounds` with a fitted camera, returning the largest NDC x or
+    /// y and the depth range in view space.
+    fn fitted_extent(camera: Camera, bounds: &Aabb, aspect: f64) -> (f64, f64, f64) {
+        let mut gl = GL::new(Vec3f::ZERO, (100.0 * aspect) as u32, 100);
+        let camera = camera.fit(bounds, aspect);
+        camera.apply(&mut gl);
+        let mvp = gl.projection * gl.model_view;
+        let (mut extent, mut near, mut far) = (0.0, f64::MAX, 0.0);
+        for c in bounds.corners() {
+            let p = ndc(&mvp, c);
+            extent = maxf(extent, maxf(p[0].abs(), p[1].abs()));
+            let depth = -ndc(&gl.model_view, c)[2];
+            near = minf(near, depth);
+            far = maxf(far, depth);
+        }
+        assert!(camera.near > 0.0 && camera.near <= near, "{:?}", camera);
+        assert!(camera.far >= far, "{:?}", camera);
+        (extent, near, far)
+    }
+
+    #[test]
+    fn test_fit() {
+        let bounds = Aabb {
+            min: Vec3f::from([10.0, -3.0, 4.0]),
+            max: Vec3f::from([14.0, 5.0, 5.0]),
+        };
+        let up = Vec3f::from([0.0, 1.0, 0.0]);
+        for view in View::ALL {
+            for aspect in [0.5, 1.0, 2.0] {
+                let camera = Camera::new(view.direction() * 3.0, Vec3f::ZERO, view.up());
+                for projection in [
+                    Projection::Perspective { fovy: 0.6 },
+                    Projection::Orthographic { half_height: 1.0 },
+                    Projection::from_name("cabinet").unwrap(),
+                ] {
+                    let camera = Camera {
+                        projection,
+                        ..camera
+                    };
+                    let (extent, _, _) = fitted_extent(camera, &bounds, aspect);
+                    // touches the frame without leaving it
+                    assert!(
+                        (extent - 1.0).abs() < 1e-9,
+                        "{:?} {:?} {}",
+                        view,
+                        projection,
+                        extent
+                    );
+                }
+            }
+        }
+        // a flat model still gets a positive near plane
+        let flat = Aabb {
+            min: Vec3f::from([-1.0, -1.0, 0.0]),
+            max: Vec3f::from([1.0, 1.0, 0.0]),
+        };
+        let camera = Camera::new(Vec3f::from([3.0, 0.0, 0.0]), Vec3f::ZERO, up);
+        fitted_extent(camera, &flat, 1.0);
+    }
+
+    #[test]
+    fn test_orbit() {
+        let orbit = Orbit {
+            target: Vec3f::ZERO,
+            distance: 2.0,
+            azimuth: f64::to_radians(90.0),
+            elevation: 0.0,
+            roll: 0.0,
+        };
+        assert_close(orbit.eye(), Vec3f::from([orbit.distance, 0.0, 0.0]));
+        assert_close(orbit.up(), Vec3f::from([0.0, 1.0, 0.0]));
+        // straight down, the up vector follows the azimuth
+        let top = Orbit {
+            elevation: f64::to_radians(90.0),
+            ..orbit
+        };
+        assert_close(top.up(), Vec3f::from([-1.0, 0.0, 0.0]));
+    }
+}
@@ -0,0 +1,291 @@
+use std::{fs, path::PathBuf, str::FromStr, thread};
+
+use log::LevelFilter;
+
+use crate::{
+    cache::CacheConfig,
+    params::{self, from_args},
+};
+
+/// Read when neither `--config` nor `TRRS_CONFIG` name a file, if it exists.
+pub const CONFIG_FILE: &str = "trrs.toml";
+/// Error of `Config::set` for a name that is no setting.
+const UNKNOWN: &str = "unknown setting";
+
+pub const USAGE: &str = "\
+Usage: trrs [options]
+
+Serves the renders over HTTP. Every option can also be set by the environment variable
+TRRS_<NAME>, e.g. TRRS_CACHE_MEMORY, or in the TOML file of --config (default trrs.toml)
+as `name = value` or in a table, `[cache] memory = ...`. Options win over the environment,
+which wins over the file. Other TRRS_* variables are ignored with a warning.
+
+Options:
+    --config <file>          TOML file with the settings below
+    --host <address>         address to listen on, default 0.0.0.0
+    --port <port>            default 8080
+    --root <dir>             directory the models, uploads and cache directory are
+                             resolved from, default the current directory
+    --workers <n>            threads serving requests and renders running at once, default
+                             one per core
+    --cache-memory <bytes>   encoded renders kept in memory, default 256M
+    --cache-dir <dir>        also keep them in this directory, across restarts
+    --cache-disk <bytes>     encoded renders kept in --cache-dir, default 1G
+    --cache-max-age <s>      Cache-Control max-age of the renders, default 3600
+    --max-size <px>          largest width or height of a render, at most 65535, default 4096
+    --timeout <s>            requests taking longer are answered 503, 0 never, default 60
+    --log <level>            off, error, warn, info, debug or trace, default info
+    --help                   print this message
+
+Sizes in bytes take a K, M or G suffix.
+";
+
+/// Settings of the server, see `USAGE`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub root: PathBuf,
+    pub workers: usize,
+    pub cache: CacheConfig,
+    pub max_size: u32,
+    /// Seconds, 0 for none
+    pub timeout: u64,
+    pub log: LevelFilter,
+    /// The `TRRS_*` variables that name no setting, logged once logging is set up
+    pub ignored: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 8080,
+            root: PathBuf::from("."),
+            workers: thread::available_parallelism().map_or(4, |n| n.get()),
+            cache: CacheConfig::default(),
+            max_size: params::MAX_SIZE,
+            timeout: 60,
+            log: LevelFilter::Info,
+            ignored: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The defaults overridden by the config file, then by `env` (the `TRRS_*` variables)
+    /// and then by the command line `args`.
+    pub fn load(
+        args: &[String],
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, String> {
+        let args = from_args(args)?;
+        let env: Vec<(String, String)> = env
+            .into_iter()
+            .filter_map(|(k, v)| Some((k.strip_prefix("TRRS_")?.to_lowercase(), v)))
+            .collect();
+        let file = args.get("config").cloned().or_else(|| {
+            env.iter()
+                .find(|(k, _)| k == "config")
+                .map(|(_, v)| v.clone())
+        });
+
+        let mut config = Self::default();
+        match &file {
+            Some(path) => config.read_file(path)?,
+            None if fs::metadata(CONFIG_FILE).is_ok() => config.read_file(CONFIG_FILE)?,
+            None => {}
+        }
+        // other programs may share the prefix
+        for (name, value) in env.iter().filter(|(k, _)| k != "config") {
+            match config.set(name, value) {
+                Err(err) if err == UNKNOWN => {
+                    config.ignored.push(format!("TRRS_{}", name.to_uppercase()))
+                }
+                result => result.map_err(|err| format!("TRRS_{}: {}", name.to_uppercase(), err))?,
+            }
+        }
+        for (name, value) in args.iter().filter(|(k, _)| *k != "config") {
+            config
+                .set(&name.replace('-', "_"), value)
+                .map_err(|err| format!("--{}: {}", name, err))?;
+        }
+        Ok(config)
+    }
+
+    /// Applies the settings of a TOML file, the keys of a table are prefixed with its name.
+    fn read_file(&mut self, path: &str) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        let table: toml::Table = text.parse().map_err(|err| format!("{}: {}", path, err))?;
+        let mut settings = Vec::new();
+        for (name, value) in table {
+            match value {
+                toml::Value::Table(table) => settings.extend(
+                    table
+                        .into_iter()
+                        .map(|(key, value)| (format!("{}_{}", name, key), value)),
+                ),
+                value => settings.push((name, value)),
+            }
+        }
+        for (name, value) in settings {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+                    value.to_string()
+                }
+                _ => return Err(format!("{}: {}: expected a string or a number", path, name)),
+            };
+            self.set(&name, &value)
+                .map_err(|err| format!("{}: {}: {}", path, name, err))?;
+        }
+        Ok(())
+    }
+
+    /// Sets one setting by its name in snake case, like `cache_memory`.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "host" => self.host = value.to_string(),
+            "port" => self.port = number(value)?,
+            "root" => self.root = PathBuf::from(value),
+            "workers" => self.workers = positive(number(value)?)?,
+            "cache_memory" => self.cache.memory = bytes(value)?,
+            "cache_dir" => {
+                self.cache.dir = Some(PathBuf::from(value)).filter(|_| !value.is_empty())
+            }
+            "cache_disk" => self.cache.disk = bytes(value)?,
+            "cache_max_age" => self.cache.max_age = number(value)?,
+            // `viewer::Viewer` sends the size in 16 bits
+            "max_size" => self.max_size = at_most(positive(number(value)?)?, u16::MAX as u32)?,
+            "timeout" => self.timeout = number(value)?,
+            "log" => {
+                self.log = value.parse().map_err(|_| {
+                    format!(
+                        "`{}` is not a level, expected off, error, warn, info, debug or trace",
+                        value
+                    )
+                })?
+            }
+            _ => return Err(UNKNOWN.to_string()),
+        }
+        Ok(())
+    }
+
+    /// `host:port`
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+fn number<T: FromStr>(s: &str) -> Result<T, String> {
+    s.parse()
+        .map_err(|_| format!("`{}` is not a valid number", s))
+}
+
+fn positive<T: Default + PartialOrd>(n: T) -> Result<T, String> {
+    if n > T::default() {
+        Ok(n)
+    } else {
+        Err("must be positive".to_string())
+    }
+}
+
+fn at_most<T: PartialOrd + std::fmt::Display>(n: T, max: T) -> Result<T, String> {
+    if n <= max {
+        Ok(n)
+    } else {
+        Err(format!("must be at most {}", max))
+    }
+}
+
+/// `1048576`, `1024K`, `1M` or `1G`.
+fn bytes(s: &str) -> Result<usize, String> {
+    let (digits, shift) = match s.as_bytes().last() {
+        Some(b'K' | b'k') => (&s[..s.len() - 1], 10),
+        Some(b'M' | b'm') => (&s[..s.len() - 1], 20),
+        Some(b'G' | b'g') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let n: usize = digits
+        .parse()
+        .map_err(|_| format!("`{}` is not a size in bytes", s))?;
+    n.checked_shl(shift)
+        .filter(|v| v >> shift == n)
+        .ok_or_else(|| format!("`{}` is too large", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("trrs-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "port = 9000\nroot = \"/srv/trrs\"\nlog = \"warn\"\n[cache]\nmemory = \"64M\"\ndir = \"cache\"\n",
+        )
+        .unwrap();
+        let file = format!("--config {}", path.display());
+
+        let config = Config::load(&args(&file), env(&[])).unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.root, PathBuf::from("/srv/trrs"));
+        assert_eq!(config.log, LevelFilter::Warn);
+        assert_eq!(config.cache.memory, 64 << 20);
+        assert_eq!(config.cache.dir, Some(PathBuf::from("cache")));
+        assert_eq!(config.address(), "0.0.0.0:9000");
+
+        // the environment wins over the file and the arguments over both
+        let vars = env(&[
+            ("TRRS_PORT", "9001"),
+            ("TRRS_CACHE_MEMORY", "1K"),
+            ("TRRS_COLOUR", "red"),
+            ("HOME", "/root"),
+        ]);
+        let config = Config::load(&args(&format!("{} --port 9002", file)), vars).unwrap();
+        assert_eq!((config.port, config.cache.memory), (9002, 1024));
+        assert_eq!(config.ignored, ["TRRS_COLOUR"]);
+
+        assert_eq!(
+            Config::load(&args("--port 70000"), env(&[])),
+            Err("--port: `70000` is not a valid number".to_string())
+        );
+        assert_eq!(
+            Config::load(&args(""), env(&[("TRRS_WORKERS", "0")])),
+            Err("TRRS_WORKERS: must be positive".to_string())
+        );
+        assert_eq!(
+            Config::load(&args("--max-size 65536"), env(&[])),
+            Err("--max-size: must be at most 65535".to_string())
+        );
+        assert!(Config::load(&args("--colour red"), env(&[])).is_err());
+        fs::write(&path, "[cache]\nmemry = 1\n").unwrap();
+        assert_eq!(
+            Config::load(&args(&file), env(&[])),
+            Err(format!("{}: cache_memry: unknown setting", path.display()))
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_bytes() {
+        assert_eq!(bytes("123"), Ok(123));
+        assert_eq!(bytes("2K"), Ok(2048));
+        assert_eq!(bytes("256M"), Ok(256 << 20));
+        assert_eq!(bytes("1g"), Ok(1 << 30));
+        assert!(bytes("M").is_err());
+        assert!(bytes("-1").is_err());
+        assert!(bytes(&format!("{}G", usize::MAX)).is_err());
+    }
+}
@@ -0,0 +1,353 @@
+use std::fmt::Display;
+
+use crate::{
+    bounds::Aabb,
+    geometry::{embed, Vec4f},
+    gl::{DepthTest, GL, W_EPSILON},
+    matrix::Mat4f,
+    util::{maxf, minf},
+};
+
+/// Which tests `Culler` runs before a mesh is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Culling {
+    pub frustum: bool,
+    /// Hierarchical-Z test against the depth written by the previous draws
+    pub occlusion: bool,
+}
+
+impl Default for Culling {
+    fn default() -> Self {
+        Self {
+            frustum: true,
+            occlusion: false,
+        }
+    }
+}
+
+/// What the culling tests rejected, triangles are counted per rejected mesh.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CullStats {
+    pub meshes: usize,
+    pub frustum_culled: usize,
+    pub occluded: usize,
+    pub triangles: usize,
+    pub triangles_culled: usize,
+}
+
+impl CullStats {
+    pub fn meshes_drawn(&self) -> usize {
+        self.meshes - self.frustum_culled - self.occluded
+    }
+
+    pub fn triangles_drawn(&self) -> usize {
+        self.triangles - self.triangles_culled
+    }
+}
+
+impl Display for CullStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "meshes={} frustum={} occluded={} triangles={} culled={}",
+            self.meshes, self.frustum_culled, self.occluded, self.triangles, self.triangles_culled
+        )
+    }
+}
+
+/// Depth pyramid, each texel of a level holds the farthest depth of the 2x2 texels below it,
+/// so one texel answers "is anything behind this depth" for a whole screen region.
+#[derive(Debug, Clone)]
+pub struct HiZ {
+    /// Width, height and texels of each level, level 0 is the depth buffer itself
+    levels: Vec<(u32, u32, Vec<f64>)>,
+    depth_test: DepthTest,
+}
+
+impl HiZ {
+    pub fn new(gl: &GL, zbuf: &[f64]) -> Self {
+        let farthest = |a: f64, b: f64| match gl.depth_test {
+            DepthTest::Less => maxf(a, b),
+            DepthTest::Greater => minf(a, b),
+        };
+        let mut levels = vec![(gl.width, gl.height, zbuf.to_vec())];
+        while let Some((w, h, texels)) = levels.last().filter(|(w, h, _)| *w > 1 || *h > 1) {
+            let (nw, nh) = (w.div_ceil(2), h.div_ceil(2));
+            let mut next = Vec::with_capacity((nw * nh) as usize);
+            for y in 0..nh {
+                for x in 0..nw {
+                    // odd sizes repeat the last row or column
+                    let (x0, y0) = (2 * x, 2 * y);
+                    let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+                    let at = |x: u32, y: u32| texels[(x + y * w) as usize];
+                    next.push(farthest(
+                        farthest(at(x0, y0), at(x1, y0)),
+                        farthest(at(x0, y1), at(x1, y1)),
+                    ));
+                }
+            }
+            levels.push((nw, nh, next));
+        }
+        Self {
+            levels,
+            depth_test: gl.depth_test,
+        }
+    }
+
+    /// Whether every pixel of the inclusive rectangle holds something closer than `nearest`.
+    /// Starts from at most 2x2 texels of the finest level where the rectangle fits and only
+    /// descends into the texels that are not conclusive, so a box tucked behind the silhouette
+    /// of an occluder is still found without reading every pixel.
+    pub fn occluded(&self, min: (u32, u32), max: (u32, u32), nearest: f64) -> bool {
+        let level = (0..self.levels.len())
+            .find(|&l| (max.0 >> l) - (min.0 >> l) <= 1 && (max.1 >> l) - (min.1 >> l) <= 1)
+            .unwrap_or(self.levels.len() - 1);
+        (min.1 >> level..=max.1 >> level).all(|y| {
+            (min.0 >> level..=max.0 >> level)
+                .all(|x| self.covered(level, (x, y), min, max, nearest))
+        })
+    }
+
+    /// Whether the part of the rectangle under texel `at` of `level` is hidden.
+    fn covered(
+        &self,
+        level: usize,
+        at: (u32, u32),
+        min: (u32, u32),
+        max: (u32, u32),
+        nearest: f64,
+    ) -> bool {
+        let (w, _, texels) = &self.levels[level];
+        if !self
+            .depth_test
+            .passes(nearest, texels[(at.0 + at.1 * w) as usize])
+        {
+            return true;
+        }
+        if level == 0 {
+            return false;
+        }
+        // the children inside both the finer level and the rectangle
+        let below = level - 1;
+        let (bw, bh, _) = &self.levels[below];
+        let xs = (2 * at.0).max(min.0 >> below)..=(2 * at.0 + 1).min(bw - 1).min(max.0 >> below);
+        let ys = (2 * at.1).max(min.1 >> below)..=(2 * at.1 + 1).min(bh - 1).min(max.1 >> below);
+        ys.clone().all(|y| {
+            xs.clone()
+                .all(|x| self.covered(below, (x, y), min, max, nearest))
+        })
+    }
+}
+
+/// Per-mesh visibility tests on bounding boxes, in the clip space of `GL::rasterize`.
+#[derive(Debug, Clone)]
+pub struct Culler {
+    /// Object to clip coordinates (after viewport)
+    clip: Mat4f,
+    width: f64,
+    height: f64,
+    depth_test: DepthTest,
+    depth_clamp: bool,
+    pub culling: Culling,
+    pub stats: CullStats,
+}
+
+impl Culler {
+    pub fn new(gl: &GL, culling: Culling) -> Self {
+        Self {
+            clip: gl.viewport * gl.projection * gl.model_view,
+            width: gl.width as f64,
+            height: gl.height as f64,
+            depth_test: gl.depth_test,
+            depth_clamp: gl.depth_clamp,
+            culling,
+            stats: CullStats::default(),
+        }
+    }
+
+    /// Whether a mesh of `ntriangles` inside `bounds` may show up and has to be drawn.
+    /// `hiz` is only read when occlusion culling is on.
+    pub fn test(&mut self, bounds: &Aabb, ntriangles: usize, hiz: Option<&HiZ>) -> bool {
+        self.test_instance(bounds, &Mat4f::identity(), ntriangles, hiz)
+    }
+
+    /// Like `test` for a mesh placed in the world by the `model` matrix, `bounds` are in
+    /// object space.
+    pub fn test_instance(
+        &mut self,
+        bounds: &Aabb,
+        model: &Mat4f,
+        ntriangles: usize,
+        hiz: Option<&HiZ>,
+    ) -> bool {
+        self.stats.meshes += 1;
+        self.stats.triangles += ntriangles;
+        let clip = self.clip * *model;
+        let corners = bounds.corners().map(|c| clip * embed::<_, 4, 3>(&c, 1.0));
+        if self.culling.frustum && self.outside_frustum(&corners) {
+            self.stats.frustum_culled += 1;
+            self.stats.triangles_culled += ntriangles;
+            return false;
+        }
+        if let Some(hiz) = hiz.filter(|_| self.culling.occlusion) {
+            if self.occluded(&corners, hiz) {
+                self.stats.occluded += 1;
+                self.stats.triangles_culled += ntriangles;
+                return false;
+            }
+        }
+        true
+    }
+
+    /// All corners on the outer side of one plane. The planes are linear in clip space so a
+    /// convex box can be tested through its corners.
+    fn outside_frustum(&self, corners: &[Vec4f; 8]) -> bool {
+        let (w, h) = (self.width, self.height);
+        let distances = |c: &Vec4f| {
+            [
+                c[3] - W_EPSILON,
+                c[0],
+                w * c[3] - c[0],
+                c[1],
+                h * c[3] - c[1],
+                c[2],
+                c[3] - c[2],
+            ]
+        };
+        // with depth clamping nothing is clipped against the near and far planes
+        let planes = if self.depth_clamp { 5 } else { 7 };
+        (0..planes).any(|i| corners.iter().all(|c| distances(c)[i] < 0.0))
+    }
+
+    /// The screen rectangle and nearest depth of the box against the pyramid. Screen position
+    /// and depth are linear-fractional in the object position, their extremes over the box
+    /// are at the corners.
+    fn occluded(&self, corners: &[Vec4f; 8], hiz: &HiZ) -> bool {
+        // the box reaches behind the eye, its projection is unbounded
+        if corners.iter().any(|c| c[3] <= W_EPSILON) {
+            return false;
+        }
+        let mut min = [f64::MAX; 3];
+        let mut max = [f64::MIN; 3];
+        for c in corners.iter() {
+            for i in 0..3 {
+                min[i] = minf(min[i], c[i] / c[3]);
+                max[i] = maxf(max[i], c[i] / c[3]);
+            }
+        }
+        let mut nearest = match self.depth_test {
+            DepthTest::Less => min[2],
+            DepthTest::Greater => max[2],
+        };
+        if self.depth_clamp {
+            nearest = maxf(0.0, minf(1.0, nearest));
+        }
+        let pixel = |v: f64, size: f64| maxf(0.0, minf(size - 1.0, v)) as u32;
+        hiz.occluded(
+            (
+                pixel(min[0].floor(), self.width),
+                pixel(min[1].floor(), self.height),
+            ),
+            (
+                pixel(max[0].ceil(), self.width),
+                pixel(max[1].ceil(), self.height),
+            ),
+            nearest,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{geometry::Vec3f, transform::Transform};
+
+    fn gl() -> GL {
+        let mut gl = GL::new(Vec3f::ZERO, 64, 64);
+        gl.viewport(0, 0, 64, 64);
+        gl.lookat(
+            Vec3f::from([0.0, 0.0, 3.0]),
+            Vec3f::ZERO,
+            Vec3f::from([0.0, 1.0, 0.0]),
+        );
+        gl.perspective(f64::to_radians(60.0), 1.0, 0.1, 10.0);
+        gl
+    }
+
+    fn cube(center: [f64; 3], half: f64) -> Aabb {
+        let c = Vec3f::from(center);
+        Aabb {
+            min: c - Vec3f::from([half; 3]),
+            max: c + Vec3f::from([half; 3]),
+        }
+    }
+
+    #[test]
+    fn test_frustum() {
+        let gl = gl();
+        let mut culler = Culler::new(&gl, Culling::default());
+        assert!(culler.test(&cube([0.0, 0.0, 0.0], 0.5), 10, None));
+        // behind the eye, far off to the side, beyond the far plane
+        assert!(!culler.test(&cube([0.0, 0.0, 5.0], 0.5), 20, None));
+        assert!(!culler.test(&cube([20.0, 0.0, 0.0], 0.5), 20, None));
+        assert!(!culler.test(&cube([0.0, 0.0, -20.0], 0.5), 20, None));
+        // straddling the side plane
+        assert!(culler.test(&cube([1.8, 0.0, 0.0], 0.5), 10, None));
+        assert_eq!(
+            culler.stats,
+            CullStats {
+                meshes: 5,
+                frustum_culled: 3,
+                occluded: 0,
+                triangles: 80,
+                triangles_culled: 60,
+            }
+        );
+        // the same box moved off to the side by an instance matrix
+        let model = Transform::from_translation(Vec3f::from([20.0, 0.0, 0.0])).to_mat();
+        assert!(!culler.test_instance(&cube([0.0, 0.0, 0.0], 0.5), &model, 1, None));
+    }
+
+    #[test]
+    fn test_hiz() {
+        let gl = gl();
+        // a wall at depth 0.5 over the 30 leftmost columns
+        let mut zbuf = gl.depth_buffer();
+        for y in 0..64 {
+            for x in 0..30 {
+                zbuf[x + y * 64] = 0.5;
+            }
+        }
+        let hiz = HiZ::new(&gl, &zbuf);
+        assert!(hiz.occluded((0, 0), (29, 63), 0.6));
+        assert!(!hiz.occluded((0, 0), (29, 63), 0.4));
+        assert!(!hiz.occluded((20, 10), (40, 20), 0.6));
+        assert!(hiz.occluded((3, 5), (3, 5), 0.6));
+        // the coarse texels reach past the wall, the finer ones settle it
+        assert!(hiz.occluded((20, 0), (29, 10), 0.6));
+        assert!(!hiz.occluded((20, 0), (30, 10), 0.6));
+    }
+
+    #[test]
+    fn test_occlusion() {
+        let gl = gl();
+        let culling = Culling {
+            frustum: true,
+            occlusion: true,
+        };
+        let mut culler = Culler::new(&gl, culling);
+        let mut zbuf = gl.depth_buffer();
+        // nothing drawn yet, nothing is occluded
+        let hiz = HiZ::new(&gl, &zbuf);
+        assert!(culler.test(&cube([0.0, 0.0, -1.0], 0.2), 1, Some(&hiz)));
+
+        // a full screen occluder through the origin
+        let origin = culler.clip * Vec4f::from([0.0, 0.0, 0.0, 1.0]);
+        zbuf.fill(origin[2] / origin[3]);
+        let hiz = HiZ::new(&gl, &zbuf);
+        assert!(!culler.test(&cube([0.0, 0.0, -1.0], 0.2), 1, Some(&hiz)));
+        assert!(culler.test(&cube([0.0, 0.0, 2.0], 0.2), 1, Some(&hiz)));
+        assert_eq!(culler.stats.occluded, 1);
+        assert_eq!(culler.stats.meshes_drawn(), 2);
+    }
+}
@@ -0,0 +1,184 @@
+use image::Rgba;
+
+use crate::{
+    geometry::{embed, proj, Vec3f, Vec4f},
+    matrix::Mat4f,
+    util::maxf,
+};
+
+/// Attributes written by an `IGeometryShader` for one fragment, all vectors in view space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Surface {
+    pub position: Vec3f,
+    pub normal: Vec3f,
+    pub albedo: Rgba<f32>, // linear
+    pub specular: f64,     // specular exponent from the specular map
+    pub emission: Rgba<f32>,
+}
+
+/// Multiple render targets of the deferred geometry pass, one entry per pixel.
+#[derive(Debug)]
+pub struct GBuffer {
+    pub width: u32,
+    pub height: u32,
+    pub depth: Vec<f64>, // screen depth, infinite where nothing was drawn
+    pub position: Vec<Vec3f>,
+    pub normal: Vec<Vec3f>,
+    pub albedo: Vec<Rgba<f32>>,
+    pub specular: Vec<f64>,
+    pub emission: Vec<Rgba<f32>>,
+}
+
+impl GBuffer {
+    /// `clear_depth` is `DepthTest::clear_value` of the depth test used to fill it.
+    pub fn new(width: u32, height: u32, clear_depth: f64) -> Self {
+        let n = (width * height) as usize;
+        Self {
+            width,
+            height,
+            depth: vec![clear_depth; n],
+            position: vec![Vec3f::ZERO; n],
+            normal: vec![Vec3f::ZERO; n],
+            albedo: vec![Rgba([0.0, 0.0, 0.0, 0.0]); n],
+            specular: vec![0.0; n],
+            emission: vec![Rgba([0.0, 0.0, 0.0, 0.0]); n],
+        }
+    }
+
+    pub fn covered(&self, idx: usize) -> bool {
+        self.depth[idx].is_finite()
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, surface: Surface) {
+        let idx = (x + y * self.width) as usize;
+        self.position[idx] = surface.position;
+        self.normal[idx] = surface.normal;
+        self.albedo[idx] = surface.albedo;
+        self.specular[idx] = surface.specular;
+        self.emission[idx] = surface.emission;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Light {
+    /// Constant term added to every surface
+    Ambient { color: [f32; 3] },
+    /// `dir` points from the surface towards the light
+    Directional { dir: Vec3f, color: [f32; 3] },
+    /// Inverse square falloff, reaching zero at `range`
+    Point {
+        pos: Vec3f,
+        color: [f32; 3],
+        range: f64,
+    },
+}
+
+impl Light {
+    /// The same light with its position/direction moved from world to view space.
+    pub fn to_view(self, model_view: &Mat4f) -> Light {
+        match self {
+            Light::Ambient { color } => Light::Ambient { color },
+            Light::Directional { dir, color } => {
+                let d = model_view * &embed::<_, 4, 3>(&dir, 0.0);
+                Light::Directional {
+                    dir: proj::<_, 3, 4>(&d).normalize(),
+                    color,
+                }
+            }
+            Light::Point { pos, color, range } => {
+                let p = model_view * &embed::<_, 4, 3>(&pos, 1.0);
+                Light::Point {
+                    pos: proj::<_, 3, 4>(&(p / p[3])),
+                    color,
+                    range,
+                }
+            }
+        }
+    }
+
+    /// Blinn-Phong contribution of this light, `v` points from the surface to the eye.
+    pub fn shade(
+        &self,
+        p: Vec3f,
+        n: Vec3f,
+        v: Vec3f,
+        albedo: Rgba<f32>,
+        specular: f64,
+    ) -> [f32; 3] {
+        let (l, color, attenuation) = match *self {
+            Light::Ambient { color } => {
+                return [
+                    albedo[0] * color[0],
+                    albedo[1] * color[1],
+                    albedo[2] * color[2],
+                ];
+            }
+            Light::Directional { dir, color } => (dir, color, 1.0),
+            Light::Point { pos, color, range } => {
+                let d = pos - p;
+                let dist = d.norm();
+                let falloff = maxf(0.0, 1.0 - dist / range);
+                (d / dist, color, falloff * falloff / maxf(dist * dist, 1e-4))
+            }
+        };
+        let diff = maxf(0.0, n.dot(&l));
+        if diff <= 0.0 || attenuation <= 0.0 {
+            return [0.0; 3];
+        }
+        let h = (l + v).normalize();
+        let spec = f64::powf(maxf(0.0, n.dot(&h)), specular);
+        // same weighting as the forward shaders, the highlight is tinted by the albedo
+        let k = ((diff + 0.6 * spec) * attenuation) as f32;
+        let mut rs = [0.0; 3];
+        for (i, r) in rs.iter_mut().enumerate() {
+            *r = albedo[i] * k * color[i];
+        }
+        rs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directional_light() {
+        let light = Light::Directional {
+            dir: Vec3f::from([0.0, 0.0, 1.0]),
+            color: [1.0, 0.5, 0.0],
+        };
+        let n = Vec3f::from([0.0, 0.0, 1.0]);
+        let albedo = Rgba([1.0, 1.0, 1.0, 1.0]);
+        // diffuse 1.0 plus a 0.6 highlight
+        assert_eq!(light.shade(Vec3f::ZERO, n, n, albedo, 8.0), [1.6, 0.8, 0.0]);
+        // lit from behind
+        assert_eq!(light.shade(Vec3f::ZERO, n * -1.0, n, albedo, 8.0), [0.0; 3]);
+    }
+
+    #[test]
+    fn test_point_light_range() {
+        let light = Light::Point {
+            pos: Vec3f::from([0.0, 0.0, 2.0]),
+            color: [1.0, 1.0, 1.0],
+            range: 1.0,
+        };
+        let n = Vec3f::from([0.0, 0.0, 1.0]);
+        let albedo = Rgba([1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(light.shade(Vec3f::ZERO, n, n, albedo, 0.0), [0.0; 3]);
+    }
+
+    #[test]
+    fn test_light_to_view() {
+        let mut mv = Mat4f::identity();
+        mv[0][3] = 1.0;
+        let light = Light::Point {
+            pos: Vec3f::ZERO,
+            color: [1.0; 3],
+            range: 1.0,
+        };
+        match light.to_view(&mv) {
+            Light::Point { pos, .. } => assert_eq!(pos, Vec3f::from([1.0, 0.0, 0.0])),
+            _ => unreachable!(),
+        }
+    }
+}
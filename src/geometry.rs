@@ -97,6 +97,12 @@ impl<T: NumLike, const N: usize> Vec_<T, N> {
     }
 }
 
+impl<T: NumLike, const N: usize> Default for Vec_<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: NumLike, const N: usize> ops::Add for Vec_<T, N> {
     type Output = Vec_<T, N>;
 
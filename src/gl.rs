@@ -1,119 +1,701 @@
-use image::{Rgba, RgbaImage};
+use std::{
+    cell::Cell,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use image::Rgba;
 
 use crate::{
-    camera::lookat,
+    camera,
+    culling::{CullStats, Culler, HiZ},
+    deferred::{GBuffer, Light, Surface},
     geometry::{embed, proj, Vec2f, Vec3f, Vec4f},
-    matrix::Mat,
+    hdr::HdrImage,
+    instance::{Instance, InstanceUniforms},
+    matrix::{Mat3f, Mat4f},
+    model::Model,
     util::{maxf, minf},
 };
 
-const DEPTH: f64 = 255.0;
+/// Smallest clip space w kept by the near clipping, avoids dividing by zero behind the eye.
+pub const W_EPSILON: f64 = 1e-6;
+
+/// Which of two depths wins the depth test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthTest {
+    /// The regular mapping, near plane at 0 and far plane at 1
+    Less,
+    /// Reversed-Z and the legacy projection, closer fragments have a larger depth
+    Greater,
+}
+
+impl DepthTest {
+    /// Value of an empty depth buffer, never a valid depth.
+    pub fn clear_value(self) -> f64 {
+        match self {
+            DepthTest::Less => f64::INFINITY,
+            DepthTest::Greater => f64::NEG_INFINITY,
+        }
+    }
+
+    /// Whether a fragment at `depth` is visible over `stored`, ties pass.
+    pub fn passes(self, depth: f64, stored: f64) -> bool {
+        match self {
+            DepthTest::Less => depth <= stored,
+            DepthTest::Greater => depth >= stored,
+        }
+    }
+
+    pub fn reversed(self) -> Self {
+        match self {
+            DepthTest::Less => DepthTest::Greater,
+            DepthTest::Greater => DepthTest::Less,
+        }
+    }
+}
+
+/// What the pipeline did for one frame, see `GL::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RenderStats {
+    /// Triangles given to the rasterizer, and those of the meshes `Culler` skipped
+    pub triangles: u64,
+    /// Triangles crossing the near plane, cut to their part in front of the eye
+    pub clipped: u64,
+    /// Triangles behind the eye, back facing or degenerate, of which nothing was drawn, and
+    /// those of the meshes outside the view or occluded, see `GL::count_culled`
+    pub culled: u64,
+    /// Fragments that passed the depth test and were shaded
+    pub fragments: u64,
+    /// Fragments behind the depth buffer or outside the depth range
+    pub depth_rejects: u64,
+    /// Time spent in each stage, in pipeline order
+    pub stages: Vec<(&'static str, Duration)>,
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "triangles={} clipped={} culled={} fragments={} depth_rejects={}",
+            self.triangles, self.clipped, self.culled, self.fragments, self.depth_rejects
+        )
+    }
+}
+
+impl RenderStats {
+    /// The stages as a `Server-Timing` header, in milliseconds.
+    pub fn server_timing(&self) -> String {
+        self.stages
+            .iter()
+            .map(|(stage, time)| format!("{};dur={:.1}", stage, time.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Counted through `&self`, the shaders hold a shared reference to the `GL`.
+#[derive(Debug, Default)]
+struct Counters {
+    triangles: Cell<u64>,
+    clipped: Cell<u64>,
+    culled: Cell<u64>,
+    fragments: Cell<u64>,
+    depth_rejects: Cell<u64>,
+    raster: Cell<Duration>,
+    lighting: Cell<Duration>,
+}
+
+fn increment(counter: &Cell<u64>) {
+    counter.set(counter.get() + 1);
+}
 
 #[derive(Debug)]
 pub struct GL {
-    pub model_view: Mat<f64>,
-    pub projection: Mat<f64>,
-    pub viewport: Mat<f64>,
+    pub model_view: Mat4f,
+    pub projection: Mat4f,
+    pub viewport: Mat4f,
 
     pub light_dir: Vec3f,
+    pub lights: Vec<Light>, // used by the deferred lighting pass
     pub width: u32,
     pub height: u32,
+
+    pub depth_test: DepthTest,
+    /// Clamp depth to [0, 1] instead of clipping fragments against the near/far planes
+    pub depth_clamp: bool,
+
+    counters: Counters,
+    created: Instant,
 }
 
 pub trait IShader {
     fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f;
-    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<u8>;
+    /// `gl_fragcoord` is the pixel position and depth of the fragment.
+    /// Returns the fragment color in linear space, values above 1.0 are kept for tone mapping.
+    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<f32>;
+    /// Called by `GL::draw_instanced` before the triangles of each instance.
+    fn instance(&mut self, uniforms: &InstanceUniforms) {}
+}
+
+/// Geometry stage of the deferred mode, the fragment fills G-buffer targets instead of
+/// returning a color.
+pub trait IGeometryShader {
+    fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f;
+    fn fragment(&mut self, bc: Vec3f) -> Surface;
+}
+
+/// Vertex stage of a shader whose outputs depend on the vertex alone, not on the face using it,
+/// so `GL::draw_indexed` can run it once per vertex of the indexed buffer of the model.
+pub trait IIndexedShader {
+    /// What the vertex stage passes on to the fragment stage for one vertex.
+    type Varying: Copy;
+
+    /// Clip coordinates (after viewport) and varyings of `model.vertices[ivert]`.
+    fn vertex_indexed(&mut self, ivert: usize) -> (Vec4f, Self::Varying);
+    /// Makes `varying` the output of corner `nthvert` of the next triangle.
+    fn load_varying(&mut self, nthvert: usize, varying: &Self::Varying);
+
+    /// `IShader::vertex` in terms of the indexed stage, for the per-face draws.
+    fn vertex_by_face(&mut self, model: &Model, iface: usize, nthvert: usize) -> Vec4f {
+        let (position, varying) = self.vertex_indexed(model.index(iface, nthvert));
+        self.load_varying(nthvert, &varying);
+        position
+    }
+}
+
+/// Post-transform cache of one draw. The vertex stage runs the first time a vertex is used,
+/// the later faces sharing it reuse the outputs.
+#[derive(Debug)]
+pub struct VertexCache<V> {
+    outputs: Vec<Option<(Vec4f, V)>>,
+    /// How many times the vertex stage ran
+    pub invocations: usize,
+}
+
+impl<V: Copy> VertexCache<V> {
+    pub fn new(nverts: usize) -> Self {
+        Self {
+            outputs: vec![None; nverts],
+            invocations: 0,
+        }
+    }
+
+    pub fn get(
+        &mut self,
+        ivert: usize,
+        shader: &mut impl IIndexedShader<Varying = V>,
+    ) -> (Vec4f, V) {
+        *self.outputs[ivert].get_or_insert_with(|| {
+            self.invocations += 1;
+            shader.vertex_indexed(ivert)
+        })
+    }
+
+    /// Feeds the three corners of `face` through the cache, returns their clip coordinates
+    /// with the varyings loaded into `shader`.
+    fn triangle<S: IIndexedShader<Varying = V>>(
+        &mut self,
+        face: &[usize; 3],
+        shader: &mut S,
+    ) -> [Vec4f; 3] {
+        let mut pts = [Vec4f::zero(); 3];
+        for (nthvert, &ivert) in face.iter().enumerate() {
+            let (position, varying) = self.get(ivert, shader);
+            shader.load_varying(nthvert, &varying);
+            pts[nthvert] = position;
+        }
+        pts
+    }
 }
 
 pub fn barycentric(pts: [Vec2f; 3], p: Vec2f) -> Vec3f {
-    let abc: Mat<f64> = Mat::from(&vec![
-        embed::<f64, 3, 2>(&pts[0], 1.0).to_vec(),
-        embed::<f64, 3, 2>(&pts[1], 1.0).to_vec(),
-        embed::<f64, 3, 2>(&pts[2], 1.0).to_vec(),
-    ]);
-    if abc.det() < 1e-3 {
-        return Vec3f::from([-1.0, 1.0, 1.0]);
+    match EdgeFunctions::new(pts) {
+        Some(edges) => edges.at(p[0], p[1]),
+        None => Vec3f::from([-1.0, 1.0, 1.0]),
+    }
+}
+
+/// The screen space barycentric coordinates of a triangle as affine functions of the pixel
+/// position, `bc[i] = w[i][0] * x + w[i][1] * y + w[i][2]`. Set up once per triangle so the
+/// pixel loop is only multiplies and adds.
+#[derive(Debug, Clone, Copy)]
+struct EdgeFunctions {
+    w: Mat3f,
+}
+
+impl EdgeFunctions {
+    /// `None` for degenerate and back facing triangles.
+    fn new(pts: [Vec2f; 3]) -> Option<Self> {
+        let abc = Mat3f::from(pts.map(|p| embed::<f64, 3, 2>(&p, 1.0).data));
+        if abc.det() < 1e-3 {
+            return None;
+        }
+        Some(Self {
+            w: abc.invert_transpose(),
+        })
+    }
+
+    fn at(&self, x: f64, y: f64) -> Vec3f {
+        let w = &self.w;
+        Vec3f::from([
+            w[0][0] * x + w[0][1] * y + w[0][2],
+            w[1][0] * x + w[1][1] * y + w[1][2],
+            w[2][0] * x + w[2][1] * y + w[2][2],
+        ])
+    }
+}
+
+/// A triangle after the perspective divide, ready to be sampled at pixel centers.
+#[derive(Debug, Clone, Copy)]
+struct ScreenTriangle {
+    edges: EdgeFunctions,
+    depth: Vec3f,
+}
+
+impl ScreenTriangle {
+    fn new(pts: [Vec3f; 3]) -> Option<Self> {
+        Some(Self {
+            edges: EdgeFunctions::new(pts.map(|p| proj::<_, 2, 3>(&p)))?,
+            depth: Vec3f::from([pts[0][2], pts[1][2], pts[2][2]]),
+        })
+    }
+
+    /// Barycentric coordinates and depth of the pixel, `None` when it is not covered.
+    fn sample(&self, x: i32, y: i32) -> Option<(Vec3f, f64)> {
+        let bc = self.edges.at(x as f64, y as f64);
+        if bc[0] < 0.0 || bc[1] < 0.0 || bc[2] < 0.0 {
+            return None;
+        }
+        // depth after the perspective divide is affine in screen space
+        let depth = self.depth[0] * bc[0] + self.depth[1] * bc[1] + self.depth[2] * bc[2];
+        Some((bc, depth))
+    }
+
+    /// `sample` of the pixels (x, y) to (x + 3, y) at once. Every lane goes through the same
+    /// operations in the same order, so the results are bit for bit those of `sample`.
+    #[cfg(feature = "simd")]
+    fn sample_x4(&self, x: i32, y: i32) -> [Option<(Vec3f, f64)>; 4] {
+        use wide::{f64x4, CmpLt};
+
+        let x = x as f64;
+        let xs = f64x4::new([x, x + 1.0, x + 2.0, x + 3.0]);
+        let ys = f64x4::splat(y as f64);
+        let w = &self.edges.w;
+        let bc = [0, 1, 2].map(|i| {
+            xs * f64x4::splat(w[i][0]) + ys * f64x4::splat(w[i][1]) + f64x4::splat(w[i][2])
+        });
+        let zero = f64x4::ZERO;
+        let outside = bc[0].cmp_lt(zero) | bc[1].cmp_lt(zero) | bc[2].cmp_lt(zero);
+        let mask = outside.move_mask();
+        if mask == 0b1111 {
+            return [None; 4];
+        }
+        let depth = f64x4::splat(self.depth[0]) * bc[0]
+            + f64x4::splat(self.depth[1]) * bc[1]
+            + f64x4::splat(self.depth[2]) * bc[2];
+        let (bc, depth) = (bc.map(f64x4::to_array), depth.to_array());
+        std::array::from_fn(|lane| {
+            (mask & (1 << lane) == 0).then(|| {
+                (
+                    Vec3f::from([bc[0][lane], bc[1][lane], bc[2][lane]]),
+                    depth[lane],
+                )
+            })
+        })
     }
-    return Vec3f::from_vec(&(abc.invert_transpose()) * &embed::<f64, 3, 2>(&p, 1.0));
 }
 
 impl GL {
     pub fn new(light_dir: Vec3f, w: u32, h: u32) -> Self {
         Self {
-            model_view: Mat::identity(4),
-            projection: Mat::identity(4),
-            viewport: Mat::identity(4),
+            model_view: Mat4f::identity(),
+            projection: Mat4f::identity(),
+            viewport: Mat4f::identity(),
             light_dir: light_dir,
+            lights: Vec::new(),
             width: w,
             height: h,
+            depth_test: DepthTest::Greater,
+            depth_clamp: true,
+            counters: Counters::default(),
+            created: Instant::now(),
+        }
+    }
+
+    /// Counts the triangles of the meshes `stats` skipped as given and culled, they never
+    /// reach the rasterizer.
+    pub fn count_culled(&self, stats: &CullStats) {
+        let n = stats.triangles_culled as u64;
+        let c = &self.counters;
+        c.triangles.set(c.triangles.get() + n);
+        c.culled.set(c.culled.get() + n);
+    }
+
+    /// What was drawn so far. The time outside the rasterizer and the lighting pass since
+    /// `new` is the `geometry` stage: the vertex shaders, culling and the setup of the render.
+    pub fn stats(&self) -> RenderStats {
+        let c = &self.counters;
+        let (raster, lighting) = (c.raster.get(), c.lighting.get());
+        let mut stages = vec![
+            (
+                "geometry",
+                self.created
+                    .elapsed()
+                    .saturating_sub(raster)
+                    .saturating_sub(lighting),
+            ),
+            ("raster", raster),
+        ];
+        if !lighting.is_zero() {
+            stages.push(("lighting", lighting));
+        }
+        RenderStats {
+            triangles: c.triangles.get(),
+            clipped: c.clipped.get(),
+            culled: c.culled.get(),
+            fragments: c.fragments.get(),
+            depth_rejects: c.depth_rejects.get(),
+            stages,
         }
     }
 
+    /// Width over height of the frame.
+    pub fn aspect(&self) -> f64 {
+        self.width as f64 / self.height as f64
+    }
+
     pub fn lookat(&mut self, eye: Vec3f, center: Vec3f, up: Vec3f) {
-        self.model_view = lookat(eye, center, up);
+        self.model_view = camera::lookat(eye, center, up);
     }
 
+    /// Legacy projection, see `camera::projection`.
     pub fn projection(&mut self, coeff: f64) {
-        self.projection[3][2] = coeff;
+        self.projection = camera::projection(coeff);
+        self.depth_test = DepthTest::Greater;
+        self.depth_clamp = true;
+    }
+
+    /// Symmetric perspective with the vertical field of view `fovy` in radians.
+    pub fn perspective(&mut self, fovy: f64, aspect: f64, near: f64, far: f64) {
+        self.set_projection(camera::perspective(fovy, aspect, near, far));
+    }
+
+    /// Off-center perspective, the edges are given on the near plane.
+    pub fn frustum(&mut self, left: f64, right: f64, bottom: f64, top: f64, near: f64, far: f64) {
+        self.set_projection(camera::frustum(left, right, bottom, top, near, far));
     }
 
+    pub fn orthographic(
+        &mut self,
+        left: f64,
+        right: f64,
+        bottom: f64,
+        top: f64,
+        near: f64,
+        far: f64,
+    ) {
+        self.set_projection(camera::orthographic(left, right, bottom, top, near, far));
+    }
+
+    /// Turn the current orthographic projection into an oblique one, see `camera::oblique`.
+    pub fn oblique(&mut self, angle: f64, depth_scale: f64, focus: f64) {
+        self.projection = self.projection * camera::oblique(angle, depth_scale, focus);
+    }
+
+    /// Switch the current projection to reversed-Z (or back), depth buffers must be cleared
+    /// with the new `depth_buffer`.
+    pub fn reverse_z(&mut self) {
+        self.projection = camera::reverse_z(&self.projection);
+        self.depth_test = self.depth_test.reversed();
+    }
+
+    fn set_projection(&mut self, projection: Mat4f) {
+        self.projection = projection;
+        self.depth_test = DepthTest::Less;
+        self.depth_clamp = false;
+    }
+
+    /// Maps NDC to pixels, and NDC depth [-1, 1] to [0, 1]. The viewport is at least one
+    /// pixel wide and high, the fractions of tiny images the renders take round to nothing.
     pub fn viewport(&mut self, x: i32, y: i32, w: i32, h: i32) {
-        self.viewport = Mat::from(&vec![
-            vec![w as f64 / 2.0, 0.0, 0.0, x as f64 + w as f64 / 2.0],
-            vec![0.0, h as f64 / 2.0, 0.0, y as f64 + h as f64 / 2.],
-            vec![0.0, 0.0, DEPTH / 2.0, DEPTH / 2.0],
-            vec![0.0, 0.0, 0.0, 1.0],
+        let (w, h) = (w.max(1), h.max(1));
+        self.viewport = Mat4f::from([
+            [w as f64 / 2.0, 0.0, 0.0, x as f64 + w as f64 / 2.0],
+            [0.0, h as f64 / 2.0, 0.0, y as f64 + h as f64 / 2.],
+            [0.0, 0.0, 0.5, 0.5],
+            [0.0, 0.0, 0.0, 1.0],
         ]);
     }
 
-    pub fn triangle(
+    /// A cleared depth buffer for the current depth test.
+    pub fn depth_buffer(&self) -> Vec<f64> {
+        vec![self.depth_test.clear_value(); (self.width * self.height) as usize]
+    }
+
+    /// Whether the eye sits at the view space origin, false for parallel projections.
+    pub fn is_perspective(&self) -> bool {
+        self.projection[3][2] != 0.0
+    }
+
+    /// Scan convert a triangle given in clip coordinates (after viewport), calling `plot`
+    /// with the pixel, barycentric coordinates and depth of every fragment that passes the
+    /// depth test. `zbuf` is updated for those fragments.
+    pub fn rasterize(
         &self,
         pts: [Vec4f; 3],
-        shader: &mut impl IShader,
-        img: &mut RgbaImage,
-        zbuf: &mut Vec<f64>,
+        zbuf: &mut [f64],
+        mut plot: impl FnMut(u32, u32, Vec3f, f64),
     ) {
-        let pts2: [Vec2f; 3] = [
-            proj::<_, 2, 4>(&(pts[0] / pts[0][3])),
-            proj::<_, 2, 4>(&(pts[1] / pts[1][3])),
-            proj::<_, 2, 4>(&(pts[2] / pts[2][3])),
-        ];
+        let start = Instant::now();
+        increment(&self.counters.triangles);
+        if matches!(pts.iter().filter(|p| p[3] <= W_EPSILON).count(), 1 | 2) {
+            increment(&self.counters.clipped);
+        }
+        let clipped = clip_near(pts);
+        let mut drawn = false;
+        // the clipped polygon is convex, fan it into triangles
+        for k in 1..clipped.len().saturating_sub(1) {
+            drawn |=
+                self.rasterize_clipped([clipped[0], clipped[k], clipped[k + 1]], zbuf, &mut plot);
+        }
+        if !drawn {
+            increment(&self.counters.culled);
+        }
+        let raster = &self.counters.raster;
+        raster.set(raster.get() + start.elapsed());
+    }
+
+    /// `tri` holds the clip coordinates of each vertex and its barycentric coordinates in
+    /// the triangle passed to `rasterize`. Whether it faces the camera and is not degenerate.
+    fn rasterize_clipped(
+        &self,
+        tri: [(Vec4f, Vec3f); 3],
+        zbuf: &mut [f64],
+        plot: &mut impl FnMut(u32, u32, Vec3f, f64),
+    ) -> bool {
+        let pts3: [Vec3f; 3] = tri.map(|(p, _)| proj::<_, 3, 4>(&(p / p[3])));
+        let Some(screen) = ScreenTriangle::new(pts3) else {
+            return false;
+        };
 
         let mut bboxmin = Vec2f::from([std::f64::MAX, std::f64::MAX]);
         let mut bboxmax = Vec2f::from([-std::f64::MAX, -std::f64::MAX]);
+        let clamp = Vec2f::from([self.width as f64 - 1.0, self.height as f64 - 1.0]);
         for i in 0..3 {
             for j in 0..2 {
-                bboxmin[j] = minf(bboxmin[j], pts[i][j] / pts[i][3]);
-                bboxmax[j] = maxf(bboxmax[j], pts[i][j] / pts[i][3]);
+                bboxmin[j] = maxf(0.0, minf(bboxmin[j], pts3[i][j]));
+                bboxmax[j] = minf(clamp[j], maxf(bboxmax[j], pts3[i][j]));
             }
         }
 
-        let mut p = Vec2f::new();
-        for x in bboxmin[0] as i32..=bboxmax[0] as i32 {
-            for y in bboxmin[1] as i32..=bboxmax[1] as i32 {
-                p[0] = x as f64;
-                p[1] = y as f64;
-                let bc_screen = barycentric(pts2, p);
-                let z =
-                    pts[0][2] * bc_screen[0] + pts[1][2] * bc_screen[1] + pts[2][2] * bc_screen[2];
-                let w =
-                    pts[0][3] * bc_screen[0] + pts[1][3] * bc_screen[1] + pts[2][3] * bc_screen[2];
-                let frag_depth = maxf(0.0, minf(255.0, z / w + 0.5));
-                let idx = (x + y * img.width() as i32) as usize;
-                if bc_screen[0] < 0.0
-                    || bc_screen[1] < 0.0
-                    || bc_screen[2] < 0.0
-                    || zbuf[idx] > frag_depth
-                {
-                    continue;
+        let mut fragment = |x: i32, y: i32, bc_screen: Vec3f, mut frag_depth: f64| {
+            let idx = (x + y * self.width as i32) as usize;
+            if self.depth_clamp {
+                frag_depth = maxf(0.0, minf(1.0, frag_depth));
+            } else if !(0.0..=1.0).contains(&frag_depth) {
+                increment(&self.counters.depth_rejects);
+                return;
+            }
+            if !self.depth_test.passes(frag_depth, zbuf[idx]) {
+                increment(&self.counters.depth_rejects);
+                return;
+            }
+            increment(&self.counters.fragments);
+            let bc = tri[0].1 * bc_screen[0] + tri[1].1 * bc_screen[1] + tri[2].1 * bc_screen[2];
+            plot(x as u32, y as u32, bc, frag_depth);
+            zbuf[idx] = frag_depth;
+        };
+
+        // row by row, the pixels of a row are next to each other in `zbuf` and the images
+        let (xmin, xmax) = (bboxmin[0] as i32, bboxmax[0] as i32);
+        for y in bboxmin[1] as i32..=bboxmax[1] as i32 {
+            // runs of four pixels go through the SIMD path, the remainder one by one
+            #[cfg(feature = "simd")]
+            let rest = {
+                let mut x = xmin;
+                while x + 3 <= xmax {
+                    for (lane, sample) in screen.sample_x4(x, y).into_iter().enumerate() {
+                        if let Some((bc, depth)) = sample {
+                            fragment(x + lane as i32, y, bc, depth);
+                        }
+                    }
+                    x += 4;
                 }
+                x
+            };
+            #[cfg(not(feature = "simd"))]
+            let rest = xmin;
+            for x in rest..=xmax {
+                if let Some((bc, depth)) = screen.sample(x, y) {
+                    fragment(x, y, bc, depth);
+                }
+            }
+        }
+        true
+    }
 
-                let color = shader.fragment(bc_screen, Vec3f::ZERO);
-                zbuf[idx] = frag_depth;
-                img.put_pixel(x as u32, y as u32, color);
+    pub fn triangle(
+        &self,
+        pts: [Vec4f; 3],
+        shader: &mut impl IShader,
+        img: &mut HdrImage,
+        zbuf: &mut [f64],
+    ) {
+        self.rasterize(pts, zbuf, |x, y, bc, depth| {
+            let color = shader.fragment(bc, Vec3f::from([x as f64, y as f64, depth]));
+            img.put_pixel(x, y, color);
+        });
+    }
+
+    /// Draws all faces of `model` through its indexed vertex buffer, running the vertex stage
+    /// once per vertex. Returns how many times it ran.
+    pub fn draw_indexed<S: IShader + IIndexedShader>(
+        &self,
+        model: &Model,
+        shader: &mut S,
+        img: &mut HdrImage,
+        zbuf: &mut [f64],
+    ) -> usize {
+        let mut cache = VertexCache::new(model.vertices.len());
+        for face in model.indices.iter() {
+            let pts = cache.triangle(face, shader);
+            self.triangle(pts, shader, img, zbuf);
+        }
+        cache.invocations
+    }
+
+    /// `draw_indexed` for the geometry pass of the deferred mode.
+    pub fn draw_indexed_deferred<S: IGeometryShader + IIndexedShader>(
+        &self,
+        model: &Model,
+        shader: &mut S,
+        gbuffer: &mut GBuffer,
+    ) -> usize {
+        let mut cache = VertexCache::new(model.vertices.len());
+        for face in model.indices.iter() {
+            let pts = cache.triangle(face, shader);
+            self.triangle_deferred(pts, shader, gbuffer);
+        }
+        cache.invocations
+    }
+
+    /// Draws `model` once per instance without copying its geometry. The shader receives the
+    /// uniforms of each instance before its triangles, the matrices in them are computed once
+    /// per instance and the model bounds once for all of them. Instances rejected by `culler`
+    /// are skipped, the others go through `draw_indexed`.
+    pub fn draw_instanced<S: IShader + IIndexedShader>(
+        &self,
+        model: &Model,
+        instances: &[Instance],
+        shader: &mut S,
+        img: &mut HdrImage,
+        zbuf: &mut [f64],
+        culler: &mut Culler,
+    ) {
+        let bounds = model.bbox();
+        // rebuilt only once an instance wrote to the depth buffer
+        let mut hiz: Option<HiZ> = None;
+        for (index, instance) in instances.iter().enumerate() {
+            let uniforms = instance.uniforms(index, self);
+            if culler.culling.occlusion && hiz.is_none() {
+                hiz = Some(HiZ::new(self, zbuf));
+            }
+            if !culler.test_instance(&bounds, &uniforms.model, model.nfaces(), hiz.as_ref()) {
+                continue;
+            }
+            shader.instance(&uniforms);
+            let fragments = self.counters.fragments.get();
+            self.draw_indexed(model, shader, img, zbuf);
+            if self.counters.fragments.get() != fragments {
+                hiz = None;
             }
         }
     }
+
+    /// Geometry pass of the deferred mode, writes the surface attributes instead of a color.
+    pub fn triangle_deferred(
+        &self,
+        pts: [Vec4f; 3],
+        shader: &mut impl IGeometryShader,
+        gbuffer: &mut GBuffer,
+    ) {
+        let mut depth = std::mem::take(&mut gbuffer.depth);
+        self.rasterize(pts, &mut depth, |x, y, bc, _| {
+            let surface = shader.fragment(bc);
+            gbuffer.set(x, y, surface);
+        });
+        gbuffer.depth = depth;
+    }
+
+    /// Lighting pass of the deferred mode, shades every covered G-buffer pixel once.
+    pub fn lighting_pass(&self, gbuffer: &GBuffer, img: &mut HdrImage) {
+        let start = Instant::now();
+        let lights: Vec<Light> = self
+            .lights
+            .iter()
+            .map(|l| l.to_view(&self.model_view))
+            .collect();
+        let perspective = self.is_perspective();
+        for y in 0..gbuffer.height {
+            for x in 0..gbuffer.width {
+                let idx = (x + y * gbuffer.width) as usize;
+                if !gbuffer.covered(idx) {
+                    continue;
+                }
+                let p = gbuffer.position[idx];
+                // the camera sits at the origin, or at infinity for parallel projections
+                let v = if perspective {
+                    (Vec3f::ZERO - p).normalize()
+                } else {
+                    Vec3f::from([0.0, 0.0, 1.0])
+                };
+                let mut color = gbuffer.emission[idx];
+                for light in lights.iter() {
+                    let c = light.shade(
+                        p,
+                        gbuffer.normal[idx],
+                        v,
+                        gbuffer.albedo[idx],
+                        gbuffer.specular[idx],
+                    );
+                    for i in 0..3 {
+                        color[i] += c[i];
+                    }
+                }
+                color[3] = 1.0;
+                img.put_pixel(x, y, color);
+            }
+        }
+        let lighting = &self.counters.lighting;
+        lighting.set(lighting.get() + start.elapsed());
+    }
+}
+
+/// Sutherland-Hodgman clip of the triangle against the plane w = W_EPSILON, which keeps
+/// everything in front of the eye. The near and far planes themselves are applied per
+/// fragment by the depth range test. Each output vertex carries its barycentric coordinates
+/// in `pts`.
+fn clip_near(pts: [Vec4f; 3]) -> Vec<(Vec4f, Vec3f)> {
+    let corners = [
+        Vec3f::from([1.0, 0.0, 0.0]),
+        Vec3f::from([0.0, 1.0, 0.0]),
+        Vec3f::from([0.0, 0.0, 1.0]),
+    ];
+    let mut out = Vec::with_capacity(4);
+    for i in 0..3 {
+        let j = (i + 1) % 3;
+        let (a, b) = (pts[i], pts[j]);
+        let (a_in, b_in) = (a[3] > W_EPSILON, b[3] > W_EPSILON);
+        if a_in {
+            out.push((a, corners[i]));
+        }
+        if a_in != b_in {
+            let t = (W_EPSILON - a[3]) / (b[3] - a[3]);
+            out.push((a + (b - a) * t, corners[i] + (corners[j] - corners[i]) * t));
+        }
+    }
+    out
 }
 
 impl Drop for GL {
@@ -137,4 +719,186 @@ mod tests {
         let bary = barycentric(pts, p);
         assert_eq!(bary, Vec3f::from([0.0, 0.5, 0.5]));
     }
+
+    #[test]
+    fn test_clip_near() {
+        let pts = [
+            Vec4f::from([0.0, 0.0, 0.0, 1.0]),
+            Vec4f::from([1.0, 0.0, 0.0, 1.0]),
+            Vec4f::from([0.0, 1.0, 0.0, -1.0]),
+        ];
+        let clipped = clip_near(pts);
+        assert_eq!(clipped.len(), 4);
+        for (p, bc) in clipped {
+            assert!(p[3] > 0.0);
+            assert!((bc[0] + bc[1] + bc[2] - 1.0).abs() < 1e-9);
+        }
+        // nothing left when the triangle is behind the eye
+        assert!(clip_near([pts[2]; 3]).is_empty());
+    }
+
+    #[test]
+    fn test_viewport() {
+        let mut gl = GL::new(Vec3f::ZERO, 1, 1);
+        // 3/4 of the image, as the renders take it
+        gl.viewport(0, 0, 0, 0);
+        assert!(gl.viewport.try_invert().is_some());
+        assert_eq!((gl.viewport[0][0], gl.viewport[1][1]), (0.5, 0.5));
+    }
+
+    #[test]
+    fn test_depth_clip() {
+        let mut gl = GL::new(Vec3f::ZERO, 4, 4);
+        gl.viewport(0, 0, 4, 4);
+        let tri = |z: f64| {
+            [
+                Vec4f::from([0.0, 0.0, z, 1.0]),
+                Vec4f::from([4.0, 0.0, z, 1.0]),
+                Vec4f::from([0.0, 4.0, z, 1.0]),
+            ]
+        };
+        let count = |gl: &GL, z: f64| {
+            let mut zbuf = gl.depth_buffer();
+            let mut n = 0;
+            gl.rasterize(tri(z), &mut zbuf, |_, _, _, _| n += 1);
+            n
+        };
+        // clamped by default, clipped once a projection with near/far planes is set
+        assert!(count(&gl, 2.0) > 0);
+        gl.perspective(1.0, 1.0, 1.0, 10.0);
+        assert_eq!(count(&gl, 2.0), 0);
+        assert!(count(&gl, 0.5) > 0);
+        assert_eq!(gl.depth_buffer()[0], f64::INFINITY);
+        gl.reverse_z();
+        assert_eq!(gl.depth_test, DepthTest::Greater);
+    }
+
+    /// Records the vertices its vertex stage ran on, every triangle lands on the same pixels.
+    #[derive(Default)]
+    struct CountingShader {
+        runs: Vec<usize>,
+        loaded: [usize; 3],
+    }
+
+    impl IIndexedShader for CountingShader {
+        type Varying = usize;
+
+        fn vertex_indexed(&mut self, ivert: usize) -> (Vec4f, usize) {
+            let corner = [[0.0, 0.0], [4.0, 0.0], [0.0, 4.0]][ivert % 3];
+            self.runs.push(ivert);
+            (Vec4f::from([corner[0], corner[1], 0.5, 1.0]), ivert)
+        }
+
+        fn load_varying(&mut self, nthvert: usize, ivert: &usize) {
+            self.loaded[nthvert] = *ivert;
+        }
+    }
+
+    impl IShader for CountingShader {
+        fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f {
+            unreachable!("only drawn indexed")
+        }
+
+        fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<f32> {
+            Rgba([1.0, 1.0, 1.0, 1.0])
+        }
+    }
+
+    #[test]
+    fn test_draw_indexed() {
+        let model = Model::new("obj/african_head/african_head.obj");
+        let gl = GL::new(Vec3f::ZERO, 4, 4);
+        let mut shader = CountingShader::default();
+        let mut img = HdrImage::new(4, 4);
+        let mut zbuf = gl.depth_buffer();
+        let invocations = gl.draw_indexed(&model, &mut shader, &mut img, &mut zbuf);
+        // once per vertex of the buffer, against three times per face without the cache
+        assert_eq!(invocations, model.vertices.len());
+        let mut runs = shader.runs.clone();
+        runs.sort();
+        runs.dedup();
+        assert_eq!(runs.len(), invocations);
+        assert!(invocations * 2 < model.nfaces() * 3);
+        assert_eq!(shader.loaded, model.indices[model.nfaces() - 1]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let gl = GL::new(Vec3f::ZERO, 8, 8);
+        let mut zbuf = gl.depth_buffer();
+        let tri = |z: f64, w: [f64; 3]| {
+            [[0.0, 0.0], [7.0, 0.0], [0.0, 7.0]]
+                .iter()
+                .zip(w)
+                .map(|(p, w)| Vec4f::from([p[0] * w, p[1] * w, z * w, w]))
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap()
+        };
+        gl.rasterize(tri(0.5, [1.0; 3]), &mut zbuf, |_, _, _, _| {});
+        let drawn = gl.stats().fragments;
+        // x + y <= 7
+        assert_eq!(drawn, 36);
+        // hidden behind the first one
+        gl.rasterize(tri(0.4, [1.0; 3]), &mut zbuf, |_, _, _, _| {});
+        let stats = gl.stats();
+        assert_eq!((stats.fragments, stats.depth_rejects), (drawn, drawn));
+        // back facing, then behind the eye
+        let [a, b, c] = tri(0.5, [1.0; 3]);
+        gl.rasterize([a, c, b], &mut zbuf, |_, _, _, _| {});
+        gl.rasterize(tri(0.5, [-1.0; 3]), &mut zbuf, |_, _, _, _| {});
+        // crossing the near plane
+        let c = Vec4f::from([0.0, 7.0, 0.5, -1.0]);
+        gl.rasterize([a, b, c], &mut zbuf, |_, _, _, _| {});
+
+        let stats = gl.stats();
+        assert_eq!((stats.triangles, stats.clipped, stats.culled), (5, 1, 2));
+        gl.count_culled(&CullStats {
+            triangles: 10,
+            triangles_culled: 4,
+            ..CullStats::default()
+        });
+        assert_eq!((gl.stats().triangles, gl.stats().culled), (9, 6));
+        assert_eq!(stats.to_string().split(' ').count(), 5);
+        let stages: Vec<&str> = stats.stages.iter().map(|(s, _)| *s).collect();
+        assert_eq!(stages, ["geometry", "raster"]);
+        assert!(stats.server_timing().starts_with("geometry;dur="));
+    }
+
+    #[cfg(feature = "simd")]
+    #[test]
+    fn test_sample_x4_matches_scalar() {
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut rand = |scale: f64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 53) as f64 * scale
+        };
+        for _ in 0..200 {
+            let pts = [0; 3].map(|_| Vec3f::from([rand(64.0), rand(64.0), rand(1.0)]));
+            let Some(screen) = ScreenTriangle::new(pts) else {
+                continue;
+            };
+            for y in 0..64 {
+                for x in (0..64).step_by(4) {
+                    let lanes = screen.sample_x4(x, y);
+                    for (lane, sample) in lanes.into_iter().enumerate() {
+                        let scalar = screen.sample(x + lane as i32, y);
+                        // compare bits, so -0.0 and NaN would show up too
+                        let bits = |s: Option<(Vec3f, f64)>| {
+                            s.map(|(bc, z)| (bc.data.map(f64::to_bits), z.to_bits()))
+                        };
+                        assert_eq!(
+                            bits(sample),
+                            bits(scalar),
+                            "pixel ({}, {})",
+                            x + lane as i32,
+                            y
+                        );
+                    }
+                }
+            }
+        }
+    }
 }
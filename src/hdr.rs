@@ -0,0 +1,148 @@
+use image::{ImageBuffer, Rgba, Rgba32FImage, RgbaImage};
+
+/// Render target holding linear, unclamped RGBA values.
+pub type HdrImage = Rgba32FImage;
+
+/// Operator used to compress linear HDR values into the displayable [0, 1] range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    /// Plain clamp, highlights above 1.0 are clipped
+    Clamp,
+    /// `c / (1 + c)`
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve
+    Aces,
+    /// `1 - exp(-c)`
+    Exposure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMapping {
+    pub operator: Operator,
+    /// Linear scale applied before the operator
+    pub exposure: f32,
+}
+
+impl Operator {
+    /// The names of `from_name`, without the aliases.
+    pub const NAMES: [&'static str; 4] = ["clamp", "reinhard", "aces", "exposure"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "clamp" | "none" => Some(Self::Clamp),
+            "reinhard" => Some(Self::Reinhard),
+            "aces" | "filmic" => Some(Self::Aces),
+            "exposure" | "exp" => Some(Self::Exposure),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Clamp => "clamp",
+            Self::Reinhard => "reinhard",
+            Self::Aces => "aces",
+            Self::Exposure => "exposure",
+        }
+    }
+
+    pub fn apply(&self, c: f32) -> f32 {
+        let c = c.max(0.0);
+        let r = match self {
+            Self::Clamp => c,
+            Self::Reinhard => c / (1.0 + c),
+            Self::Aces => (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14),
+            Self::Exposure => 1.0 - (-c).exp(),
+        };
+        r.clamp(0.0, 1.0)
+    }
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self {
+            operator: Operator::Clamp,
+            exposure: 1.0,
+        }
+    }
+}
+
+impl ToneMapping {
+    pub fn new(operator: Operator, exposure: f32) -> Self {
+        Self { operator, exposure }
+    }
+
+    /// Tone map and sRGB encode a linear render target into a displayable image.
+    pub fn resolve(&self, hdr: &HdrImage) -> RgbaImage {
+        ImageBuffer::from_fn(hdr.width(), hdr.height(), |x, y| {
+            let c = hdr.get_pixel(x, y);
+            let mut rs = [0; 4];
+            for i in 0..3 {
+                rs[i] = linear_to_srgb(self.operator.apply(c[i] * self.exposure));
+            }
+            rs[3] = (c[3].clamp(0.0, 1.0) * 255.0 + 0.5) as u8;
+            Rgba(rs)
+        })
+    }
+}
+
+pub fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let s = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (s * 255.0 + 0.5) as u8
+}
+
+/// Decode an 8-bit sRGB color texture into linear floats, alpha is kept linear.
+pub fn decode_srgb(img: &RgbaImage) -> HdrImage {
+    ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let c = img.get_pixel(x, y);
+        Rgba([
+            srgb_to_linear(c[0]),
+            srgb_to_linear(c[1]),
+            srgb_to_linear(c[2]),
+            c[3] as f32 / 255.0,
+        ])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_srgb_roundtrip() {
+        for c in 0..=255u8 {
+            assert_eq!(linear_to_srgb(srgb_to_linear(c)), c);
+        }
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(Operator::Clamp.apply(4.0), 1.0);
+        assert_eq!(Operator::Reinhard.apply(1.0), 0.5);
+        assert!(Operator::Aces.apply(100.0) <= 1.0);
+        assert!(Operator::Exposure.apply(1.0) < 1.0);
+        assert_eq!(Operator::from_name("ACES"), Some(Operator::Aces));
+        assert_eq!(Operator::from_name("foo"), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let hdr: HdrImage = ImageBuffer::from_pixel(2, 2, Rgba([1.0, 0.5, 8.0, 1.0]));
+        let img = ToneMapping::default().resolve(&hdr);
+        assert_eq!(*img.get_pixel(0, 0), Rgba([255, 188, 255, 255]));
+    }
+}
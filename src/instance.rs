@@ -0,0 +1,126 @@
+use image::Rgba;
+
+use crate::{bounds::Aabb, gl::GL, matrix::Mat4f, transform::Transform};
+
+/// Constant surface parameters used instead of the texture lookups of a model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Material {
+    pub albedo: Rgba<f32>, // linear
+    pub specular: f64,     // specular exponent
+    pub emission: Rgba<f32>,
+}
+
+/// One copy of a model in the scene.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instance {
+    /// Object to world
+    pub transform: Transform,
+    /// Multiplies the albedo
+    pub tint: [f32; 3],
+    pub material: Option<Material>,
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Self {
+            transform: Transform::IDENTITY,
+            tint: [1.0, 1.0, 1.0],
+            material: None,
+        }
+    }
+}
+
+impl Instance {
+    pub fn new(transform: Transform) -> Self {
+        Self {
+            transform,
+            ..Self::default()
+        }
+    }
+
+    /// World space bounds of the instance of a model bounded by `bounds`.
+    pub fn bbox(&self, bounds: &Aabb) -> Aabb {
+        Aabb::from_points(&bounds.corners().map(|c| self.transform.transform_point(c)))
+    }
+
+    /// Everything the shaders need from instance `index`, computed once for all its vertices.
+    pub fn uniforms(&self, index: usize, gl: &GL) -> InstanceUniforms {
+        let model = self.transform.to_mat();
+        let model_view = gl.model_view * model;
+        InstanceUniforms {
+            index,
+            model,
+            model_view,
+            mvp: gl.viewport * gl.projection * model_view,
+            normal: model_view.invert_transpose(),
+            tint: self.tint,
+            material: self.material,
+        }
+    }
+}
+
+/// Per-instance state handed to `IShader::instance` before the triangles of the instance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InstanceUniforms {
+    pub index: usize,
+    /// Object to world
+    pub model: Mat4f,
+    /// Object to view
+    pub model_view: Mat4f,
+    /// Object to clip coordinates (after viewport)
+    pub mvp: Mat4f,
+    /// `model_view.invert_transpose()`, for normals
+    pub normal: Mat4f,
+    pub tint: [f32; 3],
+    pub material: Option<Material>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        geometry::{embed, Vec3f, Vec4f},
+        quat::Quat,
+    };
+
+    fn instance() -> Instance {
+        Instance::new(Transform {
+            translation: Vec3f::from([2.0, 0.0, -1.0]),
+            rotation: Quat::from_euler(0.0, 0.7, 0.0),
+            scale: Vec3f::from([0.5; 3]),
+        })
+    }
+
+    #[test]
+    fn test_uniforms() {
+        let mut gl = GL::new(Vec3f::ZERO, 64, 64);
+        gl.viewport(0, 0, 64, 64);
+        gl.lookat(
+            Vec3f::from([1.0, 1.0, 3.0]),
+            Vec3f::ZERO,
+            Vec3f::from([0.0, 1.0, 0.0]),
+        );
+        gl.perspective(1.0, 1.0, 0.1, 10.0);
+        let instance = instance();
+        let uniforms = instance.uniforms(3, &gl);
+        let p = Vec3f::from([0.3, -0.2, 0.9]);
+        let world = instance.transform.transform_point(p);
+        let expected = gl.viewport * gl.projection * gl.model_view * embed::<_, 4, 3>(&world, 1.0);
+        let got = uniforms.mvp * embed::<_, 4, 3>(&p, 1.0);
+        assert!((got - expected).norm() < 1e-9);
+        assert_eq!(uniforms.index, 3);
+        assert_eq!(uniforms.tint, [1.0; 3]);
+    }
+
+    #[test]
+    fn test_bbox() {
+        let bounds = Aabb {
+            min: Vec3f::from([-1.0; 3]),
+            max: Vec3f::from([1.0; 3]),
+        };
+        let bbox = instance().bbox(&bounds);
+        // the rotated cube still holds its center and grows past the scaled half size
+        assert!((bbox.center() - Vec3f::from([2.0, 0.0, -1.0])).norm() < 1e-9);
+        assert!(bbox.size()[0] > 1.0 && bbox.size()[1] == 1.0);
+    }
+}
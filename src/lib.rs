@@ -0,0 +1,30 @@
+#![allow(dead_code, unused)]
+pub mod animation;
+pub mod assets;
+pub mod bounds;
+pub mod cache;
+pub mod camera;
+pub mod config;
+pub mod culling;
+pub mod deferred;
+pub mod geometry;
+pub mod gl;
+pub mod hdr;
+pub mod instance;
+pub mod line;
+pub mod matrix;
+pub mod metrics;
+pub mod model;
+pub mod output;
+pub mod params;
+pub mod post;
+pub mod quat;
+pub mod routes;
+pub mod scene;
+pub mod shaders;
+pub mod transform;
+pub mod triangle;
+pub mod turntable;
+pub mod util;
+pub mod viewer;
+pub mod zbuf;
@@ -1,153 +1,814 @@
 #![allow(dead_code, unused)]
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::ToSocketAddrs,
+    process::ExitCode,
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
 use axum::{
-    http,
-    response::{AppendHeaders, IntoResponse},
-    routing::get,
-    Router,
+    body::{Body, StreamBody},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, MatchedPath, Multipart, Path, Query, State,
+    },
+    http::{self, HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{AppendHeaders, Html, IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
 };
-mod camera;
-mod geometry;
-mod gl;
-mod line;
-mod matrix;
-mod model;
-mod shaders;
-mod triangle;
-mod util;
-mod zbuf;
-
-#[tokio::main]
-async fn main() {
-    let app = Router::new()
-        .route("/sample-line", get(sample_line))
-        .route("/wire", get(wire))
-        .route("/sample-triangle", get(sample_triangle))
-        .route("/flat-shading", get(flat_shading))
-        .route("/linear-light", get(linear_light))
-        .route("/z-buf", get(z_buf))
-        .route("/move-camera", get(move_camera))
-        .route("/shaders/gouraud", get(shader_gouraud))
-        .route("/shaders/gouraud6l", get(shader_gouraud6l))
-        .route("/shaders/texture", get(shader_texture))
-        .route("/shaders/normalmapping", get(shader_normal_mapping))
-        .route("/shaders/specularmapping", get(shader_specular_mapping))
-        .route("/shaders/shadowmapping", get(shader_shadow_mapping))
-        .route("/shaders/ambientocclusion", get(shader_ambient_occlusion));
-
-    axum::Server::bind(&"0.0.0.0:8080".parse().unwrap())
-        .serve(app.into_make_service())
-        .await
+use futures_util::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use trrs::{
+    assets,
+    cache::{self, Cached, Lookup},
+    config::{Config, USAGE},
+    gl::RenderStats,
+    metrics,
+    output::{Format, Output, OutputError, DEFAULT_QUALITY},
+    params::{self, animation, camera_options, culling, output, post_process, render_options},
+    routes::{self, Render, RouteInfo, Source, RENDERS},
+    scene::{Scene, SceneError},
+    shaders::{self, Frame},
+    turntable,
+    util::RgbaImageExt,
+    viewer::{self, Viewer},
+};
+
+/// Largest accepted `POST /assets` body, uncompressed textures are big.
+const MAX_UPLOAD: usize = 64 << 20;
+/// Separates the frames of `/shaders/:name/stream`.
+const BOUNDARY: &str = "frame";
+
+/// One per worker, renders beyond them wait for a free one.
+static RENDER_SLOTS: OnceLock<Arc<tokio::sync::Semaphore>> = OnceLock::new();
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help") {
+        print!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    let config = match Config::load(&args, std::env::vars()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("trrs: {}\n\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    env_logger::Builder::new()
+        .filter_level(config.log)
+        .format_target(false)
+        .init();
+    for name in &config.ignored {
+        log::warn!("{} is not a setting, ignored", name);
+    }
+    // the models, uploads and the cache directory are relative paths
+    if let Err(err) = std::env::set_current_dir(&config.root) {
+        log::error!("cannot use {} as root: {}", config.root.display(), err);
+        return ExitCode::FAILURE;
+    }
+    params::set_max_size(config.max_size);
+    cache::init(config.cache.clone()).unwrap();
+    RENDER_SLOTS
+        .set(Arc::new(tokio::sync::Semaphore::new(config.workers)))
         .unwrap();
+
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.workers)
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(serve(config))
 }
 
-async fn wire() -> impl IntoResponse {
-    let bs = line::wireframe();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
-    )
+async fn serve(config: Config) -> ExitCode {
+    let mut app = Router::new()
+        .route("/", get(gallery))
+        .route("/api/routes", get(api_routes));
+    // `/debug/:view` below serves the debug views
+    for render in RENDERS
+        .iter()
+        .filter(|r| !matches!(r.source, Source::Debug(_)))
+    {
+        app = app.route(
+            render.path,
+            get(move |Query(params), headers| serve_render(render, params, headers)),
+        );
+    }
+    let app = app
+        .route("/shaders/:name/turntable", get(shader_turntable))
+        .route("/shaders/:name/stream", get(shader_stream))
+        .route("/debug/:view", get(debug_view))
+        .route("/render", post(render_scene))
+        .route(
+            "/assets",
+            post(upload_asset).layer(DefaultBodyLimit::max(MAX_UPLOAD)),
+        )
+        .route("/assets/stats", get(asset_stats))
+        .route("/cache/stats", get(cache_stats))
+        .route("/metrics", get(prometheus_metrics))
+        .route("/viewer", get(viewer_page))
+        .route("/viewer/ws", get(viewer_socket))
+        .layer(middleware::from_fn_with_state(
+            Duration::from_secs(config.timeout),
+            log_request,
+        ));
+
+    let address = config.address();
+    let server = address
+        .to_socket_addrs()
+        .map_err(|err| err.to_string())
+        .and_then(|mut addrs| addrs.next().ok_or_else(|| "no address".to_string()))
+        .and_then(|addr| axum::Server::try_bind(&addr).map_err(|err| err.to_string()));
+    let server = match server {
+        Ok(server) => server,
+        Err(err) => {
+            log::error!("cannot listen on {}: {}", address, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    log::info!(
+        "listening on {} with {} workers, root {}",
+        address,
+        config.workers,
+        config.root.display()
+    );
+    if let Err(err) = server.serve(app.into_make_service()).await {
+        log::error!("{}", err);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
 }
 
-async fn sample_line() -> impl IntoResponse {
-    let bs = line::sample_line();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
-    )
+/// Logs every request with its status and duration and counts it in the metrics, and answers
+/// 503 to those still without a response after `timeout` (zero for none). Streams and
+/// WebSockets only count until their response starts.
+async fn log_request(
+    State(timeout): State<Duration>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (method, uri) = (request.method().clone(), request.uri().clone());
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let _in_flight = metrics::track(&metrics::IN_FLIGHT);
+    let start = Instant::now();
+    let response = if timeout.is_zero() {
+        next.run(request).await
+    } else {
+        match tokio::time::timeout(timeout, next.run(request)).await {
+            Ok(response) => response,
+            Err(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("no response after {}s", timeout.as_secs()),
+            )
+                .into_response(),
+        }
+    };
+    let elapsed = start.elapsed();
+    metrics::metrics().request(&route, response.status().as_u16(), elapsed);
+    log::info!(
+        "{} {} {} {:.1}ms",
+        method,
+        uri,
+        response.status().as_u16(),
+        elapsed.as_secs_f64() * 1000.0
+    );
+    response
 }
 
-async fn sample_triangle() -> impl IntoResponse {
-    let bs = triangle::sample_triangle();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
-    )
+/// Encoding of a render from `?format=`, `?quality=` and the `Accept` header, an unknown
+/// format or quality is a 400 and an `Accept` without anything we can produce a 406.
+fn negotiate(
+    params: &HashMap<String, String>,
+    headers: &HeaderMap,
+) -> Result<Output, (StatusCode, String)> {
+    let accept = headers
+        .get(http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok());
+    output(params, accept).map_err(output_error)
 }
 
-async fn flat_shading() -> impl IntoResponse {
-    let bs = triangle::flat_shading();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
-    )
+fn output_error(err: OutputError) -> (StatusCode, String) {
+    let status = match err {
+        OutputError::Invalid(_) => StatusCode::BAD_REQUEST,
+        OutputError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+    };
+    (status, err.to_string())
 }
 
-async fn linear_light() -> impl IntoResponse {
-    let bs = triangle::linear_light();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
+/// The render addressed by `key` from the result cache, rendered on a miss once a worker is
+/// free. A request naming its ETag in `If-None-Match` is answered 304 without rendering. The
+/// key covers the negotiated output, caches must key on `Accept` as well. Concurrent misses
+/// of a key share one render, see `render_once`. A render outliving its request is still
+/// cached, a failed one is answered with its error and a panicking one with 500, neither is
+/// cached.
+async fn cached_response(
+    key: String,
+    headers: &HeaderMap,
+    render: impl FnOnce() -> Result<Cached, (StatusCode, String)> + Send + 'static,
+) -> Response {
+    let etag = cache::etag(&key);
+    let cache_headers = AppendHeaders([
+        (http::header::ETAG, etag.clone()),
+        (
+            http::header::CACHE_CONTROL,
+            format!("public, max-age={}", cache::max_age()),
+        ),
+        (http::header::VARY, "accept".to_string()),
+    ]);
+    if headers
+        .get(http::header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| cache::none_match(v, &etag))
+    {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    let hit = match cache::lookup(&key) {
+        Lookup::Memory(cached) => Some(cached),
+        Lookup::Disk(path) => {
+            let key = key.clone();
+            tokio::task::spawn_blocking(move || cache::read(&key, &path))
+                .await
+                .ok()
+                .flatten()
+        }
+        Lookup::Miss => None,
+    };
+    let (cached, hit) = match hit {
+        Some(cached) => (cached, true),
+        None => match render_once(key, render).await {
+            Ok(cached) => (cached, false),
+            Err(err) => return err.into_response(),
+        },
+    };
+    let mut response = (
+        cache_headers,
+        AppendHeaders([
+            (http::header::CONTENT_TYPE, cached.content_type.as_str()),
+            (
+                http::HeaderName::from_static("x-cache"),
+                if hit { "hit" } else { "miss" },
+            ),
+        ]),
+        cached.body.clone(),
     )
+        .into_response();
+    for (name, value) in &cached.headers {
+        if let (Ok(name), Ok(value)) = (
+            http::HeaderName::try_from(name),
+            http::HeaderValue::try_from(value),
+        ) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
 }
 
-async fn z_buf() -> impl IntoResponse {
-    let bs = zbuf::z_buf();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
-    )
+/// A render in progress, shared by the requests for its key.
+type Flight = Shared<BoxFuture<'static, Result<Arc<Cached>, (StatusCode, String)>>>;
+
+/// Renders in progress by cache key.
+static FLIGHTS: OnceLock<Mutex<HashMap<String, Flight>>> = OnceLock::new();
+
+/// Renders and caches `key`, joining the render of an earlier request for the same key
+/// instead when there is one. The render is a task of its own, it completes and is cached
+/// when the requests waiting for it are gone.
+async fn render_once(
+    key: String,
+    render: impl FnOnce() -> Result<Cached, (StatusCode, String)> + Send + 'static,
+) -> Result<Arc<Cached>, (StatusCode, String)> {
+    let flights = FLIGHTS.get_or_init(Default::default);
+    let flight = flights
+        .lock()
+        .unwrap()
+        .entry(key.clone())
+        .or_insert_with(|| {
+            let task = tokio::spawn(async move {
+                let cached = render_blocking({
+                    let key = key.clone();
+                    move || {
+                        let cached = Arc::new(render()?);
+                        cache::insert(&key, Arc::clone(&cached));
+                        Ok(cached)
+                    }
+                })
+                .await
+                .and_then(|cached| cached);
+                // cached first, the requests after this one hit the cache
+                flights.lock().unwrap().remove(&key);
+                cached
+            });
+            task.map(|joined| {
+                joined.unwrap_or_else(|err| {
+                    log::error!("render failed: {}", err);
+                    Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "render failed".to_string(),
+                    ))
+                })
+            })
+            .boxed()
+            .shared()
+        })
+        .clone();
+    flight.await
 }
 
-async fn move_camera() -> impl IntoResponse {
-    let bs = camera::move_camera();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
-    )
+/// Runs `render` on a blocking thread once a worker is free, counted in the `QUEUED` and
+/// `RENDERING` gauges. A panicking render is a 500.
+async fn render_blocking<T: Send + 'static>(
+    render: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, (StatusCode, String)> {
+    let queued = metrics::track(&metrics::QUEUED);
+    let slot = Arc::clone(RENDER_SLOTS.get().unwrap())
+        .acquire_owned()
+        .await
+        .unwrap();
+    drop(queued);
+    tokio::task::spawn_blocking(move || {
+        let (_slot, _rendering) = (slot, metrics::track(&metrics::RENDERING));
+        render()
+    })
+    .await
+    .map_err(|err| {
+        log::error!("render failed: {}", err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "render failed".to_string(),
+        )
+    })
 }
 
-async fn shader_gouraud() -> impl IntoResponse {
-    let bs = shaders::gouraud::gouraud_render();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
-    )
+/// `frame` encoded as `output`, see `with_stats`.
+fn encode_frame(frame: &Frame, output: Output) -> Cached {
+    let start = Instant::now();
+    let cached = Cached::new(output.format.content_type(), output.encode(frame));
+    let mut stats = frame.stats.clone();
+    stats.stages.push(("encode", start.elapsed()));
+    with_stats(cached, stats)
 }
 
-async fn shader_gouraud6l() -> impl IntoResponse {
-    let bs = shaders::gouraud6l::gouraud6l_render();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
-    )
+/// Counts the render in the metrics and describes it in the `x-render-stats` and
+/// `Server-Timing` headers, which are cached with it.
+fn with_stats(mut cached: Cached, stats: RenderStats) -> Cached {
+    metrics::metrics().render(&stats);
+    cached
+        .headers
+        .push(("x-render-stats".to_string(), stats.to_string()));
+    cached
+        .headers
+        .push(("server-timing".to_string(), stats.server_timing()));
+    cached
 }
 
-async fn shader_texture() -> impl IntoResponse {
-    let bs = shaders::texture::texture_render();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
-    )
+/// `/`, thumbnails of every render.
+async fn gallery() -> Html<String> {
+    Html(routes::gallery())
 }
 
-async fn shader_normal_mapping() -> impl IntoResponse {
-    let bs = shaders::normalmapping::normal_mapping_render();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
-    )
+/// `GET /api/routes`, every render with its query parameters and their defaults.
+async fn api_routes() -> Json<Vec<RouteInfo>> {
+    Json(RENDERS.iter().map(Render::info).collect())
 }
 
-async fn shader_specular_mapping() -> impl IntoResponse {
-    let bs = shaders::specularmapping::specular_mapping_render();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
-    )
+/// A lesson image as is, a `/shaders/*` render as negotiated from the query parameters.
+/// Both are rendered under a render slot and cached, but for the random `flat-shading`.
+async fn serve_render(
+    render: &'static Render,
+    params: HashMap<String, String>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    match render.source {
+        Source::Lesson(image) if render.deterministic => Ok(cached_response(
+            cache::key(render.path, &(), &[]),
+            &headers,
+            move || Ok(Cached::new(Format::Png.content_type(), image())),
+        )
+        .await),
+        Source::Lesson(image) => Ok((
+            AppendHeaders([(http::header::CONTENT_TYPE, Format::Png.content_type())]),
+            render_blocking(image).await?,
+        )
+            .into_response()),
+        Source::Shader(shader) => {
+            let output = negotiate(&params, &headers)?;
+            let (post, camera, options) = (
+                post_process(&params).map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+                camera_options(&params).map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+                render_options(&params, false),
+            );
+            let key = cache::key(
+                render.path,
+                &(post, camera, &options, output),
+                &options.model.iter().map(String::as_str).collect::<Vec<_>>(),
+            );
+            Ok(cached_response(key, &headers, move || {
+                let frame = shader(post, camera, &options);
+                Ok(encode_frame(&frame, output))
+            })
+            .await)
+        }
+        Source::Debug(_) => Err((StatusCode::NOT_FOUND, render.path.to_string())),
+    }
 }
 
-async fn shader_shadow_mapping() -> impl IntoResponse {
-    let bs = shaders::shadowmapping::shadow_mapping_render();
-    (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
+/// `/debug/depth`, `/debug/normals`, ... see `shaders::debug::DebugView` for the names.
+/// The `x-cull-stats` header reports what the culling skipped. The views are images, the
+/// `exr` and `depth` formats are not available.
+async fn debug_view(
+    Path(view): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let Some(view) = shaders::debug::DebugView::from_name(&view) else {
+        let names: Vec<&str> = shaders::debug::DebugView::ALL
+            .iter()
+            .map(|v| v.name())
+            .collect();
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!(
+                "unknown debug view `{}`, expected one of: {}",
+                view,
+                names.join(", ")
+            ),
+        ));
+    };
+    let output = negotiate(&params, &headers)?;
+    if output.format.is_raw() {
+        return Err((
+            StatusCode::NOT_ACCEPTABLE,
+            format!(
+                "debug views are images, format `{}` is not available",
+                output.format.name()
+            ),
+        ));
+    }
+    let (camera, culling, options) = (
+        camera_options(&params).map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+        culling(&params),
+        render_options(&params, false),
+    );
+    let key = cache::key(
+        &format!("/debug/{}", view.name()),
+        &(camera, culling, &options, output),
+        &options.model.iter().map(String::as_str).collect::<Vec<_>>(),
+    );
+    Ok(cached_response(key, &headers, move || {
+        let (img, cull_stats, mut stats) =
+            shaders::debug::debug_render(view, camera, culling, &options);
+        let start = Instant::now();
+        let mut cached = Cached::new(
+            output.format.content_type(),
+            output.encode_image(&img).unwrap(),
+        );
+        stats.stages.push(("encode", start.elapsed()));
+        cached
+            .headers
+            .push(("x-cull-stats".to_string(), cull_stats.to_string()));
+        Ok(with_stats(cached, stats))
+    })
+    .await)
+}
+
+/// `/shaders/texture/turntable?frames=24&delay=80&format=gif|apng|strip`, one full turn
+/// around the model with the camera and post-process options of the single renders. Frames
+/// beyond `turntable::MAX_PIXELS` and other formats are a 400.
+async fn shader_turntable(
+    Path(name): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let Some(render) = shaders::by_name(&name) else {
+        return Err((StatusCode::NOT_FOUND, format!("unknown shader `{}`", name)));
+    };
+    let mut turntable = turntable::Turntable::default();
+    if let Some(frames) = params.get("frames").and_then(|s| s.parse::<usize>().ok()) {
+        turntable.frames = frames.clamp(1, 120);
+    }
+    if let Some(delay) = params.get("delay").and_then(|s| s.parse::<u32>().ok()) {
+        turntable.delay_ms = delay.min(u16::MAX as u32);
+    }
+    let encoding = match params.get("format") {
+        Some(name) => turntable::Encoding::from_name(name).ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "format: unknown turntable format `{}`, expected one of: {}",
+                    name,
+                    turntable::Encoding::NAMES.join(", ")
+                ),
+            )
+        })?,
+        None => turntable::Encoding::Gif,
+    };
+    let (post, camera, options) = (
+        post_process(&params).map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+        camera_options(&params).map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+        render_options(&params, false),
+    );
+    turntable
+        .check(&options)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    let key = cache::key(
+        &format!("/shaders/{}/turntable", name),
+        &(turntable, encoding, post, camera, &options),
+        &options.model.iter().map(String::as_str).collect::<Vec<_>>(),
+    );
+    Ok(cached_response(key, &headers, move || {
+        let frames = turntable.render(render, post, camera, &options);
+        Ok(Cached::new(
+            encoding.content_type(),
+            turntable.encode(frames, encoding),
+        ))
+    })
+    .await)
+}
+
+/// `/shaders/texture/stream?animation=turntable|light|keyframes&period=6&fps=10`, the
+/// animated render as an MJPEG stream browsers play in place, endless unless `?frames=` is
+/// given. Frames are rendered one ahead of the client and no more once it disconnects, each
+/// waits for a render slot like the other renders.
+async fn shader_stream(
+    Path(name): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let Some(render) = shaders::by_name(&name) else {
+        return Err((StatusCode::NOT_FOUND, format!("unknown shader `{}`", name)));
+    };
+    let animation = animation(&params).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    let output = Output::negotiate(
+        Some(Format::Jpeg.name()),
+        params.get("quality").map(String::as_str),
+        None,
     )
+    .map_err(output_error)?;
+    let fps = params
+        .get("fps")
+        .and_then(|s| s.parse::<u32>().ok())
+        .map_or(10, |fps| fps.clamp(1, 30));
+    let frames = params.get("frames").and_then(|s| s.parse::<u64>().ok());
+    let (post, camera, options) = (
+        post_process(&params).map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+        camera_options(&params).map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+        render_options(&params, false),
+    );
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<Vec<u8>>(1);
+    let scene = Arc::new((animation, options));
+    tokio::spawn(async move {
+        let interval = Duration::from_secs(1) / fps;
+        let mut next = tokio::time::Instant::now();
+        for i in 0..frames.unwrap_or(u64::MAX) {
+            if tx.is_closed() {
+                return;
+            }
+            let t = i as f64 / fps as f64;
+            // each frame waits for a worker like any other render
+            let scene = Arc::clone(&scene);
+            let Ok(jpeg) = render_blocking(move || {
+                let (animation, options) = &*scene;
+                output.encode(&animation.render(render, t, post, camera, options))
+            })
+            .await
+            else {
+                return;
+            };
+            let mut part = format!(
+                "--{}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                BOUNDARY,
+                Format::Jpeg.content_type(),
+                jpeg.len()
+            )
+            .into_bytes();
+            part.extend(jpeg);
+            part.extend_from_slice(b"\r\n");
+            // a render slower than `fps` plays slower instead of skipping frames
+            tokio::time::sleep_until(next).await;
+            next = next.max(tokio::time::Instant::now()) + interval;
+            if tx.send(part).await.is_err() {
+                return;
+            }
+        }
+        let _ = tx.send(format!("--{}--\r\n", BOUNDARY).into_bytes()).await;
+    });
+    let parts = futures_util::stream::unfold(rx, |mut rx| async move {
+        let part = rx.recv().await?;
+        Some((Ok::<_, Infallible>(part), rx))
+    });
+    Ok((
+        AppendHeaders([
+            (
+                http::header::CONTENT_TYPE,
+                format!("multipart/x-mixed-replace; boundary={}", BOUNDARY),
+            ),
+            (http::header::CACHE_CONTROL, "no-cache".to_string()),
+        ]),
+        StreamBody::new(parts),
+    ))
+}
+
+/// `POST /render` with a `scene::Scene` as JSON body, answers with the image in its `format`
+/// or else as negotiated by the `Accept` header. Malformed JSON is a 400, a well formed scene
+/// that cannot be rendered a 422, both with the offending fields in the body.
+async fn render_scene(
+    headers: HeaderMap,
+    body: String,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let scene = Scene::from_json(&body).map_err(|err| {
+        let status = match err {
+            SceneError::Parse(_) => StatusCode::BAD_REQUEST,
+            SceneError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        };
+        (status, err.to_string())
+    })?;
+    let mut output = match scene.format {
+        Some(format) => Output {
+            format,
+            ..Default::default()
+        },
+        None => negotiate(&HashMap::new(), &headers)?,
+    };
+    output.quality = scene.quality.unwrap_or(DEFAULT_QUALITY);
+    let key = cache::key(
+        "/render",
+        &(&scene, output),
+        &scene
+            .meshes
+            .iter()
+            .map(|m| m.path.as_str())
+            .collect::<Vec<_>>(),
+    );
+    // a POST is not conditional, the cached result is still reused
+    Ok(cached_response(key, &HeaderMap::new(), move || {
+        // the files exist, they may still not parse
+        for (i, mesh) in scene.meshes.iter().enumerate() {
+            assets::load(&mesh.path).map_err(|err| {
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("invalid scene:\n  meshes[{}].path: {}", i, err),
+                )
+            })?;
+        }
+        let frame = shaders::scene::scene_render(&scene);
+        Ok(encode_frame(&frame, output))
+    })
+    .await)
 }
 
-async fn shader_ambient_occlusion() -> impl IntoResponse {
-    let bs = shaders::ambientocclusion::ambient_occlusion_render();
+/// `POST /assets`, multipart with the `.obj` in the `obj` field and its textures in
+/// `diffuse`, `nm`, `spec` and optionally `glow`, in any format the `image` crate reads.
+/// Answers with the id of the stored model, render it with `/shaders/*?asset=<id>` or as
+/// the mesh `path` of a `POST /render` scene.
+async fn upload_asset(mut multipart: Multipart) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let bad_request = |err: axum::extract::multipart::MultipartError| {
+        (StatusCode::BAD_REQUEST, format!("invalid upload: {}", err))
+    };
+    let mut upload = assets::Upload::default();
+    while let Some(field) = multipart.next_field().await.map_err(bad_request)? {
+        let name = field.name().unwrap_or_default().to_string();
+        let bytes = field.bytes().await.map_err(bad_request)?.to_vec();
+        if name == "obj" {
+            upload.obj = bytes;
+        } else {
+            upload.textures.insert(name, bytes);
+        }
+    }
+    // decoding the textures and parsing the model block
+    let stored = tokio::task::spawn_blocking(move || {
+        upload.store(
+            std::path::Path::new(assets::UPLOAD_DIR),
+            assets::UPLOAD_QUOTA,
+        )
+    })
+    .await
+    .map_err(|err| {
+        log::error!("upload failed: {}", err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "upload failed".to_string(),
+        )
+    })?;
+    let id = stored.map_err(|errors| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("invalid upload:\n  {}", errors.join("\n  ")),
+        )
+    })?;
+    Ok(Json(serde_json::json!({
+        "id": id,
+        "path": assets::upload_path(&id),
+    })))
+}
+
+/// `GET /assets/stats`, hits, misses and memory use of the model cache.
+async fn asset_stats() -> Json<assets::CacheStats> {
+    Json(assets::stats())
+}
+
+/// `GET /cache/stats`, hits, misses and memory and disk use of the render cache.
+async fn cache_stats() -> Json<cache::CacheStats> {
+    Json(cache::stats())
+}
+
+/// `GET /metrics`, requests, latencies, render queue, render statistics and caches in the
+/// Prometheus text format.
+async fn prometheus_metrics() -> impl IntoResponse {
     (
-        AppendHeaders([(http::header::CONTENT_TYPE, "image/png")]),
-        bs,
+        AppendHeaders([(
+            http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )]),
+        metrics::text(),
     )
 }
+
+/// `GET /viewer`, orbits the `/shaders/*` renders with the mouse over `/viewer/ws`.
+async fn viewer_page() -> Html<&'static str> {
+    Html(include_str!("../public/viewer.html"))
+}
+
+/// `GET /viewer/ws`, see `viewer::Viewer` for the messages. Every change is answered with a
+/// preview and then the full frame, unless another change arrives in between.
+async fn viewer_socket(ws: WebSocketUpgrade) -> impl IntoResponse {
+    ws.on_upgrade(viewer_session)
+}
+
+async fn viewer_session(mut socket: WebSocket) {
+    let mut viewer = Viewer::default();
+    let hello = serde_json::json!({"shaders": shaders::names(), "params": viewer.params});
+    if socket.send(Message::Text(hello.to_string())).await.is_err() {
+        return;
+    }
+    let mut changed = true;
+    loop {
+        if !changed {
+            let message = socket.recv().await;
+            match viewer_message(&mut socket, &mut viewer, message).await {
+                Some(c) => changed = c,
+                None => return,
+            }
+            continue;
+        }
+        changed = false;
+        for &level in viewer.levels() {
+            let params = viewer.params.clone();
+            let img = match render_blocking(move || viewer::render(&params, level)).await {
+                Ok(img) => img,
+                Err((_, err)) => {
+                    let error = serde_json::json!({ "error": err });
+                    if socket.send(Message::Text(error.to_string())).await.is_err() {
+                        return;
+                    }
+                    break;
+                }
+            };
+            let frame = viewer.encode(img, level);
+            if socket.send(Message::Binary(frame)).await.is_err() {
+                return;
+            }
+            // the changes that came in during the render make the rest of this one stale
+            while let Ok(message) = tokio::time::timeout(Duration::ZERO, socket.recv()).await {
+                match viewer_message(&mut socket, &mut viewer, message).await {
+                    Some(c) => changed |= c,
+                    None => return,
+                }
+            }
+            if changed {
+                break;
+            }
+        }
+    }
+}
+
+/// Applies one client message, invalid ones are answered with `{"error": ...}`. Whether the
+/// settings changed, `None` once the connection is gone.
+async fn viewer_message(
+    socket: &mut WebSocket,
+    viewer: &mut Viewer,
+    message: Option<Result<Message, axum::Error>>,
+) -> Option<bool> {
+    match message?.ok()? {
+        Message::Text(text) => match viewer.update(&text) {
+            Ok(()) => Some(true),
+            Err(err) => {
+                let error = serde_json::json!({ "error": err });
+                socket.send(Message::Text(error.to_string())).await.ok()?;
+                Some(false)
+            }
+        },
+        Message::Close(_) => None,
+        _ => Some(false),
+    }
+}
@@ -6,7 +6,7 @@ use std::{
 use crate::{geometry::Vec_, util::NumLike};
 
 /// A generic matrix struct with elements of type `T`, with `M` rows and `N` columns.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Mat<T: NumLike> {
     pub M: usize,
     pub N: usize,
@@ -247,6 +247,320 @@ impl<T: NumLike> Display for Mat<T> {
     }
 }
 
+/// A fixed size matrix with `M` rows and `N` columns, stored inline so it is `Copy` and
+/// never allocates. Use it where matrices are built per vertex or per pixel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mat_<T: NumLike, const M: usize, const N: usize> {
+    pub data: [[T; N]; M],
+}
+
+pub type Mat2<T> = Mat_<T, 2, 2>;
+pub type Mat3<T> = Mat_<T, 3, 3>;
+pub type Mat4<T> = Mat_<T, 4, 4>;
+pub type Mat2f = Mat2<f64>;
+pub type Mat3f = Mat3<f64>;
+pub type Mat4f = Mat4<f64>;
+
+impl<T: NumLike, const M: usize, const N: usize> Mat_<T, M, N> {
+    pub fn new() -> Self {
+        Self {
+            data: [[T::zero(); N]; M],
+        }
+    }
+
+    pub fn from(data: [[T; N]; M]) -> Self {
+        Self { data }
+    }
+
+    pub fn row(&self, idx: usize) -> Vec_<T, N> {
+        Vec_::from(self.data[idx])
+    }
+
+    pub fn col(&self, idx: usize) -> Vec_<T, M> {
+        let mut col = Vec_::new();
+        for i in 0..M {
+            col[i] = self.data[i][idx];
+        }
+        col
+    }
+
+    pub fn set_col(&mut self, idx: usize, col: &Vec_<T, M>) {
+        for i in 0..M {
+            self.data[i][idx] = col[i];
+        }
+    }
+
+    pub fn transpose(&self) -> Mat_<T, N, M> {
+        let mut ret = Mat_::new();
+        for i in 0..M {
+            for j in 0..N {
+                ret.data[j][i] = self.data[i][j];
+            }
+        }
+        ret
+    }
+}
+
+impl<T: NumLike, const N: usize> Mat_<T, N, N> {
+    pub fn identity() -> Self {
+        let mut mat = Self::new();
+        for i in 0..N {
+            mat.data[i][i] = T::one();
+        }
+        mat
+    }
+}
+
+impl<T: NumLike, const M: usize, const N: usize> Default for Mat_<T, M, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: NumLike> Mat2<T> {
+    pub fn det(&self) -> T {
+        let a = &self.data;
+        a[0][0] * a[1][1] - a[0][1] * a[1][0]
+    }
+
+    pub fn invert(&self) -> Self {
+        let a = &self.data;
+        let det = self.det();
+        if det == T::zero() {
+            panic!("Cannot invert matrix with zero determinant");
+        }
+        Mat2::from([
+            [a[1][1] / det, (T::zero() - a[0][1]) / det],
+            [(T::zero() - a[1][0]) / det, a[0][0] / det],
+        ])
+    }
+
+    pub fn invert_transpose(&self) -> Self {
+        self.invert().transpose()
+    }
+}
+
+impl<T: NumLike> Mat3<T> {
+    pub fn det(&self) -> T {
+        let a = &self.data;
+        a[0][0] * (a[1][1] * a[2][2] - a[1][2] * a[2][1])
+            - a[0][1] * (a[1][0] * a[2][2] - a[1][2] * a[2][0])
+            + a[0][2] * (a[1][0] * a[2][1] - a[1][1] * a[2][0])
+    }
+
+    /// The cofactors divided by the determinant, which is the inverse transposed.
+    pub fn invert_transpose(&self) -> Self {
+        let a = &self.data;
+        let det = self.det();
+        if det == T::zero() {
+            panic!("Cannot invert matrix with zero determinant");
+        }
+        let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| {
+            (a[r0][c0] * a[r1][c1] - a[r0][c1] * a[r1][c0]) / det
+        };
+        Mat3::from([
+            [
+                cofactor(1, 2, 1, 2),
+                cofactor(1, 2, 2, 0),
+                cofactor(1, 2, 0, 1),
+            ],
+            [
+                cofactor(2, 0, 1, 2),
+                cofactor(2, 0, 2, 0),
+                cofactor(2, 0, 0, 1),
+            ],
+            [
+                cofactor(0, 1, 1, 2),
+                cofactor(0, 1, 2, 0),
+                cofactor(0, 1, 0, 1),
+            ],
+        ])
+    }
+
+    pub fn invert(&self) -> Self {
+        self.invert_transpose().transpose()
+    }
+}
+
+impl<T: NumLike> Mat4<T> {
+    /// The 2x2 minors of the top two rows and of the bottom two rows, `det` and the inverse
+    /// are both sums of their products.
+    fn minors(&self) -> ([T; 6], [T; 6]) {
+        let a = &self.data;
+        let m = |r0: usize, r1: usize, c0: usize, c1: usize| {
+            a[r0][c0] * a[r1][c1] - a[r0][c1] * a[r1][c0]
+        };
+        (
+            [
+                m(0, 1, 0, 1),
+                m(0, 1, 0, 2),
+                m(0, 1, 0, 3),
+                m(0, 1, 1, 2),
+                m(0, 1, 1, 3),
+                m(0, 1, 2, 3),
+            ],
+            [
+                m(2, 3, 0, 1),
+                m(2, 3, 0, 2),
+                m(2, 3, 0, 3),
+                m(2, 3, 1, 2),
+                m(2, 3, 1, 3),
+                m(2, 3, 2, 3),
+            ],
+        )
+    }
+
+    pub fn det(&self) -> T {
+        let (s, c) = self.minors();
+        s[0] * c[5] - s[1] * c[4] + s[2] * c[3] + s[3] * c[2] - s[4] * c[1] + s[5] * c[0]
+    }
+
+    pub fn invert(&self) -> Self {
+        self.try_invert()
+            .unwrap_or_else(|| panic!("Cannot invert matrix with zero determinant"))
+    }
+
+    /// The inverse, `None` when the determinant is zero.
+    pub fn try_invert(&self) -> Option<Self> {
+        let a = &self.data;
+        let (s, c) = self.minors();
+        let det = s[0] * c[5] - s[1] * c[4] + s[2] * c[3] + s[3] * c[2] - s[4] * c[1] + s[5] * c[0];
+        if det == T::zero() {
+            return None;
+        }
+        let mut inv = Mat4::from([
+            [
+                a[1][1] * c[5] - a[1][2] * c[4] + a[1][3] * c[3],
+                a[0][2] * c[4] - a[0][1] * c[5] - a[0][3] * c[3],
+                a[3][1] * s[5] - a[3][2] * s[4] + a[3][3] * s[3],
+                a[2][2] * s[4] - a[2][1] * s[5] - a[2][3] * s[3],
+            ],
+            [
+                a[1][2] * c[2] - a[1][0] * c[5] - a[1][3] * c[1],
+                a[0][0] * c[5] - a[0][2] * c[2] + a[0][3] * c[1],
+                a[3][2] * s[2] - a[3][0] * s[5] - a[3][3] * s[1],
+                a[2][0] * s[5] - a[2][2] * s[2] + a[2][3] * s[1],
+            ],
+            [
+                a[1][0] * c[4] - a[1][1] * c[2] + a[1][3] * c[0],
+                a[0][1] * c[2] - a[0][0] * c[4] - a[0][3] * c[0],
+                a[3][0] * s[4] - a[3][1] * s[2] + a[3][3] * s[0],
+                a[2][1] * s[2] - a[2][0] * s[4] - a[2][3] * s[0],
+            ],
+            [
+                a[1][1] * c[1] - a[1][0] * c[3] - a[1][2] * c[0],
+                a[0][0] * c[3] - a[0][1] * c[1] + a[0][2] * c[0],
+                a[3][1] * s[1] - a[3][0] * s[3] - a[3][2] * s[0],
+                a[2][0] * s[3] - a[2][1] * s[1] + a[2][2] * s[0],
+            ],
+        ]);
+        for row in inv.data.iter_mut() {
+            for v in row.iter_mut() {
+                *v = *v / det;
+            }
+        }
+        Some(inv)
+    }
+
+    pub fn invert_transpose(&self) -> Self {
+        self.invert().transpose()
+    }
+}
+
+impl<T: NumLike, const M: usize, const N: usize> Index<usize> for Mat_<T, M, N> {
+    type Output = [T; N];
+
+    fn index(&self, idx: usize) -> &Self::Output {
+        &self.data[idx]
+    }
+}
+
+impl<T: NumLike, const M: usize, const N: usize> IndexMut<usize> for Mat_<T, M, N> {
+    fn index_mut(&mut self, idx: usize) -> &mut Self::Output {
+        &mut self.data[idx]
+    }
+}
+
+impl<T: NumLike, const M: usize, const N: usize> Mul<T> for Mat_<T, M, N> {
+    type Output = Mat_<T, M, N>;
+
+    fn mul(self, rhs: T) -> Self::Output {
+        let mut ret = self;
+        for row in ret.data.iter_mut() {
+            for v in row.iter_mut() {
+                *v = *v * rhs;
+            }
+        }
+        ret
+    }
+}
+
+impl<T: NumLike, const M: usize, const N: usize, const P: usize> Mul<Mat_<T, N, P>>
+    for Mat_<T, M, N>
+{
+    type Output = Mat_<T, M, P>;
+
+    fn mul(self, rhs: Mat_<T, N, P>) -> Self::Output {
+        let mut ret = Mat_::new();
+        for i in 0..M {
+            for j in 0..P {
+                let mut sum = T::zero();
+                for k in 0..N {
+                    sum = sum + self.data[i][k] * rhs.data[k][j];
+                }
+                ret.data[i][j] = sum;
+            }
+        }
+        ret
+    }
+}
+
+impl<T: NumLike, const M: usize, const N: usize, const P: usize> Mul<&Mat_<T, N, P>>
+    for &Mat_<T, M, N>
+{
+    type Output = Mat_<T, M, P>;
+
+    fn mul(self, rhs: &Mat_<T, N, P>) -> Self::Output {
+        *self * *rhs
+    }
+}
+
+impl<T: NumLike, const M: usize, const N: usize> Mul<Vec_<T, N>> for Mat_<T, M, N> {
+    type Output = Vec_<T, M>;
+
+    fn mul(self, rhs: Vec_<T, N>) -> Self::Output {
+        let mut ret = Vec_::new();
+        for i in 0..M {
+            let mut sum = T::zero();
+            for j in 0..N {
+                sum = sum + self.data[i][j] * rhs[j];
+            }
+            ret[i] = sum;
+        }
+        ret
+    }
+}
+
+impl<T: NumLike, const M: usize, const N: usize> Mul<&Vec_<T, N>> for &Mat_<T, M, N> {
+    type Output = Vec_<T, M>;
+
+    fn mul(self, rhs: &Vec_<T, N>) -> Self::Output {
+        *self * *rhs
+    }
+}
+
+impl<T: NumLike, const M: usize, const N: usize> Display for Mat_<T, M, N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for row in self.data.iter() {
+            for v in row.iter() {
+                write!(f, "{:?}\t", v)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -395,4 +709,69 @@ mod tests {
         let b = Mat::from_vec(2, 2, vec![7, 8, 9, 10]);
         let _c = &a * &b;
     }
+
+    fn random_mat<const N: usize>(seed: u64) -> (Mat_<f64, N, N>, Mat<f64>) {
+        let mut state = seed;
+        let mut fixed = Mat_::<f64, N, N>::new();
+        for i in 0..N {
+            for j in 0..N {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                fixed[i][j] = (state >> 33) as f64 / (1u64 << 31) as f64 * 4.0 - 2.0;
+            }
+        }
+        let dynamic = Mat::from(&fixed.data.iter().map(|r| r.to_vec()).collect());
+        (fixed, dynamic)
+    }
+
+    fn assert_close<const N: usize>(a: &Mat_<f64, N, N>, b: &Mat<f64>) {
+        for i in 0..N {
+            for j in 0..N {
+                assert!((a[i][j] - b[i][j]).abs() < 1e-9, "{}\n{}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fixed_det_invert() {
+        // the closed forms agree with the cofactor expansion
+        for seed in 0..20 {
+            let (m, d) = random_mat::<2>(seed);
+            assert!((m.det() - d.det()).abs() < 1e-9);
+            assert_close(&m.invert(), &d.invert());
+            let (m, d) = random_mat::<3>(seed);
+            assert!((m.det() - d.det()).abs() < 1e-9);
+            assert_close(&m.invert_transpose(), &d.invert_transpose());
+            let (m, d) = random_mat::<4>(seed);
+            assert!((m.det() - d.det()).abs() < 1e-9);
+            assert_close(&m.invert(), &d.invert());
+            assert_close(&(m * m.invert()), &Mat::identity(4));
+        }
+        let mut flat = Mat4::<f64>::identity();
+        flat[0][0] = 0.0;
+        assert_eq!(flat.try_invert(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot invert matrix with zero determinant")]
+    fn test_fixed_invert_singular() {
+        Mat3::from([[1.0, 2.0, 3.0], [4.0, 5.0, 6.0], [7.0, 8.0, 9.0]]).invert();
+    }
+
+    #[test]
+    fn test_fixed_mul() {
+        let a = Mat_::<i32, 2, 3>::from([[1, 2, 3], [4, 5, 6]]);
+        let b = Mat_::<i32, 3, 2>::from([[7, 8], [9, 10], [11, 12]]);
+        assert_eq!(a * b, Mat2::from([[58, 64], [139, 154]]));
+        assert_eq!(a * Vec_::from([1, 1, 1]), Vec_::from([6, 15]));
+        assert_eq!(a.transpose().col(1), Vec_::from([4, 5, 6]));
+
+        let mut c = Mat_::<i32, 2, 3>::new();
+        c.set_col(0, &Vec_::from([1, 4]));
+        c.set_col(1, &Vec_::from([2, 5]));
+        c.set_col(2, &Vec_::from([3, 6]));
+        assert_eq!(c, a);
+        assert_eq!(Mat3::<i32>::identity() * a.transpose(), a.transpose());
+    }
 }
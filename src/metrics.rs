@@ -0,0 +1,400 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::{assets, cache, gl::RenderStats};
+
+/// Upper bounds of the latency buckets in seconds.
+pub const BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Requests being answered.
+pub static IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+/// Renders waiting for a free worker.
+pub static QUEUED: AtomicU64 = AtomicU64::new(0);
+/// Renders running.
+pub static RENDERING: AtomicU64 = AtomicU64::new(0);
+
+/// Counts itself in a gauge while alive, see `track`.
+#[derive(Debug)]
+pub struct Tracked(&'static AtomicU64);
+
+/// Adds one to `gauge` until the returned guard is dropped, also when the future holding it
+/// is cancelled.
+pub fn track(gauge: &'static AtomicU64) -> Tracked {
+    gauge.fetch_add(1, Ordering::Relaxed);
+    Tracked(gauge)
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    /// Observations per bucket of `BUCKETS`, larger ones are only in `count`
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: f64) {
+        if let Some(i) = BUCKETS.iter().position(|&le| value <= le) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Totals since the start of the server, see `Metrics::encode` for their names.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// Requests by route and status
+    requests: BTreeMap<(String, u16), u64>,
+    latency: BTreeMap<String, Histogram>,
+    renders: u64,
+    /// Sums of the `RenderStats` of the renders
+    totals: RenderStats,
+    /// Seconds by stage
+    stages: BTreeMap<&'static str, f64>,
+}
+
+impl Metrics {
+    /// A request answered by `route`, the pattern it matched like `/debug/:view`.
+    pub fn request(&mut self, route: &str, status: u16, elapsed: Duration) {
+        *self
+            .requests
+            .entry((route.to_string(), status))
+            .or_default() += 1;
+        self.latency
+            .entry(route.to_string())
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn render(&mut self, stats: &RenderStats) {
+        self.renders += 1;
+        let totals = &mut self.totals;
+        totals.triangles += stats.triangles;
+        totals.clipped += stats.clipped;
+        totals.culled += stats.culled;
+        totals.fragments += stats.fragments;
+        totals.depth_rejects += stats.depth_rejects;
+        for (stage, time) in &stats.stages {
+            *self.stages.entry(stage).or_default() += time.as_secs_f64();
+        }
+    }
+
+    /// The Prometheus text format of the metrics, the gauges and the caches.
+    pub fn encode(&self, cache: &cache::CacheStats, assets: &assets::CacheStats) -> String {
+        let mut out = String::new();
+        let w = &mut out;
+
+        family(w, "trrs_requests_total", "counter", "Requests answered.");
+        for ((route, status), n) in &self.requests {
+            let status = status.to_string();
+            sample(
+                w,
+                "trrs_requests_total",
+                &[("route", route), ("status", &status)],
+                *n,
+            );
+        }
+        family(
+            w,
+            "trrs_request_duration_seconds",
+            "histogram",
+            "Time to answer a request, until the body starts for streams.",
+        );
+        for (route, histogram) in &self.latency {
+            let mut cumulative = 0;
+            for (le, n) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += n;
+                let le = le.to_string();
+                let labels = [("route", route.as_str()), ("le", &le)];
+                sample(
+                    w,
+                    "trrs_request_duration_seconds_bucket",
+                    &labels,
+                    cumulative,
+                );
+            }
+            let labels = [("route", route.as_str()), ("le", "+Inf")];
+            sample(
+                w,
+                "trrs_request_duration_seconds_bucket",
+                &labels,
+                histogram.count,
+            );
+            let labels = [("route", route.as_str())];
+            sample(
+                w,
+                "trrs_request_duration_seconds_sum",
+                &labels,
+                histogram.sum,
+            );
+            sample(
+                w,
+                "trrs_request_duration_seconds_count",
+                &labels,
+                histogram.count,
+            );
+        }
+
+        for (name, help, gauge) in [
+            (
+                "trrs_requests_in_flight",
+                "Requests being answered.",
+                &IN_FLIGHT,
+            ),
+            (
+                "trrs_render_queue_depth",
+                "Renders waiting for a worker.",
+                &QUEUED,
+            ),
+            ("trrs_renders_running", "Renders running.", &RENDERING),
+        ] {
+            family(w, name, "gauge", help);
+            sample(w, name, &[], gauge.load(Ordering::Relaxed));
+        }
+
+        let totals = &self.totals;
+        for (name, help, value) in [
+            ("trrs_renders_total", "Frames rendered.", self.renders),
+            (
+                "trrs_render_triangles_total",
+                "Triangles given to the rasterizer.",
+                totals.triangles,
+            ),
+            (
+                "trrs_render_triangles_clipped_total",
+                "Triangles cut by the near plane.",
+                totals.clipped,
+            ),
+            (
+                "trrs_render_triangles_culled_total",
+                "Triangles behind the eye, back facing or degenerate.",
+                totals.culled,
+            ),
+            (
+                "trrs_render_fragments_total",
+                "Fragments shaded.",
+                totals.fragments,
+            ),
+            (
+                "trrs_render_depth_rejects_total",
+                "Fragments rejected by the depth test.",
+                totals.depth_rejects,
+            ),
+        ] {
+            family(w, name, "counter", help);
+            sample(w, name, &[], value);
+        }
+        family(
+            w,
+            "trrs_render_stage_seconds_total",
+            "counter",
+            "Time spent in each stage of the renders.",
+        );
+        for (stage, seconds) in &self.stages {
+            sample(
+                w,
+                "trrs_render_stage_seconds_total",
+                &[("stage", stage)],
+                *seconds,
+            );
+        }
+
+        family(
+            w,
+            "trrs_cache_requests_total",
+            "counter",
+            "Lookups of the render cache, by where they were answered from.",
+        );
+        for (result, n) in [
+            ("hit", cache.hits),
+            ("disk_hit", cache.disk_hits),
+            ("miss", cache.misses),
+        ] {
+            sample(w, "trrs_cache_requests_total", &[("result", result)], n);
+        }
+        for (name, kind, help, memory, disk) in [
+            (
+                "trrs_cache_evictions_total",
+                "counter",
+                "Renders evicted from the cache.",
+                cache.evictions,
+                cache.disk_evictions,
+            ),
+            (
+                "trrs_cache_entries",
+                "gauge",
+                "Renders in the cache.",
+                cache.entries as u64,
+                cache.disk_entries as u64,
+            ),
+            (
+                "trrs_cache_bytes",
+                "gauge",
+                "Size of the renders in the cache.",
+                cache.bytes as u64,
+                cache.disk_bytes as u64,
+            ),
+            (
+                "trrs_cache_capacity_bytes",
+                "gauge",
+                "Budget of the cache.",
+                cache.capacity as u64,
+                cache.disk_capacity as u64,
+            ),
+        ] {
+            family(w, name, kind, help);
+            sample(w, name, &[("tier", "memory")], memory);
+            sample(w, name, &[("tier", "disk")], disk);
+        }
+
+        family(
+            w,
+            "trrs_asset_cache_requests_total",
+            "counter",
+            "Lookups of the parsed models.",
+        );
+        for (result, n) in [("hit", assets.hits), ("miss", assets.misses)] {
+            sample(
+                w,
+                "trrs_asset_cache_requests_total",
+                &[("result", result)],
+                n,
+            );
+        }
+        for (name, kind, help, value) in [
+            (
+                "trrs_asset_cache_evictions_total",
+                "counter",
+                "Models evicted from the cache.",
+                assets.evictions,
+            ),
+            (
+                "trrs_asset_cache_entries",
+                "gauge",
+                "Models in the cache.",
+                assets.entries as u64,
+            ),
+            (
+                "trrs_asset_cache_bytes",
+                "gauge",
+                "Estimated size of the models in the cache.",
+                assets.bytes as u64,
+            ),
+        ] {
+            family(w, name, kind, help);
+            sample(w, name, &[], value);
+        }
+        out
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind).unwrap();
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl ToString) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect();
+        write!(out, "{{{}}}", labels.join(",")).unwrap();
+    }
+    writeln!(out, " {}", value.to_string()).unwrap();
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub fn metrics() -> MutexGuard<'static, Metrics> {
+    static METRICS: OnceLock<Mutex<Metrics>> = OnceLock::new();
+    METRICS
+        .get_or_init(|| Mutex::new(Metrics::default()))
+        .lock()
+        .unwrap()
+}
+
+/// `GET /metrics`
+pub fn text() -> String {
+    metrics().encode(&cache::stats(), &assets::stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mut metrics = Metrics::default();
+        metrics.request("/shaders/texture", 200, Duration::from_millis(40));
+        metrics.request("/shaders/texture", 200, Duration::from_millis(700));
+        metrics.request("/debug/:view", 404, Duration::from_millis(1));
+        metrics.render(&RenderStats {
+            triangles: 10,
+            fragments: 100,
+            stages: vec![("raster", Duration::from_millis(500))],
+            ..Default::default()
+        });
+        let text = metrics.encode(
+            &cache::CacheStats {
+                hits: 3,
+                ..Default::default()
+            },
+            &assets::CacheStats::default(),
+        );
+        let lines: Vec<&str> = text.lines().collect();
+        for line in [
+            r#"trrs_requests_total{route="/shaders/texture",status="200"} 2"#,
+            r#"trrs_requests_total{route="/debug/:view",status="404"} 1"#,
+            r#"trrs_request_duration_seconds_bucket{route="/shaders/texture",le="0.05"} 1"#,
+            r#"trrs_request_duration_seconds_bucket{route="/shaders/texture",le="1"} 2"#,
+            r#"trrs_request_duration_seconds_bucket{route="/shaders/texture",le="+Inf"} 2"#,
+            r#"trrs_request_duration_seconds_count{route="/shaders/texture"} 2"#,
+            "trrs_render_fragments_total 100",
+            r#"trrs_render_stage_seconds_total{stage="raster"} 0.5"#,
+            r#"trrs_cache_requests_total{result="hit"} 3"#,
+            "# TYPE trrs_render_queue_depth gauge",
+        ] {
+            assert!(lines.contains(&line), "missing {}", line);
+        }
+        // every sample is a name, optional labels and a number
+        for line in lines.iter().filter(|l| !l.starts_with('#')) {
+            let (_, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+        }
+        assert_eq!(escape("a\"b\\"), "a\\\"b\\\\");
+    }
+
+    #[test]
+    fn test_track() {
+        static GAUGE: AtomicU64 = AtomicU64::new(0);
+        let a = track(&GAUGE);
+        let b = track(&GAUGE);
+        assert_eq!(GAUGE.load(Ordering::Relaxed), 2);
+        drop(a);
+        drop(b);
+        assert_eq!(GAUGE.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,99 +1,223 @@
 use std::{
+    collections::HashMap,
+    fmt,
     fs::File,
     io::{self, BufRead, BufReader},
     path::Path,
+    str::FromStr,
 };
 
 use image::{Rgba, RgbaImage};
 
 use crate::{
+    bounds::{Aabb, Sphere},
     geometry::{Vec2f, Vec2i, Vec3f, Vec3i},
+    hdr::{decode_srgb, HdrImage},
     util::{splitext, RgbaImageExt},
 };
 
+/// One distinct position/uv/normal combination of the faces.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vertex {
+    pub position: Vec3f,
+    pub uv: Vec2f,
+    pub normal: Vec3f, // normalized
+}
+
 #[derive(Debug)]
 pub struct Model {
     pub verts: Vec<Vec3f>,
     pub faces: Vec<Vec<Vec3i>>, // vertex/uv/normal indexes
     pub norms: Vec<Vec3f>,
     pub uvs: Vec<Vec2f>,
-    pub diffuse_map: RgbaImage,
+    /// Indexed vertex buffer, every corner of every face points into it
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<[usize; 3]>, // one per face, in the order of `faces`
+    pub diffuse_map: HdrImage,    // linear, decoded from sRGB on load
     pub normal_map: RgbaImage,
     pub specular_map: RgbaImage,
+    pub glow_map: Option<HdrImage>, // linear emissive color, only some models ship one
+    pub emission: f32,              // scale applied to the glow map
+}
+
+/// Why a model could not be loaded, every variant names the file at fault.
+#[derive(Debug)]
+pub enum ModelError {
+    Read(String, io::Error),
+    /// A malformed line of the `.obj` file, numbered from 1
+    Parse {
+        path: String,
+        line: usize,
+        message: String,
+    },
+    Texture(String, image::ImageError),
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Read(path, err) => write!(f, "{}: {}", path, err),
+            ModelError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path, line, message),
+            ModelError::Texture(path, err) => write!(f, "{}: {}", path, err),
+        }
+    }
 }
 
+impl std::error::Error for ModelError {}
+
 impl Model {
+    /// Loads `filename` and its textures, panics when it cannot, see `load`.
     pub fn new(filename: &str) -> Self {
-        if let Ok(lines) = Self::read_lines(filename) {
-            let mut verts: Vec<Vec3f> = Vec::new();
-            let mut faces: Vec<Vec<Vec3i>> = Vec::new();
-            let mut norms: Vec<Vec3f> = Vec::new();
-            let mut uvs: Vec<Vec2f> = Vec::new();
-            for line in lines {
-                let mut words = line.split_whitespace();
-                match words.next() {
-                    Some("v") => {
-                        let mut vert: Vec3f = Vec3f::new();
-                        for i in 0..3 {
-                            vert[i] = words.next().unwrap().parse::<f64>().unwrap();
-                        }
-                        verts.push(vert);
+        Self::load(filename).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Parses `filename` and loads the textures next to it, `_diffuse.tga`, `_nm.tga` and
+    /// `_spec.tga` are required and `_glow.tga` is optional.
+    pub fn load(filename: &str) -> Result<Self, ModelError> {
+        let lines = Self::read_lines(filename)
+            .map_err(|err| ModelError::Read(filename.to_string(), err))?;
+        let mut verts: Vec<Vec3f> = Vec::new();
+        let mut faces: Vec<Vec<Vec3i>> = Vec::new();
+        let mut norms: Vec<Vec3f> = Vec::new();
+        let mut uvs: Vec<Vec2f> = Vec::new();
+        for (n, line) in lines.iter().enumerate() {
+            let parse_error = |message: String| ModelError::Parse {
+                path: filename.to_string(),
+                line: n + 1,
+                message,
+            };
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("v") => {
+                    let mut vert: Vec3f = Vec3f::new();
+                    for i in 0..3 {
+                        vert[i] = number(words.next()).map_err(parse_error)?;
                     }
-                    Some("vn") => {
-                        let mut norm: Vec3f = Vec3f::new();
-                        for i in 0..3 {
-                            norm[i] = words.next().unwrap().parse::<f64>().unwrap();
-                        }
-                        norms.push(norm);
+                    verts.push(vert);
+                }
+                Some("vn") => {
+                    let mut norm: Vec3f = Vec3f::new();
+                    for i in 0..3 {
+                        norm[i] = number(words.next()).map_err(parse_error)?;
                     }
-                    Some("vt") => {
-                        let mut uv = Vec2f::new();
-                        for i in 0..2 {
-                            uv[i] = words.next().unwrap().parse::<f64>().unwrap();
-                        }
-                        uvs.push(uv);
+                    norms.push(norm);
+                }
+                Some("vt") => {
+                    let mut uv = Vec2f::new();
+                    for i in 0..2 {
+                        uv[i] = number(words.next()).map_err(parse_error)?;
                     }
-                    Some("f") => {
-                        let mut face = Vec::new();
-                        let mut tmp = Vec3i::new();
-                        for word in words {
-                            let mut nums = word.split('/');
-                            for i in 0..3 {
-                                tmp[i] = nums.next().unwrap().parse::<i32>().unwrap() - 1
+                    uvs.push(uv);
+                }
+                Some("f") => {
+                    let mut face = Vec::new();
+                    let mut tmp = Vec3i::new();
+                    for word in words {
+                        let mut nums = word.split('/');
+                        let counts = [verts.len(), uvs.len(), norms.len()];
+                        for i in 0..3 {
+                            let index: i32 = number(nums.next()).map_err(parse_error)?;
+                            if !(1..=counts[i] as i32).contains(&index) {
+                                return Err(parse_error(format!(
+                                    "index {} out of range 1..={}",
+                                    index, counts[i]
+                                )));
                             }
-                            face.push(tmp);
+                            tmp[i] = index - 1
                         }
-                        faces.push(face);
+                        face.push(tmp);
                     }
-                    _ => {}
+                    if face.len() < 3 {
+                        return Err(parse_error("a face needs 3 corners".to_string()));
+                    }
+                    faces.push(face);
                 }
+                _ => {}
             }
-
-            let diffusemap = Self::load_texture(filename, "_diffuse.tga");
-            let normalmap = Self::load_texture(filename, "_nm.tga");
-            let specularmap = Self::load_texture(filename, "_spec.tga");
-            let model = Self {
-                verts,
-                faces,
-                norms,
-                uvs,
-                diffuse_map: diffusemap,
-                normal_map: normalmap,
-                specular_map: specularmap,
-            };
-            return model;
         }
-        panic!("Failed to read file")
+
+        let diffusemap = decode_srgb(&Self::load_texture(filename, "_diffuse.tga")?);
+        let normalmap = Self::load_texture(filename, "_nm.tga")?;
+        let specularmap = Self::load_texture(filename, "_spec.tga")?;
+        let glowmap = Self::try_load_texture(filename, "_glow.tga")?.map(|img| decode_srgb(&img));
+        let (vertices, indices) = Self::index_buffers(&verts, &faces, &norms, &uvs);
+        Ok(Self {
+            verts,
+            faces,
+            norms,
+            uvs,
+            vertices,
+            indices,
+            diffuse_map: diffusemap,
+            normal_map: normalmap,
+            specular_map: specularmap,
+            glow_map: glowmap,
+            emission: 2.0,
+        })
+    }
+
+    /// Deduplicates the vertex/uv/normal triples of the faces, faces with more than three
+    /// corners keep the first three like the per-face accessors.
+    fn index_buffers(
+        verts: &[Vec3f],
+        faces: &[Vec<Vec3i>],
+        norms: &[Vec3f],
+        uvs: &[Vec2f],
+    ) -> (Vec<Vertex>, Vec<[usize; 3]>) {
+        let mut vertices: Vec<Vertex> = Vec::new();
+        let mut seen: HashMap<[i32; 3], usize> = HashMap::new();
+        let indices = faces
+            .iter()
+            .map(|face| {
+                let mut triangle = [0; 3];
+                for (index, corner) in triangle.iter_mut().zip(face.iter()) {
+                    *index = *seen.entry(corner.data).or_insert_with(|| {
+                        let mut normal = norms[corner[2] as usize];
+                        normal.normalize();
+                        vertices.push(Vertex {
+                            position: verts[corner[0] as usize],
+                            uv: uvs[corner[1] as usize],
+                            normal,
+                        });
+                        vertices.len() - 1
+                    });
+                }
+                triangle
+            })
+            .collect();
+        (vertices, indices)
     }
 
     pub fn nverts(&self) -> usize {
         self.verts.len()
     }
 
+    /// Axis-aligned bounds of the vertices.
+    pub fn bbox(&self) -> Aabb {
+        Aabb::from_points(&self.verts)
+    }
+
+    pub fn bounding_sphere(&self) -> Sphere {
+        Sphere::from_points(&self.verts)
+    }
+
     pub fn nfaces(&self) -> usize {
         self.faces.len()
     }
 
+    /// Position in `vertices` of corner `nthvert` of face `iface`.
+    pub fn index(&self, iface: usize, nthvert: usize) -> usize {
+        self.indices[iface][nthvert]
+    }
+
+    pub fn vertex(&self, i: usize) -> &Vertex {
+        &self.vertices[i]
+    }
+
     pub fn vert(&self, i: usize) -> &Vec3f {
         &self.verts[i]
     }
@@ -136,7 +260,7 @@ impl Model {
         r
     }
 
-    pub fn diffuse(&self, uv0: f64, uv1: f64) -> Rgba<u8> {
+    pub fn diffuse(&self, uv0: f64, uv1: f64) -> Rgba<f32> {
         let uv = (
             (uv0 * self.diffuse_map.width() as f64) as u32,
             (uv1 * self.diffuse_map.height() as f64) as u32,
@@ -154,12 +278,43 @@ impl Model {
         color[0] as f64 / 1.0
     }
 
-    pub fn load_texture(filename: &str, suffix: &str) -> RgbaImage {
+    /// Emitted radiance at `uv`, black for models without a glow map.
+    pub fn glow(&self, uv0: f64, uv1: f64) -> Rgba<f32> {
+        match &self.glow_map {
+            Some(glow_map) => {
+                let uv = (
+                    (uv0 * glow_map.width() as f64) as u32,
+                    (uv1 * glow_map.height() as f64) as u32,
+                );
+                let color = glow_map.get_pixel(uv.0, uv.1);
+                Rgba([
+                    color[0] * self.emission,
+                    color[1] * self.emission,
+                    color[2] * self.emission,
+                    1.0,
+                ])
+            }
+            None => Rgba([0.0, 0.0, 0.0, 1.0]),
+        }
+    }
+
+    pub fn try_load_texture(filename: &str, suffix: &str) -> Result<Option<RgbaImage>, ModelError> {
+        let (name, _) = splitext(filename);
+        if Path::new(&(name + suffix)).exists() {
+            Self::load_texture(filename, suffix).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn load_texture(filename: &str, suffix: &str) -> Result<RgbaImage, ModelError> {
         let (name, ext) = splitext(filename);
         let p = name + suffix;
-        let mut img = image::open(p).unwrap().to_rgba8();
+        let mut img = image::open(&p)
+            .map_err(|err| ModelError::Texture(p, err))?
+            .to_rgba8();
         img.flip_vertical();
-        img
+        Ok(img)
     }
 
     fn read_lines<P>(filename: P) -> io::Result<Vec<String>>
@@ -172,6 +327,13 @@ impl Model {
     }
 }
 
+/// The next word of a line as a number.
+fn number<T: FromStr>(word: Option<&str>) -> Result<T, String> {
+    let word = word.ok_or("missing number")?;
+    word.parse()
+        .map_err(|_| format!("`{}` is not a number", word))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -184,4 +346,71 @@ mod tests {
         assert!(model.norms.len() > 0);
         println!("nverts:{:}, norms:{:}", model.nverts(), model.norms.len());
     }
+
+    #[test]
+    fn test_load_errors() {
+        let err = |path: &str| Model::load(path).unwrap_err().to_string();
+        assert!(err("obj/missing.obj").starts_with("obj/missing.obj: "));
+        assert!(err("obj/floor.obj").starts_with("obj/floor_nm.tga: "));
+
+        let dir = std::env::temp_dir().join(format!("trrs-model-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bad.obj");
+        let path = path.to_str().unwrap();
+        for (obj, message) in [
+            ("v 1 2\n", "1: missing number"),
+            ("v 1 2 x\n", "1: `x` is not a number"),
+            (
+                "v 0 0 0\nvt 0 0\nvn 0 0 1\nf 1/1/1 2/1/1 1/1/1\n",
+                "4: index 2 out of range 1..=1",
+            ),
+            (
+                "v 0 0 0\nvt 0 0\nvn 0 0 1\nf 1/1/1 1/1/1\n",
+                "4: a face needs 3 corners",
+            ),
+        ] {
+            std::fs::write(path, obj).unwrap();
+            assert_eq!(err(path), format!("{}:{}", path, message));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_glow_map() {
+        let model = Model::new("obj/african_head/african_head.obj");
+        assert!(model.glow_map.is_none());
+        assert_eq!(model.glow(0.5, 0.5), Rgba([0.0, 0.0, 0.0, 1.0]));
+
+        let model = Model::new("obj/diablo3_pose/diablo3_pose.obj");
+        assert!(model.glow_map.is_some());
+    }
+
+    #[test]
+    fn test_index_buffers() {
+        let model = Model::new("obj/african_head/african_head.obj");
+        assert_eq!(model.indices.len(), model.nfaces());
+        // shared corners are stored once
+        assert!(model.vertices.len() < model.nfaces() * 3);
+        for iface in 0..model.nfaces() {
+            for nthvert in 0..3 {
+                let vertex = model.vertex(model.index(iface, nthvert));
+                assert_eq!(vertex.position, *model.vert_by(iface, nthvert));
+                assert_eq!(vertex.uv, model.uv(iface, nthvert));
+                assert_eq!(vertex.normal, model.norm(iface, nthvert));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bounds() {
+        let model = Model::new("obj/african_head/african_head.obj");
+        let bbox = model.bbox();
+        let sphere = model.bounding_sphere();
+        assert!(!bbox.is_empty());
+        assert!(sphere.radius <= bbox.size().norm() / 2.0 + 1e-9);
+        for v in model.verts.iter() {
+            assert!((0..3).all(|i| v[i] >= bbox.min[i] && v[i] <= bbox.max[i]));
+            assert!((*v - sphere.center).norm() <= sphere.radius + 1e-9);
+        }
+    }
 }
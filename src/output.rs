@@ -0,0 +1,393 @@
+use std::{fmt, io::Cursor};
+
+use exr::prelude::{
+    AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer, LayerAttributes, SmallVec,
+    WritableImage,
+};
+use image::{
+    codecs::pnm::{PnmSubtype, SampleEncoding},
+    DynamicImage, ImageOutputFormat, RgbaImage,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::shaders::Frame;
+
+/// JPEG quality when `?quality=` is not given.
+pub const DEFAULT_QUALITY: u8 = 90;
+
+/// Encodings of a render. `Exr` and `Depth` write the linear color and depth of the `Frame`
+/// rather than its tone mapped image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Png,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Webp,
+    Tga,
+    Ppm,
+    /// Linear R, G, B, A and the view depth Z as 32 bit floats
+    Exr,
+    /// View depth as little endian 32 bit floats, top row first, infinite on the background
+    Depth,
+}
+
+impl Format {
+    pub const ALL: [Format; 7] = [
+        Format::Png,
+        Format::Jpeg,
+        Format::Webp,
+        Format::Tga,
+        Format::Ppm,
+        Format::Exr,
+        Format::Depth,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpeg",
+            Format::Webp => "webp",
+            Format::Tga => "tga",
+            Format::Ppm => "ppm",
+            Format::Exr => "exr",
+            Format::Depth => "depth",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "jpg" => Some(Format::Jpeg),
+            name => Format::ALL.into_iter().find(|f| f.name() == name),
+        }
+    }
+
+    /// `out.exr`, `out.jpg`... `.f32` and `.depth` are the raw depth.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let (_, ext) = path.rsplit_once('.')?;
+        match ext.to_ascii_lowercase().as_str() {
+            "f32" => Some(Format::Depth),
+            ext => Format::from_name(ext),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        self.media_types()[0]
+    }
+
+    /// The `Accept` entries naming this format, `content_type` first.
+    fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Format::Png => &["image/png"],
+            Format::Jpeg => &["image/jpeg", "image/jpg"],
+            Format::Webp => &["image/webp"],
+            Format::Tga => &["image/x-tga", "image/x-targa", "image/tga"],
+            Format::Ppm => &["image/x-portable-pixmap"],
+            Format::Exr => &["image/x-exr", "image/aces"],
+            Format::Depth => &["application/octet-stream"],
+        }
+    }
+
+    /// Whether the format needs the buffers of a `Frame` and cannot encode a plain image.
+    pub fn is_raw(self) -> bool {
+        matches!(self, Format::Exr | Format::Depth)
+    }
+
+    /// Best match of an `Accept` header, PNG for the `image/*` and `*/*` wildcards. Entries
+    /// are tried by decreasing `q`, in header order on ties.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        let mut ranges: Vec<(&str, f32)> = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let media_type = parts.next().filter(|t| !t.is_empty())?;
+                let q = parts
+                    .filter_map(|p| p.strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((media_type, q))
+            })
+            .filter(|(_, q)| *q > 0.0)
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges.into_iter().find_map(|(media_type, _)| {
+            let media_type = media_type.to_ascii_lowercase();
+            match media_type.as_str() {
+                "*/*" | "image/*" => Some(Format::Png),
+                t => Format::ALL
+                    .into_iter()
+                    .find(|f| f.media_types().contains(&t)),
+            }
+        })
+    }
+}
+
+/// Why no output could be chosen for a request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputError {
+    /// `?format=` or `?quality=` cannot be read, a 400
+    Invalid(String),
+    /// Nothing in `Accept` can be produced, a 406
+    NotAcceptable(String),
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputError::Invalid(msg) | OutputError::NotAcceptable(msg) => f.write_str(msg),
+        }
+    }
+}
+
+/// How a render is written out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Output {
+    pub format: Format,
+    /// JPEG quality in 1..=100
+    pub quality: u8,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self {
+            format: Format::default(),
+            quality: DEFAULT_QUALITY,
+        }
+    }
+}
+
+impl Output {
+    /// `?format=` wins over the `Accept` header, PNG when neither asks for anything.
+    pub fn negotiate(
+        format: Option<&str>,
+        quality: Option<&str>,
+        accept: Option<&str>,
+    ) -> Result<Self, OutputError> {
+        let names = || {
+            Format::ALL
+                .iter()
+                .map(|f| f.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let format = match (format, accept.map(str::trim).filter(|a| !a.is_empty())) {
+            (Some(name), _) => Format::from_name(name).ok_or_else(|| {
+                OutputError::Invalid(format!(
+                    "unknown format `{}`, expected one of: {}",
+                    name,
+                    names()
+                ))
+            })?,
+            (None, Some(accept)) => Format::from_accept(accept).ok_or_else(|| {
+                let types: Vec<&str> = Format::ALL.iter().map(|f| f.content_type()).collect();
+                OutputError::NotAcceptable(format!(
+                    "cannot produce any of `{}`, available: {}",
+                    accept,
+                    types.join(", ")
+                ))
+            })?,
+            (None, None) => Format::default(),
+        };
+        let quality = match quality {
+            Some(q) => q
+                .parse::<u8>()
+                .ok()
+                .filter(|q| (1..=100).contains(q))
+                .ok_or_else(|| {
+                    OutputError::Invalid(format!("quality `{}` must be in 1..=100", q))
+                })?,
+            None => DEFAULT_QUALITY,
+        };
+        Ok(Self { format, quality })
+    }
+
+    pub fn encode(&self, frame: &Frame) -> Vec<u8> {
+        match self.format {
+            Format::Exr => encode_exr(frame),
+            Format::Depth => frame
+                .depth()
+                .into_iter()
+                .flat_map(f32::to_le_bytes)
+                .collect(),
+            _ => self.encode_image(&frame.image).unwrap(),
+        }
+    }
+
+    /// For the renders that only produce an image, `None` for the raw formats.
+    pub fn encode_image(&self, img: &RgbaImage) -> Option<Vec<u8>> {
+        let (img, format) = match self.format {
+            Format::Png => (
+                DynamicImage::ImageRgba8(img.clone()),
+                ImageOutputFormat::Png,
+            ),
+            Format::Webp => (
+                DynamicImage::ImageRgba8(img.clone()),
+                ImageOutputFormat::WebP,
+            ),
+            Format::Tga => (
+                DynamicImage::ImageRgba8(img.clone()),
+                ImageOutputFormat::Tga,
+            ),
+            // neither has an alpha channel
+            Format::Jpeg => (
+                DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img.clone()).into_rgb8()),
+                ImageOutputFormat::Jpeg(self.quality),
+            ),
+            Format::Ppm => (
+                DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img.clone()).into_rgb8()),
+                ImageOutputFormat::Pnm(PnmSubtype::Pixmap(SampleEncoding::Binary)),
+            ),
+            Format::Exr | Format::Depth => return None,
+        };
+        let mut bs = Vec::new();
+        img.write_to(&mut Cursor::new(&mut bs), format).unwrap();
+        Some(bs)
+    }
+}
+
+/// One layer with the linear color and the view depth, top row first.
+fn encode_exr(frame: &Frame) -> Vec<u8> {
+    let hdr = frame.hdr_image();
+    let channel = |name: &str, i: usize| {
+        let samples = hdr.pixels().map(|p| p[i]).collect();
+        AnyChannel::new(name, FlatSamples::F32(samples))
+    };
+    let channels = AnyChannels::sort(SmallVec::from_vec(vec![
+        channel("R", 0),
+        channel("G", 1),
+        channel("B", 2),
+        channel("A", 3),
+        AnyChannel::new("Z", FlatSamples::F32(frame.depth())),
+    ]));
+    let size = (hdr.width() as usize, hdr.height() as usize);
+    let layer = Layer::new(
+        size,
+        LayerAttributes::named(""),
+        Encoding::FAST_LOSSLESS,
+        channels,
+    );
+    let mut bs = Vec::new();
+    Image::from_layer(layer)
+        .write()
+        .to_buffered(Cursor::new(&mut bs))
+        .unwrap();
+    bs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{camera::CameraOptions, post::PostProcess, shaders};
+    use exr::prelude::traits::*;
+
+    #[test]
+    fn test_from_accept() {
+        assert_eq!(Format::from_accept("image/webp"), Some(Format::Webp));
+        assert_eq!(Format::from_accept("*/*"), Some(Format::Png));
+        assert_eq!(
+            Format::from_accept("image/png;q=0.5, image/x-exr"),
+            Some(Format::Exr)
+        );
+        assert_eq!(
+            Format::from_accept("text/html, image/jpeg;q=0.9, image/*;q=0.8"),
+            Some(Format::Jpeg)
+        );
+        assert_eq!(
+            Format::from_accept("image/x-exr;q=0, image/*"),
+            Some(Format::Png)
+        );
+        assert_eq!(Format::from_accept("text/html, image/avif"), None);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let output = |format, quality, accept| Output::negotiate(format, quality, accept);
+        assert_eq!(output(None, None, None), Ok(Output::default()));
+        assert_eq!(
+            output(Some("jpg"), Some("75"), Some("image/webp")),
+            Ok(Output {
+                format: Format::Jpeg,
+                quality: 75
+            })
+        );
+        assert_eq!(
+            output(None, None, Some("application/octet-stream"))
+                .unwrap()
+                .format,
+            Format::Depth
+        );
+        assert!(matches!(
+            output(Some("gif"), None, None),
+            Err(OutputError::Invalid(_))
+        ));
+        assert!(matches!(
+            output(None, Some("0"), None),
+            Err(OutputError::Invalid(_))
+        ));
+        assert!(matches!(
+            output(None, None, Some("text/html")),
+            Err(OutputError::NotAcceptable(_))
+        ));
+        assert_eq!(Format::from_path("out/z.f32"), Some(Format::Depth));
+        assert_eq!(Format::from_path("a.JPG"), Some(Format::Jpeg));
+        assert_eq!(Format::from_path("a"), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        let options = shaders::RenderOptions {
+            width: 48,
+            height: 32,
+            ..Default::default()
+        };
+        let frame = shaders::gouraud::gouraud_render(
+            PostProcess::default(),
+            CameraOptions::default(),
+            &options,
+        );
+        for format in Format::ALL {
+            let bs = Output {
+                format,
+                ..Default::default()
+            }
+            .encode(&frame);
+            match format {
+                Format::Depth => assert_eq!(bs.len(), 48 * 32 * 4),
+                Format::Exr => assert_eq!(&bs[..4], &[0x76, 0x2f, 0x31, 0x01]),
+                _ => {
+                    let format = image::ImageFormat::from_mime_type(format.content_type());
+                    let img = image::load_from_memory_with_format(&bs, format.unwrap()).unwrap();
+                    assert_eq!((img.width(), img.height()), (48, 32));
+                }
+            }
+        }
+
+        // the model is in front of the eye, nothing was drawn in the corners
+        let depth = frame.depth();
+        let center = depth[16 * 48 + 24];
+        assert!(center.is_finite() && center > 0.0);
+        assert_eq!(depth[0], f32::INFINITY);
+
+        let bs = Output {
+            format: Format::Exr,
+            ..Default::default()
+        }
+        .encode(&frame);
+        let exr = exr::prelude::read()
+            .no_deep_data()
+            .largest_resolution_level()
+            .all_channels()
+            .first_valid_layer()
+            .all_attributes()
+            .from_buffered(Cursor::new(bs))
+            .unwrap();
+        let channels = &exr.layer_data.channel_data.list;
+        let names: Vec<String> = channels.iter().map(|c| c.name.to_string()).collect();
+        assert_eq!(names, ["A", "B", "G", "R", "Z"]);
+        match &channels[4].sample_data {
+            FlatSamples::F32(z) => assert_eq!(z[16 * 48 + 24], center),
+            _ => panic!("depth is not f32"),
+        }
+    }
+}
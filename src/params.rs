@@ -0,0 +1,529 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use serde::Serialize;
+
+use crate::{
+    animation, assets, camera, culling, geometry::Vec3f, hdr, output, post, shaders,
+    shaders::ambientocclusion as ao,
+};
+
+/// Largest accepted width or height, unless configured otherwise.
+pub const MAX_SIZE: u32 = 4096;
+
+static MAX: AtomicU32 = AtomicU32::new(MAX_SIZE);
+
+/// Largest accepted width or height.
+pub fn max_size() -> u32 {
+    MAX.load(Ordering::Relaxed)
+}
+
+/// Changes `max_size`, at startup.
+pub fn set_max_size(size: u32) {
+    MAX.store(size, Ordering::Relaxed);
+}
+
+/// A query parameter as listed by `/api/routes`. Without a `default` the render decides.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Param {
+    pub name: &'static str,
+    pub default: Option<String>,
+    pub description: &'static str,
+}
+
+fn param(name: &'static str, default: Option<String>, description: &'static str) -> Param {
+    Param {
+        name,
+        default,
+        description,
+    }
+}
+
+/// The parameters of `post_process`.
+pub fn post_process_params() -> Vec<Param> {
+    let tone = hdr::ToneMapping::default();
+    let bloom = post::Bloom::default();
+    let ssao = shaders::ambientocclusion::Ssao::default();
+    vec![
+        param(
+            "tonemap",
+            Some(tone.operator.name().to_string()),
+            "clamp, reinhard, aces or exposure",
+        ),
+        param(
+            "exposure",
+            Some(tone.exposure.to_string()),
+            "scales the linear color",
+        ),
+        param(
+            "bloom",
+            Some("0".to_string()),
+            "glow around the bright pixels",
+        ),
+        param(
+            "bloom_threshold",
+            Some(bloom.threshold.to_string()),
+            "brightness where the bloom starts",
+        ),
+        param(
+            "bloom_intensity",
+            Some(bloom.intensity.to_string()),
+            "strength of the glow",
+        ),
+        param(
+            "bloom_sigma",
+            Some(bloom.sigma.to_string()),
+            "blur radius in pixels, 0.1 to 64",
+        ),
+        param(
+            "ssao",
+            Some("0".to_string()),
+            "screen-space ambient occlusion",
+        ),
+        param(
+            "ssao_radius",
+            Some(ssao.radius.to_string()),
+            "sampling radius in view space, 0.01 to 10",
+        ),
+        param(
+            "ssao_samples",
+            Some(ssao.samples.to_string()),
+            "samples per pixel, 1 to 256",
+        ),
+        param(
+            "ssao_blur",
+            Some(ssao.blur_radius.to_string()),
+            "blur radius in pixels, 0 to 16",
+        ),
+        param(
+            "ssao_strength",
+            Some(ssao.strength.to_string()),
+            "how much the occlusion darkens",
+        ),
+    ]
+}
+
+/// The parameters of `camera_options`.
+pub fn camera_params() -> Vec<Param> {
+    let camera = camera::CameraOptions::default();
+    vec![
+        param(
+            "view",
+            None,
+            "front, back, left, right, top, bottom or isometric",
+        ),
+        param(
+            "projection",
+            None,
+            "perspective, orthographic, cabinet or cavalier",
+        ),
+        param("azimuth", None, "orbit angle in degrees"),
+        param("elevation", None, "orbit angle in degrees"),
+        param("roll", None, "orbit angle in degrees"),
+        param(
+            "fit",
+            Some((camera.fit as u8).to_string()),
+            "frame the bounding box of the model",
+        ),
+        param("zoom", None, "magnification, 2 is twice as close"),
+    ]
+}
+
+/// The parameters of `culling`.
+pub fn culling_params() -> Vec<Param> {
+    let culling = culling::Culling::default();
+    vec![
+        param(
+            "cull",
+            Some((culling.frustum as u8).to_string()),
+            "skip the meshes outside the view",
+        ),
+        param(
+            "occlusion",
+            Some((culling.occlusion as u8).to_string()),
+            "skip the meshes hidden by the earlier ones",
+        ),
+    ]
+}
+
+/// The parameters of `render_options`.
+pub fn render_params() -> Vec<Param> {
+    let options = shaders::RenderOptions::default();
+    let light = options.light_dir;
+    vec![
+        param("width", Some(options.width.to_string()), "pixels"),
+        param("height", Some(options.height.to_string()), "pixels"),
+        param(
+            "light",
+            Some(format!("{:.3},{:.3},{:.3}", light[0], light[1], light[2])),
+            "direction towards the key light",
+        ),
+        param("asset", None, "id of an uploaded model"),
+    ]
+}
+
+/// The parameters of `output`.
+pub fn output_params() -> Vec<Param> {
+    let default = output::Output::default();
+    vec![
+        param(
+            "format",
+            Some(default.format.name().to_string()),
+            "png, jpeg, webp, tga, ppm, exr or depth, else the Accept header decides",
+        ),
+        param("quality", Some(default.quality.to_string()), "JPEG quality"),
+    ]
+}
+
+/// Reads `?tonemap=reinhard|aces|exposure|clamp&exposure=1.5` and
+/// `?bloom=1&bloom_threshold=1.0&bloom_intensity=0.6&bloom_sigma=4` and
+/// `?ssao=1&ssao_radius=0.25&ssao_samples=16&ssao_blur=2&ssao_strength=1` from the query string.
+/// An unknown operator or an exposure that is not positive is an error.
+pub fn post_process(params: &HashMap<String, String>) -> Result<post::PostProcess, String> {
+    let mut post = post::PostProcess::default();
+    if let Some(name) = params.get("tonemap") {
+        post.tone.operator = hdr::Operator::from_name(name).ok_or_else(|| {
+            format!(
+                "tonemap: unknown operator `{}`, expected one of: {}",
+                name,
+                hdr::Operator::NAMES.join(", ")
+            )
+        })?;
+    }
+    if let Some(exposure) = params.get("exposure").and_then(|s| s.parse::<f32>().ok()) {
+        if !(exposure.is_finite() && exposure > 0.0) {
+            return Err("exposure: must be positive".to_string());
+        }
+        post.tone.exposure = exposure;
+    }
+    if params
+        .get("bloom")
+        .is_some_and(|s| s != "0" && s != "false")
+    {
+        let mut bloom = post::Bloom::default();
+        if let Some(threshold) = params.get("bloom_threshold").and_then(|s| s.parse().ok()) {
+            bloom.threshold = threshold;
+        }
+        if let Some(intensity) = params.get("bloom_intensity").and_then(|s| s.parse().ok()) {
+            bloom.intensity = intensity;
+        }
+        if let Some(sigma) = params
+            .get("bloom_sigma")
+            .and_then(|s| s.parse::<f32>().ok())
+            .filter(|s| s.is_finite())
+        {
+            bloom.sigma = sigma.clamp(*post::SIGMA.start(), *post::SIGMA.end());
+        }
+        post.bloom = Some(bloom);
+    }
+    if params.get("ssao").is_some_and(|s| s != "0" && s != "false") {
+        let mut ssao = ao::Ssao::default();
+        if let Some(radius) = params
+            .get("ssao_radius")
+            .and_then(|s| s.parse::<f64>().ok())
+            .filter(|r| r.is_finite())
+        {
+            ssao.radius = radius.clamp(*ao::RADIUS.start(), *ao::RADIUS.end());
+        }
+        if let Some(samples) = params
+            .get("ssao_samples")
+            .and_then(|s| s.parse::<usize>().ok())
+        {
+            ssao.samples = samples.clamp(*ao::SAMPLES.start(), *ao::SAMPLES.end());
+        }
+        if let Some(blur) = params
+            .get("ssao_blur")
+            .and_then(|s| s.parse::<usize>().ok())
+        {
+            ssao.blur_radius = blur.clamp(*ao::BLUR.start(), *ao::BLUR.end());
+        }
+        if let Some(strength) = params.get("ssao_strength").and_then(|s| s.parse().ok()) {
+            ssao.strength = strength;
+        }
+        post.ssao = Some(ssao);
+    }
+    Ok(post)
+}
+
+/// Reads `?view=front|back|left|right|top|bottom|isometric`,
+/// `?projection=perspective|orthographic|cabinet|cavalier` and the orbit angles in degrees
+/// `?azimuth=30&elevation=15&roll=0`, `?fit=1` and `?zoom=2` from the query string. An
+/// unknown view or projection is an error listing the valid names.
+pub fn camera_options(params: &HashMap<String, String>) -> Result<camera::CameraOptions, String> {
+    let degrees = |key: &str| {
+        params
+            .get(key)
+            .and_then(|s| s.parse::<f64>().ok())
+            .map(f64::to_radians)
+    };
+    let view = params
+        .get("view")
+        .map(|s| {
+            camera::View::from_name(s).ok_or_else(|| {
+                let names: Vec<&str> = camera::View::ALL.iter().map(|v| v.name()).collect();
+                format!(
+                    "view: unknown view `{}`, expected one of: {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+        })
+        .transpose()?;
+    let projection = params
+        .get("projection")
+        .map(|s| {
+            camera::Projection::from_name(s).ok_or_else(|| {
+                format!(
+                    "projection: unknown projection `{}`, expected one of: {}",
+                    s,
+                    camera::Projection::NAMES.join(", ")
+                )
+            })
+        })
+        .transpose()?;
+    Ok(camera::CameraOptions {
+        view,
+        projection,
+        azimuth: degrees("azimuth"),
+        elevation: degrees("elevation"),
+        roll: degrees("roll"),
+        fit: params.get("fit").is_some_and(|s| s != "0" && s != "false"),
+        zoom: params.get("zoom").and_then(|s| s.parse().ok()),
+    })
+}
+
+/// Reads `?cull=0` to draw every mesh and `?occlusion=1` to also skip the meshes hidden
+/// behind the depth of the earlier ones.
+pub fn culling(params: &HashMap<String, String>) -> culling::Culling {
+    let flag = |key: &str| params.get(key).map(|s| s != "0" && s != "false");
+    let default = culling::Culling::default();
+    culling::Culling {
+        frustum: flag("cull").unwrap_or(default.frustum),
+        occlusion: flag("occlusion").unwrap_or(default.occlusion),
+    }
+}
+
+/// Reads `?width=800&height=800` and the light direction `?light=1,1,1`, the model is only
+/// taken from `model` when `allow_model` is set as it names a file to read. `?asset=<id>`
+/// selects an uploaded model and is always accepted, unknown ids are ignored.
+pub fn render_options(
+    params: &HashMap<String, String>,
+    allow_model: bool,
+) -> shaders::RenderOptions {
+    let mut options = shaders::RenderOptions::default();
+    if allow_model {
+        options.model = params.get("model").cloned();
+    }
+    if let Some(path) = params
+        .get("asset")
+        .filter(|id| assets::is_id(id))
+        .map(|id| assets::upload_path(id))
+        .filter(|path| Path::new(path).is_file())
+    {
+        options.model = Some(path);
+    }
+    let size = |key: &str| {
+        params
+            .get(key)
+            .and_then(|s| s.parse::<u32>().ok())
+            .map(|v| v.clamp(1, max_size()))
+    };
+    if let Some(width) = size("width") {
+        options.width = width;
+    }
+    if let Some(height) = size("height") {
+        options.height = height;
+    }
+    if let Some(mut light) = params.get("light").and_then(|s| parse_vec3(s)) {
+        if light.norm() > 0.0 {
+            options.light_dir = light.normalize();
+        }
+    }
+    options
+}
+
+/// Reads `?format=png|jpeg|webp|tga|ppm|exr|depth&quality=90`, the `Accept` header
+/// decides when `format` is missing.
+pub fn output(
+    params: &HashMap<String, String>,
+    accept: Option<&str>,
+) -> Result<output::Output, output::OutputError> {
+    output::Output::negotiate(
+        params.get("format").map(String::as_str),
+        params.get("quality").map(String::as_str),
+        accept,
+    )
+}
+
+/// Reads `?animation=turntable|light|keyframes&period=6`, the keyframes are
+/// `?keys=0,15;90,30,1.5` (azimuth and elevation in degrees, optional zoom) and imply
+/// `animation=keyframes`.
+pub fn animation(params: &HashMap<String, String>) -> Result<animation::Animation, String> {
+    let mut animation = animation::Animation::default();
+    let keys = params.get("keys");
+    animation.motion = match (params.get("animation").map(String::as_str), keys) {
+        (Some("keyframes") | None, Some(keys)) => animation::Motion::Keyframes(
+            keys.split(';')
+                .map(|k| {
+                    animation::Keyframe::parse(k).ok_or(format!("keys: invalid keyframe `{}`", k))
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        (Some("keyframes"), None) => {
+            return Err("keys: required by animation=keyframes".to_string())
+        }
+        (Some(name), _) => animation::Motion::from_name(name).ok_or(format!(
+            "animation: unknown animation `{}`, expected one of: turntable, light, keyframes",
+            name
+        ))?,
+        (None, None) => animation::Motion::Turntable,
+    };
+    if let Some(period) = params.get("period") {
+        animation.period = period
+            .parse()
+            .ok()
+            .filter(|p: &f64| p.is_finite() && *p > 0.0)
+            .ok_or(format!(
+                "period: `{}` must be a positive number of seconds",
+                period
+            ))?;
+    }
+    Ok(animation)
+}
+
+/// `x,y,z`
+pub fn parse_vec3(s: &str) -> Option<Vec3f> {
+    let v: Vec<f64> = s
+        .split(',')
+        .map(|c| c.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    <[f64; 3]>::try_from(v).ok().map(Vec3f::from)
+}
+
+/// `--name value`, `--name=value` and bare `--flag` (read as `1`) command line arguments as
+/// the same map as a query string.
+pub fn from_args(args: &[String]) -> Result<HashMap<String, String>, String> {
+    let mut params = HashMap::new();
+    let mut args = args.iter().peekable();
+    while let Some(arg) = args.next() {
+        let Some(name) = arg.strip_prefix("--") else {
+            return Err(format!("unexpected argument `{}`", arg));
+        };
+        let (name, value) = match name.split_once('=') {
+            Some((name, value)) => (name, value.to_string()),
+            None => match args.next_if(|next| !next.starts_with("--")) {
+                Some(value) => (name, value.clone()),
+                None => (name, "1".to_string()),
+            },
+        };
+        params.insert(name.to_string(), value);
+    }
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_from_args() {
+        let params = from_args(&args("--shader texture --width=256 --fit --out a.png")).unwrap();
+        assert_eq!(params["shader"], "texture");
+        assert_eq!(params["width"], "256");
+        assert_eq!(params["fit"], "1");
+        assert_eq!(params["out"], "a.png");
+        assert!(from_args(&args("texture")).is_err());
+    }
+
+    #[test]
+    fn test_render_options() {
+        let params = from_args(&args(
+            "--width 99999 --height 64 --light 0,2,0 --model x.obj",
+        ))
+        .unwrap();
+        let options = render_options(&params, false);
+        assert_eq!((options.width, options.height), (MAX_SIZE, 64));
+        assert_eq!(options.light_dir, Vec3f::from([0.0, 1.0, 0.0]));
+        assert_eq!(options.model, None);
+        assert_eq!(
+            render_options(&params, true).model.as_deref(),
+            Some("x.obj")
+        );
+        assert_eq!(parse_vec3("1,2"), None);
+        let params = from_args(&args("--asset ../../etc/passwd")).unwrap();
+        assert_eq!(render_options(&params, false).model, None);
+    }
+
+    #[test]
+    fn test_post_process() {
+        let post = |s| post_process(&from_args(&args(s)).unwrap()).unwrap();
+        let sigma = |s| post(s).bloom.unwrap().sigma;
+        assert_eq!(sigma("--bloom --bloom_sigma 2"), 2.0);
+        assert_eq!(sigma("--bloom --bloom_sigma 1e10"), 64.0);
+        assert_eq!(sigma("--bloom --bloom_sigma 0"), 0.1);
+        assert_eq!(
+            sigma("--bloom --bloom_sigma NaN"),
+            post::Bloom::default().sigma
+        );
+        assert_eq!(post("--tonemap filmic").tone.operator, hdr::Operator::Aces);
+        let post = |s| post_process(&from_args(&args(s)).unwrap());
+        assert_eq!(
+            post("--tonemap sepia"),
+            Err("tonemap: unknown operator `sepia`, expected one of: \
+                 clamp, reinhard, aces, exposure"
+                .to_string())
+        );
+        assert_eq!(
+            post("--exposure NaN"),
+            Err("exposure: must be positive".to_string())
+        );
+        assert!(post("--exposure -1").is_err());
+    }
+
+    #[test]
+    fn test_camera_options() {
+        let camera = |s| camera_options(&from_args(&args(s)).unwrap());
+        let options = camera("--view top --projection ortho").unwrap();
+        assert_eq!(options.view, Some(camera::View::Top));
+        assert!(matches!(
+            options.projection,
+            Some(camera::Projection::Orthographic { .. })
+        ));
+        assert_eq!(
+            camera("--view side"),
+            Err("view: unknown view `side`, expected one of: \
+                 front, back, left, right, top, bottom, isometric"
+                .to_string())
+        );
+        assert_eq!(
+            camera("--projection fisheye"),
+            Err(
+                "projection: unknown projection `fisheye`, expected one of: \
+                 perspective, orthographic, cabinet, cavalier"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_animation() {
+        let animation = |s| animation(&from_args(&args(s)).unwrap());
+        assert_eq!(animation(""), Ok(animation::Animation::default()));
+        let light = animation("--animation light --period 2").unwrap();
+        assert_eq!(light.motion, animation::Motion::LightOrbit);
+        assert_eq!(light.period, 2.0);
+        let keys = animation("--keys 0,15;90,30,1.5").unwrap();
+        assert!(matches!(keys.motion, animation::Motion::Keyframes(k) if k.len() == 2));
+        assert!(animation("--animation keyframes").is_err());
+        assert!(animation("--keys 0,15;x").is_err());
+        assert!(animation("--animation spin").is_err());
+        assert!(animation("--period 0").is_err());
+    }
+}
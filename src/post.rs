@@ -0,0 +1,172 @@
+use std::ops::RangeInclusive;
+
+use image::{Rgba, RgbaImage};
+
+use crate::{
+    hdr::{HdrImage, ToneMapping},
+    shaders::ambientocclusion::Ssao,
+};
+
+/// Accepted `Bloom::sigma`, the kernel covers 6 sigma.
+pub const SIGMA: RangeInclusive<f32> = 0.1..=64.0;
+
+/// Bloom: bright-pass, blur at half resolution, add back on top of the HDR image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bloom {
+    /// Luminance above which pixels start to bleed
+    pub threshold: f32,
+    /// Scale of the blurred light added back
+    pub intensity: f32,
+    /// Gaussian sigma in half-resolution pixels
+    pub sigma: f32,
+}
+
+/// Everything applied between the shaders' HDR output and the final 8-bit image.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PostProcess {
+    /// Needs the geometry, so the render functions apply it before `resolve`
+    pub ssao: Option<Ssao>,
+    pub bloom: Option<Bloom>,
+    pub tone: ToneMapping,
+}
+
+impl Default for Bloom {
+    fn default() -> Self {
+        Self {
+            threshold: 1.0,
+            intensity: 0.6,
+            sigma: 4.0,
+        }
+    }
+}
+
+impl PostProcess {
+    pub fn resolve(&self, hdr: &mut HdrImage) -> RgbaImage {
+        if let Some(bloom) = &self.bloom {
+            bloom.apply(hdr);
+        }
+        self.tone.resolve(hdr)
+    }
+}
+
+pub fn luminance(c: &Rgba<f32>) -> f32 {
+    0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]
+}
+
+impl Bloom {
+    pub fn apply(&self, hdr: &mut HdrImage) {
+        let (w, h) = (hdr.width() as usize, hdr.height() as usize);
+        let (hw, hh) = (w.div_ceil(2), h.div_ceil(2));
+
+        // bright-pass and 2x2 box downsample in one go
+        let mut half = vec![[0.0f32; 3]; hw * hh];
+        for y in 0..h {
+            for x in 0..w {
+                let c = hdr.get_pixel(x as u32, y as u32);
+                let l = luminance(c);
+                if l <= self.threshold {
+                    continue;
+                }
+                let k = (l - self.threshold) / l * 0.25;
+                let dst = &mut half[x / 2 + y / 2 * hw];
+                for i in 0..3 {
+                    dst[i] += c[i] * k;
+                }
+            }
+        }
+
+        let kernel = gaussian_kernel(self.sigma);
+        let half = blur_separable(&half, hw, hh, &kernel);
+
+        // bilinear upsample and add
+        for y in 0..h {
+            for x in 0..w {
+                let fx = ((x as f32 + 0.5) / 2.0 - 0.5).clamp(0.0, (hw - 1) as f32);
+                let fy = ((y as f32 + 0.5) / 2.0 - 0.5).clamp(0.0, (hh - 1) as f32);
+                let (x0, y0) = (fx as usize, fy as usize);
+                let (x1, y1) = ((x0 + 1).min(hw - 1), (y0 + 1).min(hh - 1));
+                let (tx, ty) = (fx - x0 as f32, fy - y0 as f32);
+                let c = hdr.get_pixel_mut(x as u32, y as u32);
+                for i in 0..3 {
+                    let top = half[x0 + y0 * hw][i] * (1.0 - tx) + half[x1 + y0 * hw][i] * tx;
+                    let bottom = half[x0 + y1 * hw][i] * (1.0 - tx) + half[x1 + y1 * hw][i] * tx;
+                    c[i] += self.intensity * (top * (1.0 - ty) + bottom * ty);
+                }
+            }
+        }
+    }
+}
+
+/// Normalized 1D Gaussian weights covering +-3 sigma.
+pub fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let radius = (sigma * 3.0).ceil().max(1.0) as i32;
+    let mut kernel: Vec<f32> = (-radius..=radius)
+        .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    for k in kernel.iter_mut() {
+        *k /= sum;
+    }
+    kernel
+}
+
+/// Horizontal then vertical pass of `kernel` over a `w * h` buffer, edges are clamped.
+pub fn blur_separable<const C: usize>(
+    src: &[[f32; C]],
+    w: usize,
+    h: usize,
+    kernel: &[f32],
+) -> Vec<[f32; C]> {
+    let radius = (kernel.len() / 2) as i32;
+    let mut tmp = vec![[0.0f32; C]; w * h];
+    let mut dst = vec![[0.0f32; C]; w * h];
+    for y in 0..h {
+        for x in 0..w {
+            let acc = &mut tmp[x + y * w];
+            for (k, weight) in kernel.iter().enumerate() {
+                let sx = (x as i32 + k as i32 - radius).clamp(0, w as i32 - 1) as usize;
+                for i in 0..C {
+                    acc[i] += src[sx + y * w][i] * weight;
+                }
+            }
+        }
+    }
+    for y in 0..h {
+        for x in 0..w {
+            let acc = &mut dst[x + y * w];
+            for (k, weight) in kernel.iter().enumerate() {
+                let sy = (y as i32 + k as i32 - radius).clamp(0, h as i32 - 1) as usize;
+                for i in 0..C {
+                    acc[i] += tmp[x + sy * w][i] * weight;
+                }
+            }
+        }
+    }
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ImageBuffer;
+
+    use super::*;
+
+    #[test]
+    fn test_gaussian_kernel() {
+        let kernel = gaussian_kernel(2.0);
+        assert_eq!(kernel.len(), 13);
+        assert!((kernel.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        assert!(kernel[6] > kernel[5] && kernel[5] == kernel[7]);
+    }
+
+    #[test]
+    fn test_bloom_spreads_highlights() {
+        let mut hdr: HdrImage = ImageBuffer::from_pixel(64, 64, Rgba([0.0, 0.0, 0.0, 1.0]));
+        hdr.put_pixel(48, 48, Rgba([50.0, 50.0, 50.0, 1.0]));
+        hdr.put_pixel(0, 0, Rgba([0.5, 0.5, 0.5, 1.0]));
+        Bloom::default().apply(&mut hdr);
+        assert!(hdr.get_pixel(52, 48)[0] > 0.0);
+        // below the threshold nothing bleeds
+        assert_eq!(hdr.get_pixel(0, 1)[0], 0.0);
+    }
+}
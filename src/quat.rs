@@ -0,0 +1,223 @@
+use std::ops::Mul;
+
+use crate::{geometry::Vec3f, matrix::Mat4f};
+
+/// Rotation quaternion `w + xi + yj + zk`, kept at unit length by the constructors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quat {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Quat {
+    pub const IDENTITY: Quat = Quat {
+        w: 1.0,
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    /// Counter-clockwise rotation by `angle` radians around `axis`, seen from its tip.
+    pub fn from_axis_angle(mut axis: Vec3f, angle: f64) -> Self {
+        axis.normalize();
+        let (s, c) = (angle / 2.0).sin_cos();
+        Self {
+            w: c,
+            x: axis[0] * s,
+            y: axis[1] * s,
+            z: axis[2] * s,
+        }
+    }
+
+    /// Rotation around x, then around y, then around z, all about the fixed world axes.
+    pub fn from_euler(x: f64, y: f64, z: f64) -> Self {
+        let axis = |i: usize| {
+            let mut v = Vec3f::zero();
+            v[i] = 1.0;
+            v
+        };
+        Self::from_axis_angle(axis(2), z)
+            * Self::from_axis_angle(axis(1), y)
+            * Self::from_axis_angle(axis(0), x)
+    }
+
+    pub fn dot(&self, other: &Quat) -> f64 {
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn norm(&self) -> f64 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn normalize(&self) -> Self {
+        let n = self.norm();
+        Self {
+            w: self.w / n,
+            x: self.x / n,
+            y: self.y / n,
+            z: self.z / n,
+        }
+    }
+
+    /// The opposite rotation, for unit quaternions the conjugate.
+    pub fn inverse(&self) -> Self {
+        Self {
+            w: self.w,
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+
+    pub fn rotate(&self, v: Vec3f) -> Vec3f {
+        // v + 2w(u x v) + 2u x (u x v), u the vector part
+        let u = Vec3f::from([self.x, self.y, self.z]);
+        let t = (u ^ v) * 2.0;
+        v + t * self.w + (u ^ t)
+    }
+
+    /// Spherical interpolation along the shortest arc, `t = 0` gives `self`.
+    pub fn slerp(&self, other: &Quat, t: f64) -> Self {
+        let mut cos = self.dot(other);
+        // q and -q are the same rotation, go the short way round
+        let other = if cos < 0.0 {
+            cos = -cos;
+            Quat {
+                w: -other.w,
+                x: -other.x,
+                y: -other.y,
+                z: -other.z,
+            }
+        } else {
+            *other
+        };
+        let (a, b) = if cos > 1.0 - 1e-9 {
+            // nearly parallel, sin(theta) vanishes
+            (1.0 - t, t)
+        } else {
+            let theta = cos.acos();
+            let sin = theta.sin();
+            (((1.0 - t) * theta).sin() / sin, (t * theta).sin() / sin)
+        };
+        Quat {
+            w: self.w * a + other.w * b,
+            x: self.x * a + other.x * b,
+            y: self.y * a + other.y * b,
+            z: self.z * a + other.z * b,
+        }
+        .normalize()
+    }
+
+    /// Homogeneous 4x4 rotation matrix.
+    pub fn to_mat(self) -> Mat4f {
+        let Quat { w, x, y, z } = self;
+        Mat4f::from([
+            [
+                1.0 - 2.0 * (y * y + z * z),
+                2.0 * (x * y - w * z),
+                2.0 * (x * z + w * y),
+                0.0,
+            ],
+            [
+                2.0 * (x * y + w * z),
+                1.0 - 2.0 * (x * x + z * z),
+                2.0 * (y * z - w * x),
+                0.0,
+            ],
+            [
+                2.0 * (x * z - w * y),
+                2.0 * (y * z + w * x),
+                1.0 - 2.0 * (x * x + y * y),
+                0.0,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+}
+
+impl Default for Quat {
+    fn default() -> Self {
+        Quat::IDENTITY
+    }
+}
+
+/// Hamilton product, `a * b` rotates by `b` first.
+impl Mul for Quat {
+    type Output = Quat;
+
+    fn mul(self, rhs: Quat) -> Self::Output {
+        Quat {
+            w: self.w * rhs.w - self.x * rhs.x - self.y * rhs.y - self.z * rhs.z,
+            x: self.w * rhs.x + self.x * rhs.w + self.y * rhs.z - self.z * rhs.y,
+            y: self.w * rhs.y - self.x * rhs.z + self.y * rhs.w + self.z * rhs.x,
+            z: self.w * rhs.z + self.x * rhs.y - self.y * rhs.x + self.z * rhs.w,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::geometry::{embed, Vec4f};
+
+    fn assert_close(a: Vec3f, b: Vec3f) {
+        assert!((a - b).norm() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let q = Quat::from_axis_angle(Vec3f::from([0.0, 0.0, 2.0]), PI / 2.0);
+        assert_close(
+            q.rotate(Vec3f::from([1.0, 0.0, 0.0])),
+            Vec3f::from([0.0, 1.0, 0.0]),
+        );
+        assert_close(
+            q.inverse().rotate(q.rotate(Vec3f::from([1.0, 2.0, 3.0]))),
+            Vec3f::from([1.0, 2.0, 3.0]),
+        );
+    }
+
+    #[test]
+    fn test_euler() {
+        // x first: y goes to z, then the z rotation leaves it
+        let q = Quat::from_euler(PI / 2.0, 0.0, PI / 2.0);
+        assert_close(
+            q.rotate(Vec3f::from([0.0, 1.0, 0.0])),
+            Vec3f::from([0.0, 0.0, 1.0]),
+        );
+        assert_close(
+            q.rotate(Vec3f::from([1.0, 0.0, 0.0])),
+            Vec3f::from([0.0, 1.0, 0.0]),
+        );
+    }
+
+    #[test]
+    fn test_slerp() {
+        let a = Quat::IDENTITY;
+        let b = Quat::from_axis_angle(Vec3f::from([0.0, 1.0, 0.0]), PI / 2.0);
+        let half = a.slerp(&b, 0.5);
+        let expected = Quat::from_axis_angle(Vec3f::from([0.0, 1.0, 0.0]), PI / 4.0);
+        assert!((half.dot(&expected) - 1.0).abs() < 1e-9);
+        assert_eq!(a.slerp(&b, 0.0), a);
+        // -b is the same rotation, the result must not take the long way
+        let neg = Quat {
+            w: -b.w,
+            x: -b.x,
+            y: -b.y,
+            z: -b.z,
+        };
+        assert!((a.slerp(&neg, 0.5).dot(&expected).abs() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_to_mat() {
+        let q = Quat::from_euler(0.3, -1.2, 2.0);
+        let v = Vec3f::from([1.0, -2.0, 0.5]);
+        let m = q.to_mat() * embed::<_, 4, 3>(&v, 1.0);
+        assert_close(Vec3f::from([m[0], m[1], m[2]]), q.rotate(v));
+    }
+}
@@ -0,0 +1,363 @@
+use serde::Serialize;
+
+use crate::{
+    camera, line,
+    params::{self, Param},
+    shaders::{self, debug::DebugView, RenderFn},
+    triangle, zbuf,
+};
+
+/// What produces the image of a `Render`.
+#[derive(Debug, Clone, Copy)]
+pub enum Source {
+    /// A fixed image of the early lessons, encoded as PNG
+    Lesson(fn() -> Vec<u8>),
+    /// A `/shaders/*` render, configured by the query parameters
+    Shader(RenderFn),
+    /// A `/debug/*` view of the pipeline
+    Debug(DebugView),
+}
+
+/// One image served by the app, the router, `/` and `/api/routes` are built from `RENDERS`.
+#[derive(Debug, Clone, Copy)]
+pub struct Render {
+    pub name: &'static str,
+    pub path: &'static str,
+    pub description: &'static str,
+    pub source: Source,
+    /// Every request gets the same image, so it can be cached
+    pub deterministic: bool,
+}
+
+const fn lesson(
+    name: &'static str,
+    path: &'static str,
+    description: &'static str,
+    f: fn() -> Vec<u8>,
+) -> Render {
+    Render {
+        name,
+        path,
+        description,
+        source: Source::Lesson(f),
+        deterministic: true,
+    }
+}
+
+/// `render` drawn differently on every request.
+const fn random(render: Render) -> Render {
+    Render {
+        deterministic: false,
+        ..render
+    }
+}
+
+const fn shader(
+    name: &'static str,
+    path: &'static str,
+    description: &'static str,
+    f: RenderFn,
+) -> Render {
+    Render {
+        name,
+        path,
+        description,
+        source: Source::Shader(f),
+        deterministic: true,
+    }
+}
+
+const fn debug(
+    name: &'static str,
+    path: &'static str,
+    description: &'static str,
+    view: DebugView,
+) -> Render {
+    Render {
+        name,
+        path,
+        description,
+        source: Source::Debug(view),
+        deterministic: true,
+    }
+}
+
+pub static RENDERS: [Render; 26] = [
+    lesson(
+        "sample-line",
+        "/sample-line",
+        "A single Bresenham line",
+        line::sample_line,
+    ),
+    lesson(
+        "wire",
+        "/wire",
+        "Wireframe of the head, lesson 1",
+        line::wireframe,
+    ),
+    lesson(
+        "sample-triangle",
+        "/sample-triangle",
+        "Filled triangles",
+        triangle::sample_triangle,
+    ),
+    random(lesson(
+        "flat-shading",
+        "/flat-shading",
+        "Triangles filled with random colors",
+        triangle::flat_shading,
+    )),
+    lesson(
+        "linear-light",
+        "/linear-light",
+        "Flat lighting with back-face culling",
+        triangle::linear_light,
+    ),
+    lesson(
+        "z-buf",
+        "/z-buf",
+        "Hidden faces removed with a depth buffer",
+        zbuf::z_buf,
+    ),
+    lesson(
+        "move-camera",
+        "/move-camera",
+        "Perspective projection, a moved camera and Gouraud shading",
+        camera::move_camera,
+    ),
+    lesson(
+        "move-camera/zbuf",
+        "/move-camera/zbuf",
+        "Depth buffer of move-camera",
+        camera::move_camera_zbuf,
+    ),
+    shader(
+        "gouraud",
+        "/shaders/gouraud",
+        "Gouraud shading",
+        shaders::gouraud::gouraud_render,
+    ),
+    shader(
+        "gouraud6l",
+        "/shaders/gouraud6l",
+        "Gouraud shading with the intensity rounded to 6 levels",
+        shaders::gouraud6l::gouraud6l_render,
+    ),
+    shader(
+        "texture",
+        "/shaders/texture",
+        "Diffuse texture",
+        shaders::texture::texture_render,
+    ),
+    shader(
+        "normalmapping",
+        "/shaders/normalmapping",
+        "Normal mapping",
+        shaders::normalmapping::normal_mapping_render,
+    ),
+    shader(
+        "specularmapping",
+        "/shaders/specularmapping",
+        "Specular mapping",
+        shaders::specularmapping::specular_mapping_render,
+    ),
+    shader(
+        "shadowmapping",
+        "/shaders/shadowmapping",
+        "Hard shadows from a shadow map",
+        shaders::shadowmapping::shadow_mapping_render,
+    ),
+    shader(
+        "ambientocclusion",
+        "/shaders/ambientocclusion",
+        "Screen-space ambient occlusion term alone",
+        shaders::ambientocclusion::ambient_occlusion_render,
+    ),
+    shader(
+        "deferred",
+        "/shaders/deferred",
+        "Deferred shading of ambient, directional and point lights from a G-buffer",
+        shaders::deferred::deferred_render,
+    ),
+    shader(
+        "crowd",
+        "/shaders/crowd",
+        "One model drawn fifteen times, each instance with its own transform, tint and material",
+        shaders::crowd::crowd_render,
+    ),
+    debug(
+        "depth",
+        "/debug/depth",
+        "Linear distance to the camera, near is white",
+        DebugView::Depth,
+    ),
+    debug(
+        "normals",
+        "/debug/normals",
+        "View space normals",
+        DebugView::Normals,
+    ),
+    debug(
+        "world-normals",
+        "/debug/world-normals",
+        "World space normals",
+        DebugView::WorldNormals,
+    ),
+    debug(
+        "uv",
+        "/debug/uv",
+        "Checkerboard over the texture coordinates",
+        DebugView::UvChecker,
+    ),
+    debug(
+        "tangents",
+        "/debug/tangents",
+        "Per-face tangents from the uv derivatives",
+        DebugView::Tangents,
+    ),
+    debug(
+        "overdraw",
+        "/debug/overdraw",
+        "How many fragments passed the depth test at each pixel",
+        DebugView::Overdraw,
+    ),
+    debug(
+        "triangles",
+        "/debug/triangles",
+        "One color per triangle",
+        DebugView::TriangleId,
+    ),
+    debug(
+        "meshes",
+        "/debug/meshes",
+        "One color per mesh",
+        DebugView::MeshId,
+    ),
+    debug(
+        "wireframe",
+        "/debug/wireframe",
+        "Diffuse texture with the visible edges drawn on top",
+        DebugView::Wireframe,
+    ),
+];
+
+/// One entry of `/api/routes`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteInfo {
+    pub name: &'static str,
+    pub path: &'static str,
+    pub kind: &'static str,
+    pub description: &'static str,
+    pub params: Vec<Param>,
+}
+
+impl Render {
+    pub fn kind(&self) -> &'static str {
+        match self.source {
+            Source::Lesson(_) => "lesson",
+            Source::Shader(_) => "shader",
+            Source::Debug(_) => "debug",
+        }
+    }
+
+    /// The query parameters read by the route, lessons take none.
+    pub fn params(&self) -> Vec<Param> {
+        match self.source {
+            Source::Lesson(_) => Vec::new(),
+            Source::Shader(_) => [
+                params::render_params(),
+                params::camera_params(),
+                params::post_process_params(),
+                params::output_params(),
+            ]
+            .concat(),
+            Source::Debug(_) => [
+                params::render_params(),
+                params::camera_params(),
+                params::culling_params(),
+                params::output_params(),
+            ]
+            .concat(),
+        }
+    }
+
+    pub fn info(&self) -> RouteInfo {
+        RouteInfo {
+            name: self.name,
+            path: self.path,
+            kind: self.kind(),
+            description: self.description,
+            params: self.params(),
+        }
+    }
+
+    /// Small image for the gallery, the lessons have a fixed size and are scaled by the page.
+    pub fn thumbnail(&self) -> String {
+        match self.source {
+            Source::Lesson(_) => self.path.to_string(),
+            _ => format!("{}?width=240&height=240&format=jpeg", self.path),
+        }
+    }
+}
+
+/// The `/` page, a card with the thumbnail and description of every render.
+pub fn gallery() -> String {
+    let mut sections = String::new();
+    for (kind, title) in [
+        ("lesson", "Lessons"),
+        ("shader", "Shaders"),
+        ("debug", "Debug views"),
+    ] {
+        sections += &format!("<h2>{}</h2>\n<div class=\"grid\">\n", title);
+        for render in RENDERS.iter().filter(|r| r.kind() == kind) {
+            sections += &format!(
+                "<a class=\"card\" href=\"{path}\"><img loading=\"lazy\" src=\"{thumbnail}\" \
+                 alt=\"{name}\"><b>{path}</b><span>{description}</span></a>\n",
+                path = render.path,
+                thumbnail = render.thumbnail(),
+                name = render.name,
+                description = render.description,
+            );
+        }
+        sections += "</div>\n";
+    }
+    include_str!("../public/gallery.html").replace("{{renders}}", &sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        for (i, render) in RENDERS.iter().enumerate() {
+            assert!(
+                RENDERS[..i].iter().all(|r| r.path != render.path),
+                "{} is registered twice",
+                render.path
+            );
+            assert!(render.path.ends_with(render.name));
+        }
+        let random: Vec<&str> = RENDERS
+            .iter()
+            .filter(|r| !r.deterministic)
+            .map(|r| r.name)
+            .collect();
+        assert_eq!(random, ["flat-shading"]);
+        assert_eq!(shaders::names().len(), 9);
+        assert!(shaders::by_name("texture").is_some());
+        for view in DebugView::ALL {
+            assert!(RENDERS
+                .iter()
+                .any(|r| r.path == format!("/debug/{}", view.name())));
+        }
+
+        let info = RENDERS[10].info();
+        assert_eq!((info.name, info.kind), ("texture", "shader"));
+        let width = info.params.iter().find(|p| p.name == "width").unwrap();
+        assert_eq!(width.default.as_deref(), Some("800"));
+        assert!(info.params.iter().any(|p| p.name == "bloom"));
+        assert!(RENDERS[0].params().is_empty());
+        assert!(gallery().contains("<b>/debug/uv</b>"));
+    }
+}
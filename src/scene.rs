@@ -0,0 +1,700 @@
+use std::{
+    fmt,
+    path::{Component, Path},
+};
+
+use image::Rgba;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets,
+    camera::{CameraOptions, Projection, View},
+    deferred::Light,
+    geometry::Vec3f,
+    hdr::{Operator, ToneMapping},
+    instance::{Instance, Material},
+    output::Format,
+    params::max_size,
+    post::{Bloom, PostProcess, SIGMA},
+    quat::Quat,
+    shaders::ambientocclusion::{self as ao, Ssao},
+    transform::Transform,
+    util::splitext,
+};
+
+/// Most meshes and lights accepted in one scene.
+pub const MAX_MESHES: usize = 64;
+pub const MAX_LIGHTS: usize = 16;
+
+/// Everything `POST /render` needs to produce one image. Only `meshes` is required, the
+/// other sections fall back to the defaults of the `/shaders/*` routes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scene {
+    pub meshes: Vec<MeshDesc>,
+    /// Empty keeps the default ambient and key light
+    #[serde(default)]
+    pub lights: Vec<LightDesc>,
+    #[serde(default)]
+    pub camera: CameraDesc,
+    #[serde(default)]
+    pub pipeline: Pipeline,
+    #[serde(default)]
+    pub settings: Settings,
+    /// Falls back to the `Accept` header of the request, then PNG
+    #[serde(default)]
+    pub format: Option<Format>,
+    /// JPEG quality in 1..=100
+    #[serde(default)]
+    pub quality: Option<u8>,
+}
+
+/// One `.obj` file placed in the world, the textures are looked up next to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeshDesc {
+    pub path: String,
+    #[serde(default)]
+    pub transform: TransformDesc,
+    /// Multiplies the albedo
+    #[serde(default = "white")]
+    pub tint: [f32; 3],
+    /// Replaces the textures when given
+    #[serde(default)]
+    pub material: Option<MaterialDesc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransformDesc {
+    #[serde(default)]
+    pub translation: [f64; 3],
+    /// Euler angles in degrees, applied around x, then y, then z
+    #[serde(default)]
+    pub rotation: [f64; 3],
+    #[serde(default = "ones")]
+    pub scale: [f64; 3],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialDesc {
+    /// Linear color
+    pub albedo: [f32; 3],
+    /// Specular exponent
+    #[serde(default = "default_specular")]
+    pub specular: f64,
+    #[serde(default)]
+    pub emission: [f32; 3],
+}
+
+/// Positions and directions are in world space, colors are linear and may exceed 1.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum LightDesc {
+    Ambient {
+        color: [f32; 3],
+    },
+    Directional {
+        /// Towards the light
+        direction: [f64; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+    },
+    Point {
+        position: [f64; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+        range: f64,
+    },
+}
+
+/// Without `eye` the camera looks at the scene from the front right and frames it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDesc {
+    pub eye: Option<[f64; 3]>,
+    /// Defaults to the center of the scene bounds
+    pub center: Option<[f64; 3]>,
+    pub up: Option<[f64; 3]>,
+    /// Same names as `?view=`
+    pub view: Option<String>,
+    /// Same names as `?projection=`
+    pub projection: Option<String>,
+    /// Orbit angles in degrees
+    pub azimuth: Option<f64>,
+    pub elevation: Option<f64>,
+    pub roll: Option<f64>,
+    /// Defaults to framing the scene unless `eye` is given
+    pub fit: Option<bool>,
+    /// Magnification after framing, 2 is twice as close
+    pub zoom: Option<f64>,
+}
+
+/// Forward shades every fragment as it is drawn, deferred shades the visible pixels once
+/// after a geometry pass. Both produce the same image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pipeline {
+    Forward,
+    #[default]
+    Deferred,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    #[serde(default = "default_size")]
+    pub width: u32,
+    #[serde(default = "default_size")]
+    pub height: u32,
+    /// Same names as `?tonemap=`
+    #[serde(default)]
+    pub tonemap: Option<String>,
+    #[serde(default = "one")]
+    pub exposure: f32,
+    #[serde(default)]
+    pub bloom: Option<BloomDesc>,
+    #[serde(default)]
+    pub ssao: Option<SsaoDesc>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BloomDesc {
+    pub threshold: Option<f32>,
+    pub intensity: Option<f32>,
+    pub sigma: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SsaoDesc {
+    pub radius: Option<f64>,
+    pub samples: Option<usize>,
+    pub blur: Option<usize>,
+    pub strength: Option<f32>,
+}
+
+fn white() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn ones() -> [f64; 3] {
+    [1.0; 3]
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+fn default_specular() -> f64 {
+    10.0
+}
+
+fn default_size() -> u32 {
+    800
+}
+
+impl Default for TransformDesc {
+    fn default() -> Self {
+        Self {
+            translation: [0.0; 3],
+            rotation: [0.0; 3],
+            scale: ones(),
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            width: default_size(),
+            height: default_size(),
+            tonemap: None,
+            exposure: one(),
+            bloom: None,
+            ssao: None,
+        }
+    }
+}
+
+/// Why a scene was rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneError {
+    /// Not JSON, or not the shape of a `Scene`
+    Parse(String),
+    /// Well formed but with values that cannot be rendered, one message per problem
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Parse(message) => write!(f, "invalid scene: {}", message),
+            SceneError::Invalid(errors) => {
+                write!(f, "invalid scene:")?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl Scene {
+    /// Parses and validates `json`, errors name the offending field, e.g.
+    /// `meshes[0].transform.scale: invalid length 2, expected an array of length 3`.
+    pub fn from_json(json: &str) -> Result<Scene, SceneError> {
+        let de = &mut serde_json::Deserializer::from_str(json);
+        let scene: Scene = serde_path_to_error::deserialize(de).map_err(|err| {
+            let path = err.path().to_string();
+            let inner = err.into_inner();
+            SceneError::Parse(if path == "." {
+                inner.to_string()
+            } else {
+                format!("{}: {}", path, inner)
+            })
+        })?;
+        scene.validate()?;
+        Ok(scene)
+    }
+
+    /// Checks the values serde cannot: ranges, names and that the mesh files exist.
+    pub fn validate(&self) -> Result<(), SceneError> {
+        let mut errors = Vec::new();
+        if self.meshes.is_empty() {
+            errors.push("meshes: at least one mesh is required".to_string());
+        }
+        if self.meshes.len() > MAX_MESHES {
+            errors.push(format!("meshes: at most {} meshes are allowed", MAX_MESHES));
+        }
+        for (i, mesh) in self.meshes.iter().enumerate() {
+            if let Err(err) = check_path(&mesh.path) {
+                errors.push(format!("meshes[{}].path: {}", i, err));
+            }
+            let t = &mesh.transform;
+            if !all_finite(&t.translation) || !all_finite(&t.rotation) || !all_finite(&t.scale) {
+                errors.push(format!("meshes[{}].transform: values must be finite", i));
+            }
+            if t.scale.contains(&0.0) {
+                errors.push(format!("meshes[{}].transform.scale: must not be zero", i));
+            }
+            if !is_color(&mesh.tint) {
+                errors.push(format!("meshes[{}].tint: {}", i, COLOR_ERROR));
+            }
+            if let Some(material) = &mesh.material {
+                if !is_color(&material.albedo) || !is_color(&material.emission) {
+                    errors.push(format!("meshes[{}].material: {}", i, COLOR_ERROR));
+                }
+                if !(material.specular.is_finite() && material.specular >= 0.0) {
+                    errors.push(format!("meshes[{}].material.specular: must be >= 0", i));
+                }
+            }
+        }
+
+        if self.lights.len() > MAX_LIGHTS {
+            errors.push(format!("lights: at most {} lights are allowed", MAX_LIGHTS));
+        }
+        for (i, light) in self.lights.iter().enumerate() {
+            let (color, ok) = match light {
+                LightDesc::Ambient { color } => (color, true),
+                LightDesc::Directional { direction, color } => (
+                    color,
+                    all_finite(direction) && Vec3f::from(*direction).norm() > 0.0,
+                ),
+                LightDesc::Point {
+                    position,
+                    color,
+                    range,
+                } => (
+                    color,
+                    all_finite(position) && range.is_finite() && *range > 0.0,
+                ),
+            };
+            if !is_color(color) {
+                errors.push(format!("lights[{}].color: {}", i, COLOR_ERROR));
+            }
+            if !ok {
+                errors.push(format!(
+                    "lights[{}]: direction must be finite and non zero, range positive",
+                    i
+                ));
+            }
+        }
+
+        let camera = &self.camera;
+        if let Some(view) = &camera.view {
+            if View::from_name(view).is_none() {
+                let names: Vec<&str> = View::ALL.iter().map(|v| v.name()).collect();
+                errors.push(format!(
+                    "camera.view: unknown view `{}`, expected one of: {}",
+                    view,
+                    names.join(", ")
+                ));
+            }
+        }
+        if let Some(projection) = &camera.projection {
+            if Projection::from_name(projection).is_none() {
+                errors.push(format!(
+                    "camera.projection: unknown projection `{}`, expected one of: {}",
+                    projection,
+                    Projection::NAMES.join(", ")
+                ));
+            }
+        }
+        let points = [camera.eye, camera.center, camera.up];
+        if points.iter().flatten().any(|p| !all_finite(p)) {
+            errors.push("camera: eye, center and up must be finite".to_string());
+        } else if camera.eye.is_some() && camera.eye == camera.center {
+            errors.push("camera: eye and center must differ".to_string());
+        }
+        if camera.zoom.is_some_and(|z| !(z.is_finite() && z > 0.0)) {
+            errors.push("camera.zoom: must be positive".to_string());
+        }
+
+        let settings = &self.settings;
+        for (name, size) in [("width", settings.width), ("height", settings.height)] {
+            if !(1..=max_size()).contains(&size) {
+                errors.push(format!("settings.{}: must be in 1..={}", name, max_size()));
+            }
+        }
+        if let Some(tonemap) = &settings.tonemap {
+            if Operator::from_name(tonemap).is_none() {
+                errors.push(format!(
+                    "settings.tonemap: unknown operator `{}`, expected one of: {}",
+                    tonemap,
+                    Operator::NAMES.join(", ")
+                ));
+            }
+        }
+        if !(settings.exposure.is_finite() && settings.exposure > 0.0) {
+            errors.push("settings.exposure: must be positive".to_string());
+        }
+        // negative or not finite
+        let negative = |v: Option<f32>| v.is_some_and(|v| !(v.is_finite() && v >= 0.0));
+        if let Some(bloom) = settings.bloom {
+            if negative(bloom.threshold) {
+                errors.push("settings.bloom.threshold: must be >= 0".to_string());
+            }
+            if negative(bloom.intensity) {
+                errors.push("settings.bloom.intensity: must be >= 0".to_string());
+            }
+        }
+        if settings
+            .bloom
+            .and_then(|b| b.sigma)
+            .is_some_and(|s| !SIGMA.contains(&s))
+        {
+            errors.push(format!(
+                "settings.bloom.sigma: must be in {}..={}",
+                SIGMA.start(),
+                SIGMA.end()
+            ));
+        }
+        if let Some(ssao) = settings.ssao {
+            if ssao.samples.is_some_and(|n| !ao::SAMPLES.contains(&n)) {
+                errors.push(format!(
+                    "settings.ssao.samples: must be in {}..={}",
+                    ao::SAMPLES.start(),
+                    ao::SAMPLES.end()
+                ));
+            }
+            if ssao.radius.is_some_and(|r| !ao::RADIUS.contains(&r)) {
+                errors.push(format!(
+                    "settings.ssao.radius: must be in {}..={}",
+                    ao::RADIUS.start(),
+                    ao::RADIUS.end()
+                ));
+            }
+            if negative(ssao.strength) {
+                errors.push("settings.ssao.strength: must be >= 0".to_string());
+            }
+            if ssao.blur.is_some_and(|b| !ao::BLUR.contains(&b)) {
+                errors.push(format!(
+                    "settings.ssao.blur: must be in {}..={}",
+                    ao::BLUR.start(),
+                    ao::BLUR.end()
+                ));
+            }
+        }
+        if self.quality.is_some_and(|q| !(1..=100).contains(&q)) {
+            errors.push("quality: must be in 1..=100".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(SceneError::Invalid(errors))
+        }
+    }
+
+    /// One instance per mesh, in the order of `meshes`.
+    pub fn instances(&self) -> Vec<Instance> {
+        self.meshes
+            .iter()
+            .map(|mesh| {
+                let t = &mesh.transform;
+                let [x, y, z] = t.rotation.map(f64::to_radians);
+                Instance {
+                    transform: Transform {
+                        translation: Vec3f::from(t.translation),
+                        rotation: Quat::from_euler(x, y, z),
+                        scale: Vec3f::from(t.scale),
+                    },
+                    tint: mesh.tint,
+                    material: mesh.material.map(|m| Material {
+                        albedo: rgba(m.albedo),
+                        specular: m.specular,
+                        emission: rgba(m.emission),
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    /// The lights of the scene, or a dim ambient term and a white key light along
+    /// `light_dir` when it has none.
+    pub fn lights(&self, light_dir: Vec3f) -> Vec<Light> {
+        if self.lights.is_empty() {
+            return vec![
+                Light::Ambient {
+                    color: [0.03, 0.03, 0.03],
+                },
+                Light::Directional {
+                    dir: light_dir,
+                    color: [1.0, 1.0, 1.0],
+                },
+            ];
+        }
+        self.lights
+            .iter()
+            .map(|light| match *light {
+                LightDesc::Ambient { color } => Light::Ambient { color },
+                LightDesc::Directional { direction, color } => Light::Directional {
+                    dir: Vec3f::from(direction).normalize(),
+                    color,
+                },
+                LightDesc::Point {
+                    position,
+                    color,
+                    range,
+                } => Light::Point {
+                    pos: Vec3f::from(position),
+                    color,
+                    range,
+                },
+            })
+            .collect()
+    }
+
+    /// Direction towards the first directional light, the default key light otherwise.
+    pub fn light_dir(&self) -> Vec3f {
+        self.lights
+            .iter()
+            .find_map(|light| match light {
+                LightDesc::Directional { direction, .. } => {
+                    Some(Vec3f::from(*direction).normalize())
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| Vec3f::from([1.0, 1.0, 1.0]).normalize())
+    }
+
+    pub fn camera_options(&self) -> CameraOptions {
+        let camera = &self.camera;
+        CameraOptions {
+            view: camera.view.as_deref().and_then(View::from_name),
+            projection: camera.projection.as_deref().and_then(Projection::from_name),
+            azimuth: camera.azimuth.map(f64::to_radians),
+            elevation: camera.elevation.map(f64::to_radians),
+            roll: camera.roll.map(f64::to_radians),
+            fit: camera.fit.unwrap_or(camera.eye.is_none()),
+            zoom: camera.zoom,
+        }
+    }
+
+    pub fn post_process(&self) -> PostProcess {
+        let settings = &self.settings;
+        PostProcess {
+            ssao: settings.ssao.map(|desc| {
+                let default = Ssao::default();
+                Ssao {
+                    radius: desc.radius.unwrap_or(default.radius),
+                    samples: desc.samples.unwrap_or(default.samples),
+                    blur_radius: desc.blur.unwrap_or(default.blur_radius),
+                    strength: desc.strength.unwrap_or(default.strength),
+                    ..default
+                }
+            }),
+            bloom: settings.bloom.map(|desc| {
+                let default = Bloom::default();
+                Bloom {
+                    threshold: desc.threshold.unwrap_or(default.threshold),
+                    intensity: desc.intensity.unwrap_or(default.intensity),
+                    sigma: desc.sigma.unwrap_or(default.sigma),
+                }
+            }),
+            tone: ToneMapping {
+                operator: settings
+                    .tonemap
+                    .as_deref()
+                    .and_then(Operator::from_name)
+                    .unwrap_or(ToneMapping::default().operator),
+                exposure: settings.exposure,
+            },
+        }
+    }
+}
+
+const COLOR_ERROR: &str = "color components must be finite and >= 0";
+
+fn is_color(c: &[f32; 3]) -> bool {
+    c.iter().all(|v| v.is_finite() && *v >= 0.0)
+}
+
+fn all_finite(v: &[f64; 3]) -> bool {
+    v.iter().all(|c| c.is_finite())
+}
+
+fn rgba(c: [f32; 3]) -> Rgba<f32> {
+    Rgba([c[0], c[1], c[2], 1.0])
+}
+
+/// Mesh paths are read on the server, so only relative paths below the working directory
+/// are accepted, with the textures `Model::load` requires next to them.
+fn check_path(path: &str) -> Result<(), String> {
+    let p = Path::new(path);
+    if path.is_empty() {
+        return Err("must not be empty".to_string());
+    }
+    if !p
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format!(
+            "`{}` must be relative and must not contain `..`",
+            path
+        ));
+    }
+    if p.extension().and_then(|e| e.to_str()) != Some("obj") {
+        return Err(format!("`{}` is not an .obj file", path));
+    }
+    if !p.is_file() {
+        return Err(format!("`{}` not found", path));
+    }
+    let (name, _) = splitext(path);
+    for (_, suffix, required) in assets::TEXTURES {
+        let texture = name.clone() + suffix;
+        if required && !Path::new(&texture).is_file() {
+            return Err(format!("texture `{}` of `{}` not found", texture, path));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_json() {
+        let scene = Scene::from_json(
+            r#"{
+                "meshes": [{
+                    "path": "obj/african_head/african_head.obj",
+                    "transform": {"translation": [1, 0, 0], "rotation": [0, 90, 0]},
+                    "material": {"albedo": [1, 0.5, 0.2]}
+                }],
+                "lights": [{"type": "point", "position": [0, 2, 0], "range": 4}],
+                "camera": {"view": "isometric"},
+                "settings": {"width": 64, "height": 32, "tonemap": "aces"},
+                "format": "jpg"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(scene.meshes[0].transform.scale, [1.0; 3]);
+        assert_eq!(scene.meshes[0].material.unwrap().specular, 10.0);
+        assert_eq!(scene.pipeline, Pipeline::Deferred);
+        assert_eq!(scene.format, Some(Format::Jpeg));
+        assert!(scene.camera_options().fit);
+        assert_eq!(scene.post_process().tone.operator, Operator::Aces);
+        let instance = scene.instances()[0];
+        let p = instance
+            .transform
+            .transform_point(Vec3f::from([1.0, 0.0, 0.0]));
+        assert!((p - Vec3f::from([1.0, 0.0, -1.0])).norm() < 1e-9);
+        // the default key light is only added to scenes without lights
+        assert_eq!(scene.lights(scene.light_dir()).len(), 1);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |json: &str| match Scene::from_json(json) {
+            Err(SceneError::Parse(message)) => message,
+            other => panic!("{:?}", other),
+        };
+        assert!(err(r#"{"lights": []}"#).contains("missing field `meshes`"));
+        assert!(
+            err(r#"{"meshes": [{"path": "a.obj", "transform": {"scale": [1, 2]}}]}"#)
+                .starts_with("meshes[0].transform.scale: invalid length 2")
+        );
+        assert!(err(r#"{"meshes": [], "lights": [{"type": "spot"}]}"#)
+            .starts_with("lights[0].type: unknown variant `spot`, expected one of"));
+        assert!(err(r#"{"meshes": [], "camera": {"fov": 1}}"#)
+            .starts_with("camera.fov: unknown field `fov`"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let scene = Scene::from_json(
+            r#"{
+                "meshes": [
+                    {"path": "../secret.obj"},
+                    {"path": "obj/missing.obj", "tint": [-1, 0, 0]},
+                    {"path": "obj/floor.obj"},
+                    {"path": "obj/boggie/body.obj"}
+                ],
+                "lights": [{"type": "directional", "direction": [0, 0, 0]}],
+                "camera": {"view": "side"},
+                "settings": {
+                    "width": 0,
+                    "exposure": -1,
+                    "bloom": {"threshold": -1, "intensity": -0.5, "sigma": 1e10},
+                    "ssao": {"samples": 0, "blur": 100, "strength": -1}
+                },
+                "quality": 0
+            }"#,
+        );
+        let Err(SceneError::Invalid(errors)) = scene else {
+            panic!("{:?}", scene);
+        };
+        assert_eq!(
+            errors,
+            vec![
+                "meshes[0].path: `../secret.obj` must be relative and must not contain `..`",
+                "meshes[1].path: `obj/missing.obj` not found",
+                "meshes[1].tint: color components must be finite and >= 0",
+                "meshes[2].path: texture `obj/floor_nm.tga` of `obj/floor.obj` not found",
+                "meshes[3].path: texture `obj/boggie/body_diffuse.tga` of `obj/boggie/body.obj` \
+                 not found",
+                "lights[0]: direction must be finite and non zero, range positive",
+                "camera.view: unknown view `side`, expected one of: \
+                 front, back, left, right, top, bottom, isometric",
+                "settings.width: must be in 1..=4096",
+                "settings.exposure: must be positive",
+                "settings.bloom.threshold: must be >= 0",
+                "settings.bloom.intensity: must be >= 0",
+                "settings.bloom.sigma: must be in 0.1..=64",
+                "settings.ssao.samples: must be in 1..=256",
+                "settings.ssao.strength: must be >= 0",
+                "settings.ssao.blur: must be in 0..=16",
+                "quality: must be in 1..=100",
+            ]
+        );
+        assert!(Scene::from_json(r#"{"meshes": [{"path": "/etc/passwd.obj"}]}"#).is_err());
+    }
+}
@@ -1,157 +1,276 @@
-use std::{f64::consts::PI, io::Cursor, rc::Rc};
+use std::ops::RangeInclusive;
 
-use image::{ImageBuffer, Rgba, RgbaImage};
+use image::{ImageBuffer, Rgba};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
 use crate::{
-    geometry::{embed, Vec2f, Vec3f, Vec4f},
-    gl::{IShader, GL},
-    matrix::Mat,
+    assets,
+    camera::CameraOptions,
+    deferred::GBuffer,
+    geometry::{cross, embed, proj, Vec3f},
+    gl::GL,
+    hdr::HdrImage,
+    matrix::Mat4f,
     model::Model,
-    util::{maxf, minf, RgbaImageExt},
+    post::PostProcess,
+    shaders::deferred::geometry_pass,
+    shaders::{Frame, RenderOptions},
+    util::{maxf, minf},
 };
 
-pub struct ZShader<'a> {
-    gl: Rc<&'a GL>,
-    varying_tri: Mat<f64>, // <4, 3>
-    model: Rc<&'a Model>,
+/// Accepted `Ssao::samples`, `radius` and `blur_radius`, the cost grows with each.
+pub const SAMPLES: RangeInclusive<usize> = 1..=256;
+pub const RADIUS: RangeInclusive<f64> = 0.01..=10.0;
+pub const BLUR: RangeInclusive<usize> = 0..=16;
+
+/// Screen space ambient occlusion with a randomized normal-oriented hemisphere kernel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ssao {
+    /// Hemisphere radius in view space units
+    pub radius: f64,
+    pub samples: usize,
+    /// Depth offset that avoids self occlusion on flat surfaces
+    pub bias: f64,
+    /// Bilateral blur radius in pixels, 0 disables the blur
+    pub blur_radius: usize,
+    /// 0 leaves the image untouched, 1 applies the full occlusion term
+    pub strength: f32,
 }
 
-impl<'a> ZShader<'a> {
-    pub fn new(gl: Rc<&'a GL>, model: Rc<&'a Model>) -> Self {
+impl Default for Ssao {
+    fn default() -> Self {
         Self {
-            gl,
-            varying_tri: Mat::new(4, 3),
-            model,
+            radius: 0.25,
+            samples: 16,
+            bias: 0.005,
+            blur_radius: 2,
+            strength: 1.0,
         }
     }
 }
 
-impl<'a> IShader for ZShader<'a> {
-    fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f {
-        let gl_vertex = embed::<_, 4, 3>(self.model.vert_by(iface, nthvert), 1.0);
-        let r = Vec4f::from_vec(
-            &(&(&self.gl.viewport * &self.gl.projection) * &self.gl.model_view) * &gl_vertex,
-        );
-        self.varying_tri.set_col(nthvert, &(r / r[3]).to_vec());
-        r
+const NOISE_SIZE: usize = 4;
+
+/// `m * (v, 1)` followed by the perspective divide.
+fn transform_point(m: &Mat4f, v: &Vec3f) -> Vec3f {
+    let p = *m * embed::<_, 4, 3>(v, 1.0);
+    proj::<_, 3, 4>(&(p / p[3]))
+}
+
+impl Ssao {
+    /// Sample offsets in the unit hemisphere around +z, denser close to the origin.
+    pub fn kernel(&self, rng: &mut StdRng) -> Vec<Vec3f> {
+        (0..self.samples)
+            .map(|i| {
+                let mut s = Vec3f::from([
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(0.0..1.0),
+                ])
+                .normalize();
+                let t = i as f64 / self.samples as f64;
+                s = s * (rng.gen_range(0.0..1.0) * (0.1 + 0.9 * t * t));
+                s
+            })
+            .collect()
     }
 
-    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<u8> {
-        Rgba([0, 0, 0, 255])
+    /// Unblurred occlusion term per pixel, 1.0 means fully open.
+    pub fn occlusion(&self, gl: &GL, gbuffer: &GBuffer) -> Vec<f32> {
+        let (w, h) = (gbuffer.width as usize, gbuffer.height as usize);
+        let m = gl.viewport * gl.projection;
+        // fixed seed so renders are reproducible
+        let mut rng = StdRng::seed_from_u64(0x55a0);
+        let kernel = self.kernel(&mut rng);
+        let noise: Vec<Vec3f> = (0..NOISE_SIZE * NOISE_SIZE)
+            .map(|_| Vec3f::from([rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.0]))
+            .collect();
+
+        let mut ao = vec![1.0; w * h];
+        for y in 0..h {
+            for x in 0..w {
+                let idx = x + y * w;
+                if !gbuffer.covered(idx) {
+                    continue;
+                }
+                let p = gbuffer.position[idx];
+                let n = gbuffer.normal[idx];
+                // Gram-Schmidt a random rotation around the normal
+                let rvec = noise[x % NOISE_SIZE + (y % NOISE_SIZE) * NOISE_SIZE];
+                let mut t = rvec - n * rvec.dot(&n);
+                if t.norm() < 1e-6 {
+                    t = cross(&n, &Vec3f::from([1.0, 0.0, 0.0]));
+                }
+                let t = t.normalize();
+                let b = cross(&n, &t);
+
+                let mut occluded = 0.0;
+                for k in kernel.iter() {
+                    let s = p + (t * k[0] + b * k[1] + n * k[2]) * self.radius;
+                    let screen = transform_point(&m, &s);
+                    if screen[0] < 0.0 || screen[1] < 0.0 {
+                        continue;
+                    }
+                    let (sx, sy) = (screen[0] as usize, screen[1] as usize);
+                    if sx >= w || sy >= h || !gbuffer.covered(sx + sy * w) {
+                        continue;
+                    }
+                    // view space looks down -z, larger z is closer to the camera
+                    let scene_z = gbuffer.position[sx + sy * w][2];
+                    if scene_z >= s[2] + self.bias {
+                        occluded += minf(1.0, self.radius / (p[2] - scene_z).abs());
+                    }
+                }
+                ao[idx] = 1.0 - (occluded / kernel.len() as f64) as f32;
+            }
+        }
+        ao
     }
-}
 
-pub fn max_elevation_angle(
-    zbuf: &mut Vec<f64>,
-    width: u32,
-    height: u32,
-    p: Vec2f,
-    dir: Vec2f,
-) -> f64 {
-    let mut max_angle = 0.0;
-    let mut t = 0.0;
-    while t < 1000.0 {
-        t += 1.0;
-        let cur = p + dir * t;
-        if cur[0] >= width as f64 || cur[1] >= height as f64 || cur[0] < 0.0 || cur[1] < 0.0 {
-            return max_angle;
+    /// Depth and normal aware blur, keeps occlusion from leaking across silhouettes.
+    pub fn blur(&self, ao: &[f32], gbuffer: &GBuffer) -> Vec<f32> {
+        let (w, h) = (gbuffer.width as i32, gbuffer.height as i32);
+        let r = self.blur_radius as i32;
+        let sigma = maxf(r as f64, 1.0) / 2.0;
+        let mut out = ao.to_vec();
+        for y in 0..h {
+            for x in 0..w {
+                let idx = (x + y * w) as usize;
+                if !gbuffer.covered(idx) {
+                    continue;
+                }
+                let (p, n) = (gbuffer.position[idx], gbuffer.normal[idx]);
+                let mut sum = 0.0;
+                let mut weights = 0.0;
+                for dy in -r..=r {
+                    for dx in -r..=r {
+                        let (sx, sy) = (x + dx, y + dy);
+                        if sx < 0 || sy < 0 || sx >= w || sy >= h {
+                            continue;
+                        }
+                        let sidx = (sx + sy * w) as usize;
+                        if !gbuffer.covered(sidx) {
+                            continue;
+                        }
+                        let dz = (gbuffer.position[sidx][2] - p[2]) / self.radius;
+                        let spatial = (-((dx * dx + dy * dy) as f64) / (2.0 * sigma * sigma)).exp();
+                        let range = (-dz * dz * 8.0).exp();
+                        let normal = maxf(0.0, gbuffer.normal[sidx].dot(&n)).powi(8);
+                        let weight = spatial * range * normal;
+                        sum += ao[sidx] as f64 * weight;
+                        weights += weight;
+                    }
+                }
+                if weights > 0.0 {
+                    out[idx] = (sum / weights) as f32;
+                }
+            }
         }
+        out
+    }
 
-        let distance = (p - cur).norm();
-        if distance < 1.0 {
-            continue;
+    /// Occlusion term, blurred when `blur_radius` is set.
+    pub fn ambient_occlusion(&self, gl: &GL, gbuffer: &GBuffer) -> Vec<f32> {
+        let ao = self.occlusion(gl, gbuffer);
+        if self.blur_radius > 0 {
+            self.blur(&ao, gbuffer)
+        } else {
+            ao
         }
-        let elevation = zbuf[cur[0] as usize + cur[1] as usize * width as usize]
-            - zbuf[p[0] as usize + p[1] as usize * width as usize];
-        max_angle = maxf(max_angle, f64::atan(elevation / distance));
     }
-    max_angle
+
+    /// Darken an already lit image by the occlusion term.
+    pub fn composite(&self, hdr: &mut HdrImage, ao: &[f32]) {
+        for (idx, pixel) in hdr.pixels_mut().enumerate() {
+            let k = 1.0 - self.strength * (1.0 - ao[idx]);
+            for i in 0..3 {
+                pixel[i] *= k;
+            }
+        }
+    }
+
+    /// Runs its own geometry pass, for forward shaders that have no G-buffer.
+    pub fn apply(&self, gl: &GL, model: &Model, hdr: &mut HdrImage) {
+        let ao = self.ambient_occlusion(gl, &geometry_pass(gl, model));
+        self.composite(hdr, &ao);
+    }
 }
 
-pub fn ambient_occlusion_render() -> Vec<u8> {
-    const W: u32 = 800;
-    const H: u32 = 800;
-    // let model = Model::new("obj/diablo3_pose/diablo3_pose.obj");
-    let model = Model::new("obj/african_head/african_head.obj");
+/// Shows the occlusion term alone, composite it into other shaders with `?ssao=1`.
+pub fn ambient_occlusion_render(
+    post: PostProcess,
+    camera: CameraOptions,
+    options: &RenderOptions,
+) -> Frame {
+    let (width, height) = (options.width, options.height);
+    // let model = assets::model(options.model_or("obj/diablo3_pose/diablo3_pose.obj"));
+    let model = assets::model(options.model_or("obj/african_head/african_head.obj"));
 
-    let light_dir = Vec3f::from([1.0, 1.0, 1.0]).normalize();
+    let light_dir = options.light_dir;
 
     let eye = Vec3f::from([1.2, -0.8, 3.0]);
     let center = Vec3f::from([0.0, 0.0, 0.0]);
     let up = Vec3f::from([0.0, 1.0, 0.0]);
 
-    let mut gl = GL::new(light_dir, W, H);
-    gl.lookat(eye, center, up);
+    let mut gl = GL::new(light_dir, width, height);
     gl.viewport(
-        W as i32 / 8,
-        H as i32 / 8,
-        W as i32 * 3 / 4,
-        H as i32 * 3 / 4,
+        width as i32 / 8,
+        height as i32 / 8,
+        width as i32 * 3 / 4,
+        height as i32 * 3 / 4,
     );
-    gl.projection(-1.0 / (eye - center).norm());
-
-    let rcgl = Rc::new(&gl);
+    camera
+        .resolve(&model.bbox(), eye, center, up, gl.aspect())
+        .apply(&mut gl);
 
-    println!("ModelView:\n {:}", &gl.model_view);
-    println!("Viewport:\n {:}", &gl.viewport);
-    println!("Projection:\n {:}", &gl.projection);
-    println!(
-        "Z:\n {:}",
-        &(&gl.viewport * &gl.projection) * &gl.model_view
-    );
+    log::debug!("ModelView:\n {:}", &gl.model_view);
+    log::debug!("Viewport:\n {:}", &gl.viewport);
+    log::debug!("Projection:\n {:}", &gl.projection);
+    log::debug!("Z:\n {:}", gl.viewport * gl.projection * gl.model_view);
 
-    // 2-pass
-    let mut img: RgbaImage = ImageBuffer::from_pixel(W, H, Rgba([0, 0, 0, 255]));
-    let mut zbuf: Vec<f64> = vec![f64::MIN; (W * H) as usize];
-    let mut zshader = ZShader::new(Rc::clone(&rcgl), Rc::new(&model));
+    let ssao = post.ssao.unwrap_or_default();
+    let gbuffer = geometry_pass(&gl, &model);
+    let ao = ssao.ambient_occlusion(&gl, &gbuffer);
 
-    for i in 0..model.nfaces() {
-        for j in 0..3 {
-            zshader.vertex(i, j);
+    let mut hdr: HdrImage = ImageBuffer::from_pixel(width, height, Rgba([0.0, 0.0, 0.0, 1.0]));
+    for (idx, pixel) in hdr.pixels_mut().enumerate() {
+        if gbuffer.covered(idx) {
+            *pixel = Rgba([ao[idx], ao[idx], ao[idx], 1.0]);
         }
-        let pt0 = Vec4f::from_vec(zshader.varying_tri.col(0));
-        let pt1 = Vec4f::from_vec(zshader.varying_tri.col(1));
-        let pt2 = Vec4f::from_vec(zshader.varying_tri.col(2));
-        gl.triangle([pt0, pt1, pt2], &mut zshader, &mut img, &mut zbuf);
     }
 
-    for x in 0..W {
-        for y in 0..H {
-            if zbuf[x as usize + y as usize * W as usize] < -1e5 {
-                continue;
-            }
+    Frame::new(&gl, &post, hdr, gbuffer.depth)
+}
 
-            let mut total = 0.0;
-            let mut a = 0.0;
-            while a < (PI * 2.0 - 1e-4) {
-                total += PI / 2.0
-                    - max_elevation_angle(
-                        &mut zbuf,
-                        W,
-                        H,
-                        Vec2f::from([x as f64, y as f64]),
-                        Vec2f::from([a.cos(), a.sin()]),
-                    );
-                a += PI / 4.0;
-            }
-            total /= (PI / 2.0) * 8.0; // pi / 2 * 8
-            total = f64::powf(total, 10.0);
-            img.put_pixel(
-                x,
-                y,
-                Rgba([
-                    (total * 255.0) as u8,
-                    (total * 255.0) as u8,
-                    (total * 255.0) as u8,
-                    255,
-                ]),
-            );
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel_in_hemisphere() {
+        let ssao = Ssao::default();
+        let kernel = ssao.kernel(&mut StdRng::seed_from_u64(1));
+        assert_eq!(kernel.len(), ssao.samples);
+        for k in kernel {
+            assert!(k[2] >= 0.0 && k.norm() <= 1.0);
         }
     }
 
-    let mut bs: Vec<u8> = Vec::new();
-    img.flip_vertical();
-    img.write_to(&mut Cursor::new(&mut bs), image::ImageOutputFormat::Png)
-        .unwrap();
-    bs
+    #[test]
+    fn test_flat_plane_is_unoccluded() {
+        let mut gbuffer = GBuffer::new(8, 8, f64::NEG_INFINITY);
+        for idx in 0..64 {
+            let (x, y) = ((idx % 8) as f64, (idx / 8) as f64);
+            gbuffer.depth[idx] = 0.0;
+            gbuffer.position[idx] = Vec3f::from([x, y, 0.0]);
+            gbuffer.normal[idx] = Vec3f::from([0.0, 0.0, 1.0]);
+        }
+        let gl = GL::new(Vec3f::ZERO, 8, 8);
+        let ssao = Ssao {
+            radius: 1.0,
+            ..Ssao::default()
+        };
+        let ao = ssao.blur(&ssao.occlusion(&gl, &gbuffer), &gbuffer);
+        assert!(ao.iter().all(|&a| a > 0.999));
+    }
 }
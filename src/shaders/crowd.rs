@@ -0,0 +1,258 @@
+use std::rc::Rc;
+
+use image::{ImageBuffer, Rgba};
+
+use crate::{
+    assets,
+    bounds::Aabb,
+    camera::CameraOptions,
+    culling::{Culler, Culling},
+    geometry::{embed, proj, Vec2f, Vec3f, Vec4f},
+    gl::{IIndexedShader, IShader, GL},
+    hdr::{srgb_to_linear, HdrImage},
+    instance::{Instance, InstanceUniforms, Material},
+    matrix::{Mat4f, Mat_},
+    model::Model,
+    post::PostProcess,
+    quat::Quat,
+    shaders::{Frame, RenderOptions},
+    transform::Transform,
+    util::maxf,
+};
+
+/// Specular mapping with the tint and material override of the current instance.
+#[derive(Debug)]
+pub struct CrowdShader<'a> {
+    gl: Rc<&'a GL>,
+    varying_uv: Mat_<f64, 2, 3>,
+    uniform_l: Vec3f, // light direction in view space, shared by all instances
+    uniform_mvp: Mat4f,
+    uniform_n: Mat4f, // ModelView.invert_transpose()
+    uniform_tint: [f32; 3],
+    uniform_material: Option<Material>,
+    model: Rc<&'a Model>,
+}
+
+impl<'a> CrowdShader<'a> {
+    pub fn new(gl: Rc<&'a GL>, model: Rc<&'a Model>) -> Self {
+        let l = gl.model_view * embed::<f64, 4, 3>(&gl.light_dir, 0.0);
+        Self {
+            uniform_l: proj::<_, 3, 4>(&l).normalize(),
+            uniform_mvp: gl.viewport * gl.projection * gl.model_view,
+            uniform_n: gl.model_view.invert_transpose(),
+            uniform_tint: [1.0; 3],
+            uniform_material: None,
+            gl,
+            varying_uv: Mat_::new(),
+            model,
+        }
+    }
+}
+
+impl<'a> IIndexedShader for CrowdShader<'a> {
+    type Varying = Vec2f; // uv
+
+    fn vertex_indexed(&mut self, ivert: usize) -> (Vec4f, Vec2f) {
+        let vertex = self.model.vertex(ivert);
+        let gl_vertex = embed::<_, 4, 3>(&vertex.position, 1.0);
+        (self.uniform_mvp * gl_vertex, vertex.uv)
+    }
+
+    fn load_varying(&mut self, nthvert: usize, uv: &Vec2f) {
+        self.varying_uv.set_col(nthvert, uv);
+    }
+}
+
+impl<'a> IShader for CrowdShader<'a> {
+    fn instance(&mut self, uniforms: &InstanceUniforms) {
+        self.uniform_mvp = uniforms.mvp;
+        self.uniform_n = uniforms.normal;
+        self.uniform_tint = uniforms.tint;
+        self.uniform_material = uniforms.material;
+    }
+
+    fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f {
+        let model = *self.model;
+        self.vertex_by_face(model, iface, nthvert)
+    }
+
+    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<f32> {
+        let uv = self.varying_uv * bc;
+        let _n = self.uniform_n
+            * embed::<f64, 4, 3>(&self.model.norm_by(Vec2f::from([uv[0], uv[1]])), 0.0);
+        let n = proj::<_, 3, 4>(&_n).normalize();
+        let l = self.uniform_l;
+        let r = (n * ((n.dot(&l)) * 2.0) - l).normalize();
+        let (albedo, exponent, emission) = match self.uniform_material {
+            Some(m) => (m.albedo, m.specular, m.emission),
+            None => (
+                self.model.diffuse(uv[0], uv[1]),
+                self.model.specular(uv[0], uv[1]),
+                self.model.glow(uv[0], uv[1]),
+            ),
+        };
+        let tint = self.uniform_tint;
+        let spec = f64::powf(maxf(r[2], 0.0), exponent);
+        let diff = maxf(0.0, n.dot(&l));
+        let light = (diff + 0.6 * spec) as f32;
+        let ambient = srgb_to_linear(5);
+        Rgba([
+            ambient + albedo[0] * tint[0] * light + emission[0],
+            ambient + albedo[1] * tint[1] * light + emission[1],
+            ambient + albedo[2] * tint[2] * light + emission[2],
+            1.0,
+        ])
+    }
+}
+
+/// Three staggered rows of heads, each turned and tinted a little differently. Two of them
+/// replace the textures with a plain material.
+pub fn crowd(rows: usize, cols: usize) -> Vec<Instance> {
+    let gold = Material {
+        albedo: Rgba([1.0, 0.71, 0.29, 1.0]),
+        specular: 40.0,
+        emission: Rgba([0.0, 0.0, 0.0, 0.0]),
+    };
+    let glowing = Material {
+        albedo: Rgba([0.1, 0.1, 0.1, 1.0]),
+        specular: 5.0,
+        emission: Rgba([0.2, 0.5, 1.0, 0.0]),
+    };
+    let mut instances = Vec::with_capacity(rows * cols);
+    for row in 0..rows {
+        for col in 0..cols {
+            let i = row * cols + col;
+            let x = (col as f64 - (cols - 1) as f64 / 2.0) * 2.2 + (row % 2) as f64 * 1.1;
+            let yaw = ((i * 5) % 7) as f64 * 0.15 - 0.45;
+            let shade = 0.75 + 0.25 * ((i * 3) % 4) as f32 / 3.0;
+            instances.push(Instance {
+                transform: Transform {
+                    translation: Vec3f::from([x, 0.0, -2.5 * row as f64]),
+                    rotation: Quat::from_euler(0.0, yaw, 0.0),
+                    scale: Vec3f::from([1.0; 3]),
+                },
+                tint: [shade, 0.8 + 0.2 * (1.0 - shade), 1.0 - 0.3 * (shade - 0.75)],
+                material: match i % 7 {
+                    3 => Some(gold),
+                    5 => Some(glowing),
+                    _ => None,
+                },
+            });
+        }
+    }
+    instances
+}
+
+pub fn crowd_render(post: PostProcess, camera: CameraOptions, options: &RenderOptions) -> Frame {
+    let (width, height) = (options.width, options.height);
+    let mut hdr: HdrImage = ImageBuffer::from_pixel(width, height, Rgba([0.0, 0.0, 0.0, 1.0]));
+    // loaded once, drawn for every instance
+    let model = assets::model(options.model_or("obj/african_head/african_head.obj"));
+    let instances = crowd(3, 5);
+    let bounds = instances[1..]
+        .iter()
+        .fold(instances[0].bbox(&model.bbox()), |b, i| {
+            b.union(&i.bbox(&model.bbox()))
+        });
+
+    let light_dir = options.light_dir;
+
+    let center = bounds.center();
+    let eye = center + Vec3f::from([1.0, 2.0, 6.0]);
+    let up = Vec3f::from([0.0, 1.0, 0.0]);
+
+    let mut gl = GL::new(light_dir, width, height);
+    gl.viewport(
+        width as i32 / 8,
+        height as i32 / 8,
+        width as i32 * 3 / 4,
+        height as i32 * 3 / 4,
+    );
+    // the crowd is much larger than one model, always frame all of it
+    CameraOptions {
+        fit: true,
+        ..camera
+    }
+    .resolve(&bounds, eye, center, up, gl.aspect())
+    .apply(&mut gl);
+    let mut zbuf = gl.depth_buffer();
+
+    let rcgl = Rc::new(&gl);
+    let mut shader = CrowdShader::new(Rc::clone(&rcgl), Rc::new(&*model));
+    let mut culler = Culler::new(&gl, Culling::default());
+    gl.draw_instanced(
+        &model,
+        &instances,
+        &mut shader,
+        &mut hdr,
+        &mut zbuf,
+        &mut culler,
+    );
+    log::debug!("Culling: {}", culler.stats);
+    gl.count_culled(&culler.stats);
+    // the screen-space ambient occlusion of `post` needs a single model and is left out
+
+    Frame::new(&gl, &post, hdr, zbuf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crowd() {
+        let instances = crowd(3, 5);
+        assert_eq!(instances.len(), 15);
+        assert_eq!(instances.iter().filter(|i| i.material.is_some()).count(), 4);
+        // every instance stands apart from the others
+        let bounds = Aabb {
+            min: Vec3f::from([-1.0; 3]),
+            max: Vec3f::from([1.0; 3]),
+        };
+        for (a, b) in instances.iter().zip(instances[1..].iter()) {
+            assert_ne!(a.bbox(&bounds).center(), b.bbox(&bounds).center());
+        }
+    }
+
+    #[test]
+    fn test_occlusion() {
+        let model = assets::model("obj/african_head/african_head.obj");
+        let mut gl = GL::new(Vec3f::from([0.0, 0.0, 1.0]), 64, 64);
+        gl.viewport(0, 0, 64, 64);
+        gl.lookat(
+            Vec3f::from([0.0, 0.0, 3.0]),
+            Vec3f::ZERO,
+            Vec3f::from([0.0, 1.0, 0.0]),
+        );
+        gl.perspective(f64::to_radians(60.0), 1.0, 0.1, 10.0);
+        // the head, then small copies hidden behind its face
+        let instances: Vec<Instance> = [(0.0, 1.0), (-2.0, 0.05), (-2.5, 0.05), (-3.0, 0.05)]
+            .into_iter()
+            .map(|(z, scale)| {
+                Instance::new(Transform {
+                    translation: Vec3f::from([0.0, 0.0, z]),
+                    scale: Vec3f::from([scale; 3]),
+                    ..Transform::default()
+                })
+            })
+            .collect();
+        let mut hdr: HdrImage = ImageBuffer::from_pixel(64, 64, Rgba([0.0, 0.0, 0.0, 1.0]));
+        let mut zbuf = gl.depth_buffer();
+        let culling = Culling {
+            frustum: true,
+            occlusion: true,
+        };
+        let mut culler = Culler::new(&gl, culling);
+        let rcgl = Rc::new(&gl);
+        let mut shader = CrowdShader::new(Rc::clone(&rcgl), Rc::new(&*model));
+        gl.draw_instanced(
+            &model,
+            &instances,
+            &mut shader,
+            &mut hdr,
+            &mut zbuf,
+            &mut culler,
+        );
+        assert_eq!((culler.stats.meshes, culler.stats.occluded), (4, 3));
+    }
+}
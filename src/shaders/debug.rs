@@ -0,0 +1,422 @@
+use std::rc::Rc;
+
+use image::{ImageBuffer, Rgba, RgbaImage};
+
+use crate::{
+    assets,
+    camera::CameraOptions,
+    culling::{CullStats, Culler, Culling, HiZ},
+    geometry::{embed, proj, Vec2f, Vec3f, Vec4f},
+    gl::{IShader, RenderStats, GL},
+    hdr::{linear_to_srgb, HdrImage},
+    matrix::{Mat3f, Mat4f, Mat_},
+    model::Model,
+    shaders::RenderOptions,
+    util::{maxf, minf, RgbaImageExt},
+};
+
+/// Intermediate data that can be shown instead of the shaded image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebugView {
+    /// Linear distance to the camera, near is white
+    Depth,
+    /// View space normals
+    Normals,
+    WorldNormals,
+    /// Checkerboard over the texture coordinates
+    UvChecker,
+    /// Per-face tangent from the uv derivatives, view space
+    Tangents,
+    /// How many fragments passed the depth test at each pixel
+    Overdraw,
+    TriangleId,
+    MeshId,
+    /// Diffuse texture and Lambert shading with the visible edges drawn on top
+    Wireframe,
+}
+
+impl DebugView {
+    pub const ALL: [DebugView; 9] = [
+        DebugView::Depth,
+        DebugView::Normals,
+        DebugView::WorldNormals,
+        DebugView::UvChecker,
+        DebugView::Tangents,
+        DebugView::Overdraw,
+        DebugView::TriangleId,
+        DebugView::MeshId,
+        DebugView::Wireframe,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DebugView::Depth => "depth",
+            DebugView::Normals => "normals",
+            DebugView::WorldNormals => "world-normals",
+            DebugView::UvChecker => "uv",
+            DebugView::Tangents => "tangents",
+            DebugView::Overdraw => "overdraw",
+            DebugView::TriangleId => "triangles",
+            DebugView::MeshId => "meshes",
+            DebugView::Wireframe => "wireframe",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|v| v.name() == name)
+    }
+}
+
+#[derive(Debug)]
+pub struct DebugShader<'a> {
+    gl: Rc<&'a GL>,
+    view: DebugView,
+    mesh_id: usize,
+    iface: usize,
+    varying_uv: Mat_<f64, 2, 3>,
+    varying_pos: Mat3f,     // view space
+    varying_norm: Mat3f,    // view or world space depending on `view`
+    varying_tangent: Vec3f, // per face, view space
+    uniform_mv_it: Mat4f,   // ModelView.invert_transpose()
+    model: Rc<&'a Model>,
+    /// Linear depth of the visible fragment, only written in `DebugView::Depth`
+    pub depth: Vec<f64>,
+    pub overdraw: Vec<u32>,
+}
+
+impl<'a> DebugShader<'a> {
+    pub fn new(gl: Rc<&'a GL>, model: Rc<&'a Model>, view: DebugView, mesh_id: usize) -> Self {
+        let n = (gl.width * gl.height) as usize;
+        let uniform_mv_it = gl.model_view.invert_transpose();
+        Self {
+            gl,
+            view,
+            mesh_id,
+            iface: 0,
+            varying_uv: Mat_::new(),
+            varying_pos: Mat3f::new(),
+            varying_norm: Mat3f::new(),
+            varying_tangent: Vec3f::ZERO,
+            uniform_mv_it,
+            model,
+            depth: vec![f64::NAN; n],
+            overdraw: vec![0; n],
+        }
+    }
+
+    /// Tangent of face `iface`, solved from the edge vectors and their uv deltas.
+    fn face_tangent(&self, iface: usize) -> Vec3f {
+        let (v0, v1, v2) = (
+            *self.model.vert_by(iface, 0),
+            *self.model.vert_by(iface, 1),
+            *self.model.vert_by(iface, 2),
+        );
+        let (uv0, uv1, uv2) = (
+            self.model.uv(iface, 0),
+            self.model.uv(iface, 1),
+            self.model.uv(iface, 2),
+        );
+        let (e1, e2) = (v1 - v0, v2 - v0);
+        let (d1, d2) = (uv1 - uv0, uv2 - uv0);
+        let det = d1[0] * d2[1] - d2[0] * d1[1];
+        if det.abs() < 1e-12 {
+            return Vec3f::ZERO;
+        }
+        let t = (e1 * d2[1] - e2 * d1[1]) / det;
+        let mut t = proj::<_, 3, 4>(&(self.gl.model_view * embed::<_, 4, 3>(&t, 0.0)));
+        if t.norm() < 1e-12 {
+            Vec3f::ZERO
+        } else {
+            t.normalize()
+        }
+    }
+}
+
+impl<'a> IShader for DebugShader<'a> {
+    fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f {
+        if nthvert == 0 {
+            self.iface = iface;
+            if self.view == DebugView::Tangents {
+                self.varying_tangent = self.face_tangent(iface);
+            }
+        }
+        self.varying_uv
+            .set_col(nthvert, &self.model.uv(iface, nthvert));
+        let norm = self.model.norm(iface, nthvert);
+        if self.view == DebugView::WorldNormals {
+            self.varying_norm.set_col(nthvert, &norm);
+        } else {
+            let n = self.uniform_mv_it * embed::<_, 4, 3>(&norm, 0.0);
+            self.varying_norm.set_col(nthvert, &proj::<_, 3, 4>(&n));
+        }
+        let gl_vertex = embed::<_, 4, 3>(self.model.vert_by(iface, nthvert), 1.0);
+        let view = self.gl.model_view * gl_vertex;
+        self.varying_pos
+            .set_col(nthvert, &proj::<_, 3, 4>(&(view / view[3])));
+        self.gl.viewport * self.gl.projection * view
+    }
+
+    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<f32> {
+        let idx = gl_fragcoord[0] as usize + gl_fragcoord[1] as usize * self.gl.width as usize;
+        self.overdraw[idx] += 1;
+        let c = match self.view {
+            DebugView::Depth => {
+                let p = self.varying_pos * bc;
+                // distance along the view axis, the camera looks down -z
+                self.depth[idx] = -p[2];
+                [0.0; 3]
+            }
+            DebugView::Normals | DebugView::WorldNormals => {
+                let n = (self.varying_norm * bc).normalize();
+                signed_to_color(n)
+            }
+            DebugView::UvChecker => {
+                let uv = self.varying_uv * bc;
+                let cells = 16.0;
+                let odd = ((uv[0] * cells).floor() + (uv[1] * cells).floor()) as i64 % 2 != 0;
+                let k = if odd { 1.0 } else { 0.35 };
+                [uv[0] as f32 * k, uv[1] as f32 * k, 0.5 * k]
+            }
+            DebugView::Tangents => signed_to_color(self.varying_tangent),
+            DebugView::Overdraw => [0.0; 3],
+            DebugView::TriangleId => id_color(self.iface),
+            DebugView::MeshId => id_color(self.mesh_id),
+            DebugView::Wireframe => {
+                let n = (self.varying_norm * bc).normalize();
+                let l = proj::<_, 3, 4>(
+                    &(self.gl.model_view * embed::<_, 4, 3>(&self.gl.light_dir, 0.0)),
+                )
+                .normalize();
+                let uv = self.varying_uv * bc;
+                let color = self.model.diffuse(uv[0], uv[1]);
+                let k = maxf(0.1, n.dot(&l)) as f32;
+                // the texture is linear, encode it here since `quantize` does not
+                let srgb = |c: f32| linear_to_srgb(c * k) as f32 / 255.0;
+                [srgb(color[0]), srgb(color[1]), srgb(color[2])]
+            }
+        };
+        Rgba([c[0], c[1], c[2], 1.0])
+    }
+}
+
+/// Maps a unit vector from [-1, 1] to [0, 1] per component.
+fn signed_to_color(v: Vec3f) -> [f32; 3] {
+    [
+        v[0] as f32 * 0.5 + 0.5,
+        v[1] as f32 * 0.5 + 0.5,
+        v[2] as f32 * 0.5 + 0.5,
+    ]
+}
+
+/// Well separated false color for an integer id, golden ratio steps around the hue circle.
+pub fn id_color(id: usize) -> [f32; 3] {
+    let h = (id as f64 * 0.618_033_988_75).fract() * 6.0;
+    let (s, v) = (0.65, 0.95);
+    let c = v * s;
+    let x = c * (1.0 - ((h % 2.0) - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    [(r + m) as f32, (g + m) as f32, (b + m) as f32]
+}
+
+/// Blue - green - yellow - red ramp for `t` in [0, 1].
+pub fn heatmap(t: f32) -> [f32; 3] {
+    let t = t.clamp(0.0, 1.0) * 3.0;
+    match t as u32 {
+        0 => [0.0, t, 1.0 - t],
+        1 => [t - 1.0, 1.0, 0.0],
+        _ => [1.0, 3.0 - t, 0.0],
+    }
+}
+
+/// Debug colors are already display values, quantize them without tone mapping.
+fn quantize(hdr: &HdrImage) -> RgbaImage {
+    ImageBuffer::from_fn(hdr.width(), hdr.height(), |x, y| {
+        let c = hdr.get_pixel(x, y);
+        let q = |v: f32| (v.clamp(0.0, 1.0) * 255.0 + 0.5) as u8;
+        Rgba([q(c[0]), q(c[1]), q(c[2]), q(c[3])])
+    })
+}
+
+/// Draw the triangle edges that are not hidden by the depth buffer.
+pub fn wireframe_overlay(
+    gl: &GL,
+    model: &Model,
+    zbuf: &[f64],
+    img: &mut RgbaImage,
+    color: Rgba<u8>,
+) {
+    let m = gl.viewport * gl.projection;
+    let (w, h) = (gl.width as i32, gl.height as i32);
+    for i in 0..model.nfaces() {
+        let mut pts = [Vec3f::ZERO; 3];
+        for (j, pt) in pts.iter_mut().enumerate() {
+            let v = gl.model_view * embed::<_, 4, 3>(model.vert_by(i, j), 1.0);
+            let mut v = proj::<_, 3, 4>(&(v / v[3]));
+            // pull the edges slightly towards the eye so the visible surface does not z-fight
+            if gl.is_perspective() {
+                v = v * (1.0 - 0.005 / v.norm());
+            } else {
+                v[2] += 0.005;
+            }
+            let p = m * embed::<_, 4, 3>(&v, 1.0);
+            *pt = proj::<_, 3, 4>(&(p / p[3]));
+        }
+        for j in 0..3 {
+            let (a, b) = (pts[j], pts[(j + 1) % 3]);
+            let steps = maxf((b[0] - a[0]).abs(), (b[1] - a[1]).abs()).ceil() as i32;
+            for s in 0..=steps {
+                let t = if steps == 0 {
+                    0.0
+                } else {
+                    s as f64 / steps as f64
+                };
+                let p = a + (b - a) * t;
+                let (x, y) = (p[0] as i32, p[1] as i32);
+                if x < 0 || y < 0 || x >= w || y >= h {
+                    continue;
+                }
+                if gl.depth_test.passes(p[2], zbuf[(x + y * w) as usize]) {
+                    img.put_pixel(x as u32, y as u32, color);
+                }
+            }
+        }
+    }
+}
+
+/// The african head and its inner eyes (or the model of `options`), shown as `view`. Meshes rejected by `culling` are
+/// skipped, the returned stats tell how many, along with those of the pipeline.
+pub fn debug_render(
+    view: DebugView,
+    camera: CameraOptions,
+    culling: Culling,
+    options: &RenderOptions,
+) -> (RgbaImage, CullStats, RenderStats) {
+    let (width, height) = (options.width, options.height);
+    let models = match &options.model {
+        Some(path) => vec![assets::model(path)],
+        None => vec![
+            assets::model("obj/african_head/african_head.obj"),
+            assets::model("obj/african_head/african_head_eye_inner.obj"),
+        ],
+    };
+
+    let light_dir = options.light_dir;
+
+    let eye = Vec3f::from([1.0, 1.0, 3.0]);
+    let center = Vec3f::from([0.0, 0.0, 0.0]);
+    let up = Vec3f::from([0.0, 1.0, 0.0]);
+
+    let mut gl = GL::new(light_dir, width, height);
+    gl.viewport(
+        width as i32 / 8,
+        height as i32 / 8,
+        width as i32 * 3 / 4,
+        height as i32 * 3 / 4,
+    );
+    let bounds = models[1..]
+        .iter()
+        .fold(models[0].bbox(), |b, m| b.union(&m.bbox()));
+    camera
+        .resolve(&bounds, eye, center, up, gl.aspect())
+        .apply(&mut gl);
+    let mut zbuf = gl.depth_buffer();
+
+    let rcgl = Rc::new(&gl);
+    let mut hdr: HdrImage = ImageBuffer::from_pixel(width, height, Rgba([0.0, 0.0, 0.0, 1.0]));
+    let mut depth = vec![f64::NAN; (width * height) as usize];
+    let mut overdraw = vec![0u32; (width * height) as usize];
+    let mut culler = Culler::new(&gl, culling);
+    let mut drawn = vec![false; models.len()];
+    for (mesh_id, model) in models.iter().enumerate() {
+        // the pyramid of what the previous meshes left in the depth buffer
+        let hiz = culling.occlusion.then(|| HiZ::new(&gl, &zbuf));
+        if !culler.test(&model.bbox(), model.nfaces(), hiz.as_ref()) {
+            continue;
+        }
+        drawn[mesh_id] = true;
+        let mut shader = DebugShader::new(Rc::clone(&rcgl), Rc::new(&**model), view, mesh_id);
+        for i in 0..model.nfaces() {
+            let mut screen_coords: [Vec4f; 3] = [Vec4f::from([0.0, 0.0, 0.0, 0.0]); 3];
+            for (j, coord) in screen_coords.iter_mut().enumerate() {
+                *coord = shader.vertex(i, j);
+            }
+            gl.triangle(screen_coords, &mut shader, &mut hdr, &mut zbuf);
+        }
+        for idx in 0..depth.len() {
+            if !shader.depth[idx].is_nan() {
+                depth[idx] = shader.depth[idx];
+            }
+            overdraw[idx] += shader.overdraw[idx];
+        }
+    }
+
+    match view {
+        DebugView::Depth => {
+            let (near, far) = depth
+                .iter()
+                .filter(|d| !d.is_nan())
+                .fold((f64::MAX, f64::MIN), |(lo, hi), &d| {
+                    (minf(lo, d), maxf(hi, d))
+                });
+            for (idx, pixel) in hdr.pixels_mut().enumerate() {
+                if !depth[idx].is_nan() {
+                    let g = (1.0 - (depth[idx] - near) / maxf(far - near, 1e-9)) as f32;
+                    *pixel = Rgba([g, g, g, 1.0]);
+                }
+            }
+        }
+        DebugView::Overdraw => {
+            let max = *overdraw.iter().max().unwrap_or(&1) as f32;
+            for (idx, pixel) in hdr.pixels_mut().enumerate() {
+                if overdraw[idx] > 0 {
+                    let c = heatmap(overdraw[idx] as f32 / max);
+                    *pixel = Rgba([c[0], c[1], c[2], 1.0]);
+                }
+            }
+        }
+        _ => {}
+    }
+
+    let mut img = quantize(&hdr);
+    if view == DebugView::Wireframe {
+        for (model, _) in models.iter().zip(drawn).filter(|(_, drawn)| *drawn) {
+            wireframe_overlay(&gl, model, &zbuf, &mut img, Rgba([255, 255, 255, 255]));
+        }
+    }
+    img.flip_vertical();
+    gl.count_culled(&culler.stats);
+    (img, culler.stats, gl.stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_view_names() {
+        for view in DebugView::ALL {
+            assert_eq!(DebugView::from_name(view.name()), Some(view));
+        }
+        assert_eq!(DebugView::from_name("shaded"), None);
+    }
+
+    #[test]
+    fn test_heatmap() {
+        assert_eq!(heatmap(0.0), [0.0, 0.0, 1.0]);
+        assert_eq!(heatmap(1.0), [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_id_color_distinct() {
+        assert_ne!(id_color(0), id_color(1));
+        assert_ne!(id_color(1), id_color(2));
+    }
+}
@@ -0,0 +1,143 @@
+use std::rc::Rc;
+
+use image::{ImageBuffer, Rgba};
+
+use crate::{
+    assets,
+    camera::CameraOptions,
+    deferred::{GBuffer, Light, Surface},
+    geometry::{embed, proj, Vec2f, Vec3f, Vec4f},
+    gl::{IGeometryShader, IIndexedShader, GL},
+    hdr::HdrImage,
+    matrix::{Mat3f, Mat4f, Mat_},
+    model::Model,
+    post::PostProcess,
+    shaders::{Frame, RenderOptions},
+};
+
+/// Geometry pass writing textured, normal-mapped surface attributes in view space.
+#[derive(Debug)]
+pub struct DeferredShader<'a> {
+    gl: Rc<&'a GL>,
+    varying_uv: Mat_<f64, 2, 3>,
+    varying_pos: Mat3f,   // view space
+    uniform_mv_it: Mat4f, // ModelView.invert_transpose()
+    model: Rc<&'a Model>,
+}
+
+impl<'a> DeferredShader<'a> {
+    pub fn new(gl: Rc<&'a GL>, model: Rc<&'a Model>) -> Self {
+        let uniform_mv_it = gl.model_view.invert_transpose();
+        Self {
+            gl,
+            varying_uv: Mat_::new(),
+            varying_pos: Mat3f::new(),
+            uniform_mv_it,
+            model,
+        }
+    }
+}
+
+impl<'a> IIndexedShader for DeferredShader<'a> {
+    type Varying = (Vec2f, Vec3f); // uv, view space position
+
+    fn vertex_indexed(&mut self, ivert: usize) -> (Vec4f, (Vec2f, Vec3f)) {
+        let vertex = self.model.vertex(ivert);
+        let gl_vertex = embed::<_, 4, 3>(&vertex.position, 1.0);
+        let view = self.gl.model_view * gl_vertex;
+        let position = proj::<_, 3, 4>(&(view / view[3]));
+        (
+            self.gl.viewport * self.gl.projection * view,
+            (vertex.uv, position),
+        )
+    }
+
+    fn load_varying(&mut self, nthvert: usize, (uv, position): &(Vec2f, Vec3f)) {
+        self.varying_uv.set_col(nthvert, uv);
+        self.varying_pos.set_col(nthvert, position);
+    }
+}
+
+impl<'a> IGeometryShader for DeferredShader<'a> {
+    fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f {
+        let model = *self.model;
+        self.vertex_by_face(model, iface, nthvert)
+    }
+
+    fn fragment(&mut self, bc: Vec3f) -> Surface {
+        let uv = self.varying_uv * bc;
+        let n = self.uniform_mv_it
+            * embed::<f64, 4, 3>(&self.model.norm_by(Vec2f::from([uv[0], uv[1]])), 0.0);
+        Surface {
+            position: self.varying_pos * bc,
+            normal: proj::<_, 3, 4>(&n).normalize(),
+            albedo: self.model.diffuse(uv[0], uv[1]),
+            specular: self.model.specular(uv[0], uv[1]),
+            emission: self.model.glow(uv[0], uv[1]),
+        }
+    }
+}
+
+/// Rasterize `model` with the current `gl` matrices into a fresh `GBuffer`.
+pub fn geometry_pass(gl: &GL, model: &Model) -> GBuffer {
+    let mut gbuffer = GBuffer::new(gl.width, gl.height, gl.depth_test.clear_value());
+    let mut shader = DeferredShader::new(Rc::new(gl), Rc::new(model));
+    gl.draw_indexed_deferred(model, &mut shader, &mut gbuffer);
+    gbuffer
+}
+
+pub fn deferred_render(post: PostProcess, camera: CameraOptions, options: &RenderOptions) -> Frame {
+    let (width, height) = (options.width, options.height);
+    let model = assets::model(options.model_or("obj/diablo3_pose/diablo3_pose.obj"));
+
+    let light_dir = options.light_dir;
+
+    let eye = Vec3f::from([1.0, 1.0, 3.0]);
+    let center = Vec3f::from([0.0, 0.0, 0.0]);
+    let up = Vec3f::from([0.0, 1.0, 0.0]);
+
+    let mut gl = GL::new(light_dir, width, height);
+    gl.viewport(
+        width as i32 / 8,
+        height as i32 / 8,
+        width as i32 * 3 / 4,
+        height as i32 * 3 / 4,
+    );
+    camera
+        .resolve(&model.bbox(), eye, center, up, gl.aspect())
+        .apply(&mut gl);
+    gl.lights = vec![
+        Light::Ambient {
+            color: [0.03, 0.03, 0.03],
+        },
+        Light::Directional {
+            dir: light_dir,
+            color: [0.6, 0.6, 0.6],
+        },
+        Light::Point {
+            pos: Vec3f::from([-1.2, 0.8, 0.8]),
+            color: [3.0, 0.6, 0.2],
+            range: 3.0,
+        },
+        Light::Point {
+            pos: Vec3f::from([1.2, -0.4, 0.6]),
+            color: [0.3, 0.8, 3.0],
+            range: 3.0,
+        },
+        Light::Point {
+            pos: Vec3f::from([0.0, 1.5, -1.0]),
+            color: [2.0, 2.0, 2.0],
+            range: 3.0,
+        },
+    ];
+
+    let gbuffer = geometry_pass(&gl, &model);
+    let mut hdr: HdrImage = ImageBuffer::from_pixel(width, height, Rgba([0.0, 0.0, 0.0, 1.0]));
+    gl.lighting_pass(&gbuffer, &mut hdr);
+
+    if let Some(ssao) = &post.ssao {
+        let ao = ssao.ambient_occlusion(&gl, &gbuffer);
+        ssao.composite(&mut hdr, &ao);
+    }
+    Frame::new(&gl, &post, hdr, gbuffer.depth)
+}
@@ -1,12 +1,17 @@
-use std::{io::Cursor, rc::Rc};
+use std::rc::Rc;
 
-use image::{ImageBuffer, Rgba, RgbaImage};
+use image::{ImageBuffer, Rgba};
 
 use crate::{
+    assets,
+    camera::CameraOptions,
     geometry::{embed, Vec3f, Vec4f},
-    gl::{IShader, GL},
+    gl::{IIndexedShader, IShader, GL},
+    hdr::{srgb_to_linear, HdrImage},
     model::Model,
-    util::{maxf, RgbaImageExt},
+    post::PostProcess,
+    shaders::{Frame, RenderOptions},
+    util::maxf,
 };
 
 #[derive(Debug)]
@@ -26,18 +31,33 @@ impl<'a> Gouraud6LShader<'a> {
     }
 }
 
+impl<'a> IIndexedShader for Gouraud6LShader<'a> {
+    type Varying = f64; // intensity
+
+    fn vertex_indexed(&mut self, ivert: usize) -> (Vec4f, f64) {
+        let vertex = self.model.vertex(ivert);
+        let intensity = maxf(0.0, vertex.normal.dot(&self.gl.light_dir));
+        let gl_vertex = embed::<_, 4, 3>(&vertex.position, 1.0);
+        (
+            self.gl.viewport * self.gl.projection * self.gl.model_view * gl_vertex,
+            intensity,
+        )
+    }
+
+    fn load_varying(&mut self, nthvert: usize, intensity: &f64) {
+        self.varying_intensity[nthvert] = *intensity;
+    }
+}
+
 impl<'a> IShader for Gouraud6LShader<'a> {
     #[inline]
     fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f {
-        self.varying_intensity[nthvert] =
-            maxf(0.0, self.model.norm(iface, nthvert).dot(&self.gl.light_dir));
-        let gl_vertex = embed::<_, 4, 3>(self.model.vert_by(iface, nthvert), 1.0);
-        let r = &(&(&self.gl.viewport * &self.gl.projection) * &self.gl.model_view) * &gl_vertex;
-        Vec4f::from_vec(r)
+        let model = *self.model;
+        self.vertex_by_face(model, iface, nthvert)
     }
 
     #[inline]
-    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<u8> {
+    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<f32> {
         let intensity = match self.varying_intensity.dot(&bc) {
             i if i > 0.85 => 1.0,
             i if i > 0.60 => 0.80,
@@ -46,61 +66,60 @@ impl<'a> IShader for Gouraud6LShader<'a> {
             i if i > 0.15 => 0.30,
             _ => 0.0,
         };
-        let color = Rgba([
-            (255.0 * intensity) as u8,
-            (155.0 * intensity) as u8,
-            (0.0 * intensity) as u8,
-            255,
+        let base = Rgba([
+            srgb_to_linear(255),
+            srgb_to_linear(155),
+            srgb_to_linear(0),
+            1.0,
         ]);
-        color
+        Rgba([
+            base[0] * intensity,
+            base[1] * intensity,
+            base[2] * intensity,
+            1.0,
+        ])
     }
 }
 
-pub fn gouraud6l_render() -> Vec<u8> {
-    const W: u32 = 800;
-    const H: u32 = 800;
-    let mut img: RgbaImage = ImageBuffer::from_pixel(W, H, Rgba([0, 0, 0, 255]));
-    let model = Model::new("obj/african_head/african_head.obj");
+pub fn gouraud6l_render(
+    post: PostProcess,
+    camera: CameraOptions,
+    options: &RenderOptions,
+) -> Frame {
+    let (width, height) = (options.width, options.height);
+    let mut hdr: HdrImage = ImageBuffer::from_pixel(width, height, Rgba([0.0, 0.0, 0.0, 1.0]));
+    let model = assets::model(options.model_or("obj/african_head/african_head.obj"));
 
-    let light_dir = Vec3f::from([1.0, 1.0, 1.0]).normalize();
+    let light_dir = options.light_dir;
 
     let eye = Vec3f::from([1.0, 1.0, 3.0]);
     let center = Vec3f::from([0.0, 0.0, 0.0]);
     let up = Vec3f::from([0.0, 1.0, 0.0]);
-    let mut zbuf = vec![f64::MIN; (W * H) as usize];
 
-    let mut gl = GL::new(light_dir, W, H);
-    gl.lookat(eye, center, up);
+    let mut gl = GL::new(light_dir, width, height);
     gl.viewport(
-        W as i32 / 8,
-        H as i32 / 8,
-        W as i32 * 3 / 4,
-        H as i32 * 3 / 4,
+        width as i32 / 8,
+        height as i32 / 8,
+        width as i32 * 3 / 4,
+        height as i32 * 3 / 4,
     );
-    gl.projection(-1.0 / (eye - center).norm());
+    camera
+        .resolve(&model.bbox(), eye, center, up, gl.aspect())
+        .apply(&mut gl);
+    let mut zbuf = gl.depth_buffer();
 
     let rcgl = Rc::new(&gl);
 
-    let mut shader = Gouraud6LShader::new(Rc::clone(&rcgl), Rc::new(&model));
+    let mut shader = Gouraud6LShader::new(Rc::clone(&rcgl), Rc::new(&*model));
 
-    println!("ModelView:\n {:}", &gl.model_view);
-    println!("Viewport:\n {:}", &gl.viewport);
-    println!("Projection:\n {:}", &gl.projection);
-    println!(
-        "Z:\n {:}",
-        &(&gl.viewport * &gl.projection) * &gl.model_view
-    );
+    log::debug!("ModelView:\n {:}", &gl.model_view);
+    log::debug!("Viewport:\n {:}", &gl.viewport);
+    log::debug!("Projection:\n {:}", &gl.projection);
+    log::debug!("Z:\n {:}", gl.viewport * gl.projection * gl.model_view);
 
-    for i in 0..model.nfaces() {
-        let mut screen_coords: [Vec4f; 3] = [Vec4f::from([0.0, 0.0, 0.0, 0.0]); 3];
-        for j in 0..3 {
-            screen_coords[j] = shader.vertex(i, j);
-        }
-        gl.triangle(screen_coords, &mut shader, &mut img, &mut zbuf);
+    gl.draw_indexed(&model, &mut shader, &mut hdr, &mut zbuf);
+    if let Some(ssao) = &post.ssao {
+        ssao.apply(&gl, &model, &mut hdr);
     }
-    let mut bs: Vec<u8> = Vec::new();
-    img.flip_vertical();
-    img.write_to(&mut Cursor::new(&mut bs), image::ImageOutputFormat::Png)
-        .unwrap();
-    bs
+    Frame::new(&gl, &post, hdr, zbuf)
 }
@@ -1,7 +1,163 @@
 pub mod ambientocclusion;
+pub mod crowd;
+pub mod debug;
+pub mod deferred;
 pub mod gouraud;
 pub mod gouraud6l;
 pub mod normalmapping;
+pub mod scene;
 pub mod shadowmapping;
 pub mod specularmapping;
 pub mod texture;
+
+use std::time::Instant;
+
+use image::{imageops, RgbaImage};
+
+use crate::{
+    camera::CameraOptions,
+    geometry::{Vec3f, Vec4f},
+    gl::{RenderStats, GL},
+    hdr::HdrImage,
+    matrix::Mat4f,
+    post::PostProcess,
+    routes::{Source, RENDERS},
+    util::RgbaImageExt,
+};
+
+/// What a render draws besides camera and post-processing, the defaults are those of the
+/// routes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderOptions {
+    /// `.obj` file, the textures are looked up next to it. `None` keeps the model of the render
+    pub model: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    /// Direction towards the key light, normalized
+    pub light_dir: Vec3f,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            model: None,
+            width: 800,
+            height: 800,
+            light_dir: Vec3f::from([1.0, 1.0, 1.0]).normalize(),
+        }
+    }
+}
+
+impl RenderOptions {
+    pub fn model_or<'a>(&'a self, default: &'a str) -> &'a str {
+        self.model.as_deref().unwrap_or(default)
+    }
+}
+
+/// What a render produced: the displayable image and the buffers it was resolved from.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// Tone mapped, top row first
+    pub image: RgbaImage,
+    /// Linear color before tone mapping, bottom row first like the framebuffer
+    pub hdr: HdrImage,
+    /// Screen depth of the visible surfaces, `DepthTest::clear_value` where nothing was drawn
+    pub zbuf: Vec<f64>,
+    /// View to screen space (after the viewport), inverted by `depth`
+    pub screen: Mat4f,
+    /// What drawing it took, up to the post processing
+    pub stats: RenderStats,
+}
+
+impl Frame {
+    /// Applies `post` to `hdr` and flips the result to image order.
+    pub fn new(gl: &GL, post: &PostProcess, mut hdr: HdrImage, zbuf: Vec<f64>) -> Self {
+        let mut stats = gl.stats();
+        let start = Instant::now();
+        let mut image = post.resolve(&mut hdr);
+        image.flip_vertical();
+        stats.stages.push(("post", start.elapsed()));
+        log::debug!("{} {}", stats, stats.server_timing());
+        Self {
+            image,
+            hdr,
+            zbuf,
+            screen: gl.viewport * gl.projection,
+            stats,
+        }
+    }
+
+    /// `hdr` top row first like `image`.
+    pub fn hdr_image(&self) -> HdrImage {
+        imageops::flip_vertical(&self.hdr)
+    }
+
+    /// Distance from the eye along the view direction, top row first like `image` and
+    /// infinite where nothing was drawn. A flat viewport draws nothing and is all infinite.
+    pub fn depth(&self) -> Vec<f32> {
+        let (width, height) = self.hdr.dimensions();
+        let mut depth = vec![f32::INFINITY; (width * height) as usize];
+        let Some(unproject) = self.screen.try_invert() else {
+            return depth;
+        };
+        for y in 0..height {
+            for x in 0..width {
+                let z = self.zbuf[(x + y * width) as usize];
+                if !z.is_finite() {
+                    continue;
+                }
+                let p = unproject * Vec4f::from([x as f64 + 0.5, y as f64 + 0.5, z, 1.0]);
+                depth[(x + (height - 1 - y) * width) as usize] = (-p[2] / p[3]) as f32;
+            }
+        }
+        depth
+    }
+}
+
+/// Signature shared by the `/shaders/*` renders.
+pub type RenderFn = fn(PostProcess, CameraOptions, &RenderOptions) -> Frame;
+
+/// Names accepted by `by_name`, in the order of `routes::RENDERS`.
+pub fn names() -> Vec<&'static str> {
+    RENDERS
+        .iter()
+        .filter(|r| matches!(r.source, Source::Shader(_)))
+        .map(|r| r.name)
+        .collect()
+}
+
+/// The render behind `/shaders/<name>`.
+pub fn by_name(name: &str) -> Option<RenderFn> {
+    RENDERS.iter().find_map(|r| match r.source {
+        Source::Shader(render) if r.name == name => Some(render),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        assert!(names().iter().all(|name| by_name(name).is_some()));
+        assert!(by_name("phong").is_none());
+    }
+
+    #[test]
+    fn test_tiny() {
+        // the viewport of 3/4 of the image rounds to nothing, `GL::viewport` keeps a pixel
+        let options = RenderOptions {
+            width: 1,
+            height: 1,
+            ..RenderOptions::default()
+        };
+        for name in names() {
+            let frame =
+                by_name(name).unwrap()(PostProcess::default(), CameraOptions::default(), &options);
+            assert_eq!(frame.image.dimensions(), (1, 1), "{}", name);
+            assert_eq!(frame.depth().len(), 1, "{}", name);
+            assert!(frame.screen.try_invert().is_some(), "{}", name);
+        }
+    }
+}
@@ -1,21 +1,26 @@
-use std::{io::Cursor, rc::Rc};
+use std::rc::Rc;
 
-use image::{ImageBuffer, Rgba, RgbaImage};
+use image::{ImageBuffer, Rgba};
 
 use crate::{
+    assets,
+    camera::CameraOptions,
     geometry::{embed, proj, Vec2f, Vec3f, Vec4f},
-    gl::{IShader, GL},
-    matrix::Mat,
+    gl::{IIndexedShader, IShader, GL},
+    hdr::HdrImage,
+    matrix::{Mat4f, Mat_},
     model::Model,
-    util::{maxf, RgbaImageExt},
+    post::PostProcess,
+    shaders::{Frame, RenderOptions},
+    util::maxf,
 };
 
 #[derive(Debug)]
 pub struct NormalMappingShader<'a> {
     gl: Rc<&'a GL>,
-    varying_uv: Mat<f64>,   // <2, 3>
-    uniform_m: Mat<f64>,    // <4, 4>, Projection * ModelView
-    uniform_m_it: Mat<f64>, // <4, 4>, (Projection * ModelView).invert_transpose()
+    varying_uv: Mat_<f64, 2, 3>,
+    uniform_m: Mat4f,    // ModelView
+    uniform_m_it: Mat4f, // ModelView.invert_transpose()
     model: Rc<&'a Model>,
 }
 
@@ -23,89 +28,98 @@ impl<'a> NormalMappingShader<'a> {
     pub fn new(gl: Rc<&'a GL>, model: Rc<&'a Model>) -> Self {
         Self {
             gl,
-            varying_uv: Mat::new(2, 3),
-            uniform_m: Mat::identity(4),
-            uniform_m_it: Mat::identity(4),
+            varying_uv: Mat_::new(),
+            uniform_m: Mat4f::identity(),
+            uniform_m_it: Mat4f::identity(),
             model,
         }
     }
 }
 
+impl<'a> IIndexedShader for NormalMappingShader<'a> {
+    type Varying = Vec2f; // uv
+
+    fn vertex_indexed(&mut self, ivert: usize) -> (Vec4f, Vec2f) {
+        let vertex = self.model.vertex(ivert);
+        let gl_vertex = embed::<_, 4, 3>(&vertex.position, 1.0);
+        (
+            self.gl.viewport * self.gl.projection * self.gl.model_view * gl_vertex,
+            vertex.uv,
+        )
+    }
+
+    fn load_varying(&mut self, nthvert: usize, uv: &Vec2f) {
+        self.varying_uv.set_col(nthvert, uv);
+    }
+}
+
 impl<'a> IShader for NormalMappingShader<'a> {
     fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f {
-        self.varying_uv
-            .set_col(nthvert, &self.model.uv(iface, nthvert).to_vec());
-        let gl_vertex = embed::<_, 4, 3>(self.model.vert_by(iface, nthvert), 1.0);
-        let r = &(&(&self.gl.viewport * &self.gl.projection) * &self.gl.model_view) * &gl_vertex;
-        Vec4f::from_vec(r)
+        let model = *self.model;
+        self.vertex_by_face(model, iface, nthvert)
     }
 
-    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<u8> {
-        let uv = &self.varying_uv * &bc;
-        let _n = &self.uniform_m
-            * &embed::<f64, 4, 3>(&self.model.norm_by(Vec2f::from([uv[0], uv[1]])), 1.0);
-        let n = Vec3f::from([_n[0], _n[1], _n[2]]).normalize();
-        let _l = &self.uniform_m_it * &embed::<f64, 4, 3>(&self.gl.light_dir, 1.0);
-        let l = Vec3f::from([_l[0], _l[1], _l[2]]).normalize();
-        let intensity = maxf(0.0, n.dot(&l));
+    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<f32> {
+        let uv = self.varying_uv * bc;
+        let _n = self.uniform_m
+            * embed::<f64, 4, 3>(&self.model.norm_by(Vec2f::from([uv[0], uv[1]])), 0.0);
+        let n = proj::<_, 3, 4>(&_n).normalize();
+        let _l = self.uniform_m_it * embed::<f64, 4, 3>(&self.gl.light_dir, 0.0);
+        let l = proj::<_, 3, 4>(&_l).normalize();
+        let intensity = maxf(0.0, n.dot(&l)) as f32;
         let color = self.model.diffuse(uv[0], uv[1]);
-        let color = Rgba([
-            (color[0] as f64 * intensity) as u8,
-            (color[1] as f64 * intensity) as u8,
-            (color[2] as f64 * intensity) as u8,
-            255,
-        ]);
-        color
+        let glow = self.model.glow(uv[0], uv[1]);
+        Rgba([
+            color[0] * intensity + glow[0],
+            color[1] * intensity + glow[1],
+            color[2] * intensity + glow[2],
+            1.0,
+        ])
     }
 }
 
-pub fn normal_mapping_render() -> Vec<u8> {
-    const W: u32 = 800;
-    const H: u32 = 800;
-    let mut img: RgbaImage = ImageBuffer::from_pixel(W, H, Rgba([0, 0, 0, 255]));
-    let model = Model::new("obj/african_head/african_head.obj");
+pub fn normal_mapping_render(
+    post: PostProcess,
+    camera: CameraOptions,
+    options: &RenderOptions,
+) -> Frame {
+    let (width, height) = (options.width, options.height);
+    let mut hdr: HdrImage = ImageBuffer::from_pixel(width, height, Rgba([0.0, 0.0, 0.0, 1.0]));
+    let model = assets::model(options.model_or("obj/african_head/african_head.obj"));
 
-    let light_dir = Vec3f::from([1.0, 1.0, 1.0]).normalize();
+    let light_dir = options.light_dir;
 
     let eye = Vec3f::from([1.0, 1.0, 3.0]);
     let center = Vec3f::from([0.0, 0.0, 0.0]);
     let up = Vec3f::from([0.0, 1.0, 0.0]);
-    let mut zbuf = vec![f64::MIN; (W * H) as usize];
 
-    let mut gl = GL::new(light_dir, W, H);
-    gl.lookat(eye, center, up);
+    let mut gl = GL::new(light_dir, width, height);
     gl.viewport(
-        W as i32 / 8,
-        H as i32 / 8,
-        W as i32 * 3 / 4,
-        H as i32 * 3 / 4,
+        width as i32 / 8,
+        height as i32 / 8,
+        width as i32 * 3 / 4,
+        height as i32 * 3 / 4,
     );
-    gl.projection(-1.0 / (eye - center).norm());
+    camera
+        .resolve(&model.bbox(), eye, center, up, gl.aspect())
+        .apply(&mut gl);
+    let mut zbuf = gl.depth_buffer();
 
     let rcgl = Rc::new(&gl);
 
-    let mut shader = NormalMappingShader::new(Rc::clone(&rcgl), Rc::new(&model));
-    shader.uniform_m = &gl.projection * &gl.model_view;
+    let mut shader = NormalMappingShader::new(Rc::clone(&rcgl), Rc::new(&*model));
+    // lighting happens in view space, independent of the projection
+    shader.uniform_m = gl.model_view;
     shader.uniform_m_it = shader.uniform_m.invert_transpose();
 
-    println!("ModelView:\n {:}", &gl.model_view);
-    println!("Viewport:\n {:}", &gl.viewport);
-    println!("Projection:\n {:}", &gl.projection);
-    println!(
-        "Z:\n {:}",
-        &(&gl.viewport * &gl.projection) * &gl.model_view
-    );
+    log::debug!("ModelView:\n {:}", &gl.model_view);
+    log::debug!("Viewport:\n {:}", &gl.viewport);
+    log::debug!("Projection:\n {:}", &gl.projection);
+    log::debug!("Z:\n {:}", gl.viewport * gl.projection * gl.model_view);
 
-    for i in 0..model.nfaces() {
-        let mut screen_coords: [Vec4f; 3] = [Vec4f::from([0.0, 0.0, 0.0, 0.0]); 3];
-        for j in 0..3 {
-            screen_coords[j] = shader.vertex(i, j);
-        }
-        gl.triangle(screen_coords, &mut shader, &mut img, &mut zbuf);
+    gl.draw_indexed(&model, &mut shader, &mut hdr, &mut zbuf);
+    if let Some(ssao) = &post.ssao {
+        ssao.apply(&gl, &model, &mut hdr);
     }
-    let mut bs: Vec<u8> = Vec::new();
-    img.flip_vertical();
-    img.write_to(&mut Cursor::new(&mut bs), image::ImageOutputFormat::Png)
-        .unwrap();
-    bs
+    Frame::new(&gl, &post, hdr, zbuf)
 }
@@ -0,0 +1,304 @@
+use std::{rc::Rc, sync::Arc};
+
+use image::{ImageBuffer, Rgba};
+
+use crate::{
+    assets,
+    bounds::Aabb,
+    culling::{Culler, Culling},
+    deferred::{GBuffer, Light, Surface},
+    geometry::{embed, proj, Vec2f, Vec3f, Vec4f},
+    gl::{IGeometryShader, IIndexedShader, IShader, GL},
+    hdr::HdrImage,
+    instance::{Instance, InstanceUniforms, Material},
+    matrix::{Mat3f, Mat4f, Mat_},
+    model::Model,
+    scene::{Pipeline, Scene},
+    shaders::Frame,
+};
+
+/// Normal-mapped surface with the transform, tint and material of the current instance, lit
+/// by every light of the scene. The forward mode shades each fragment, the deferred mode
+/// writes the same surface to the G-buffer.
+#[derive(Debug)]
+pub struct SceneShader<'a> {
+    gl: Rc<&'a GL>,
+    varying_uv: Mat_<f64, 2, 3>,
+    varying_pos: Mat3f,         // view space
+    uniform_lights: Vec<Light>, // view space
+    uniform_mv: Mat4f,
+    uniform_mvp: Mat4f,
+    uniform_n: Mat4f, // ModelView.invert_transpose()
+    uniform_tint: [f32; 3],
+    uniform_material: Option<Material>,
+    model: Rc<&'a Model>,
+}
+
+impl<'a> SceneShader<'a> {
+    pub fn new(gl: Rc<&'a GL>, model: Rc<&'a Model>) -> Self {
+        Self {
+            uniform_lights: gl
+                .lights
+                .iter()
+                .map(|l| l.to_view(&gl.model_view))
+                .collect(),
+            uniform_mv: gl.model_view,
+            uniform_mvp: gl.viewport * gl.projection * gl.model_view,
+            uniform_n: gl.model_view.invert_transpose(),
+            uniform_tint: [1.0; 3],
+            uniform_material: None,
+            gl,
+            varying_uv: Mat_::new(),
+            varying_pos: Mat3f::new(),
+            model,
+        }
+    }
+
+    fn surface(&self, bc: Vec3f) -> Surface {
+        let uv = self.varying_uv * bc;
+        let n = self.uniform_n
+            * embed::<f64, 4, 3>(&self.model.norm_by(Vec2f::from([uv[0], uv[1]])), 0.0);
+        let (albedo, specular, emission) = match self.uniform_material {
+            Some(m) => (m.albedo, m.specular, m.emission),
+            None => (
+                self.model.diffuse(uv[0], uv[1]),
+                self.model.specular(uv[0], uv[1]),
+                self.model.glow(uv[0], uv[1]),
+            ),
+        };
+        let tint = self.uniform_tint;
+        Surface {
+            position: self.varying_pos * bc,
+            normal: proj::<_, 3, 4>(&n).normalize(),
+            albedo: Rgba([
+                albedo[0] * tint[0],
+                albedo[1] * tint[1],
+                albedo[2] * tint[2],
+                albedo[3],
+            ]),
+            specular,
+            emission,
+        }
+    }
+}
+
+impl<'a> IIndexedShader for SceneShader<'a> {
+    type Varying = (Vec2f, Vec3f); // uv, view space position
+
+    fn vertex_indexed(&mut self, ivert: usize) -> (Vec4f, (Vec2f, Vec3f)) {
+        let vertex = self.model.vertex(ivert);
+        let gl_vertex = embed::<_, 4, 3>(&vertex.position, 1.0);
+        let view = self.uniform_mv * gl_vertex;
+        (
+            self.uniform_mvp * gl_vertex,
+            (vertex.uv, proj::<_, 3, 4>(&(view / view[3]))),
+        )
+    }
+
+    fn load_varying(&mut self, nthvert: usize, (uv, position): &(Vec2f, Vec3f)) {
+        self.varying_uv.set_col(nthvert, uv);
+        self.varying_pos.set_col(nthvert, position);
+    }
+}
+
+impl<'a> IShader for SceneShader<'a> {
+    fn instance(&mut self, uniforms: &InstanceUniforms) {
+        self.uniform_mv = uniforms.model_view;
+        self.uniform_mvp = uniforms.mvp;
+        self.uniform_n = uniforms.normal;
+        self.uniform_tint = uniforms.tint;
+        self.uniform_material = uniforms.material;
+    }
+
+    fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f {
+        let model = *self.model;
+        self.vertex_by_face(model, iface, nthvert)
+    }
+
+    /// Same sum as `GL::lighting_pass`.
+    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<f32> {
+        let s = self.surface(bc);
+        let v = if self.gl.is_perspective() {
+            (Vec3f::ZERO - s.position).normalize()
+        } else {
+            Vec3f::from([0.0, 0.0, 1.0])
+        };
+        let mut color = s.emission;
+        for light in self.uniform_lights.iter() {
+            let c = light.shade(s.position, s.normal, v, s.albedo, s.specular);
+            for i in 0..3 {
+                color[i] += c[i];
+            }
+        }
+        color[3] = 1.0;
+        color
+    }
+}
+
+impl<'a> IGeometryShader for SceneShader<'a> {
+    fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f {
+        let model = *self.model;
+        self.vertex_by_face(model, iface, nthvert)
+    }
+
+    fn fragment(&mut self, bc: Vec3f) -> Surface {
+        self.surface(bc)
+    }
+}
+
+/// The models of a scene, each loaded once with the instances that draw it.
+fn load(scene: &Scene) -> Vec<(Arc<Model>, Vec<Instance>)> {
+    let mut paths: Vec<&str> = Vec::new();
+    let mut groups: Vec<(Arc<Model>, Vec<Instance>)> = Vec::new();
+    for (mesh, instance) in scene.meshes.iter().zip(scene.instances()) {
+        match paths.iter().position(|p| *p == mesh.path) {
+            Some(i) => groups[i].1.push(instance),
+            None => {
+                paths.push(&mesh.path);
+                groups.push((assets::model(&mesh.path), vec![instance]));
+            }
+        }
+    }
+    groups
+}
+
+/// Geometry pass over all instances, skipping those outside the view.
+fn geometry_pass(gl: &GL, groups: &[(Arc<Model>, Vec<Instance>)], culler: &mut Culler) -> GBuffer {
+    let mut gbuffer = GBuffer::new(gl.width, gl.height, gl.depth_test.clear_value());
+    for (model, instances) in groups {
+        let bounds = model.bbox();
+        let mut shader = SceneShader::new(Rc::new(gl), Rc::new(&**model));
+        for (index, instance) in instances.iter().enumerate() {
+            let uniforms = instance.uniforms(index, gl);
+            if !culler.test_instance(&bounds, &uniforms.model, model.nfaces(), None) {
+                continue;
+            }
+            shader.instance(&uniforms);
+            gl.draw_indexed_deferred(model, &mut shader, &mut gbuffer);
+        }
+    }
+    gbuffer
+}
+
+/// Renders a validated scene, see `Scene::from_json`.
+pub fn scene_render(scene: &Scene) -> Frame {
+    let (width, height) = (scene.settings.width, scene.settings.height);
+    let post = scene.post_process();
+    let groups = load(scene);
+    let bounds = groups
+        .iter()
+        .flat_map(|(model, instances)| {
+            let bbox = model.bbox();
+            instances.iter().map(move |i| i.bbox(&bbox))
+        })
+        .reduce(|a, b| a.union(&b))
+        .unwrap_or(Aabb {
+            min: Vec3f::ZERO,
+            max: Vec3f::ZERO,
+        });
+
+    let light_dir = scene.light_dir();
+    let center = scene.camera.center.map_or(bounds.center(), Vec3f::from);
+    let eye = scene
+        .camera
+        .eye
+        .map_or(center + Vec3f::from([1.0, 1.0, 3.0]), Vec3f::from);
+    let up = Vec3f::from(scene.camera.up.unwrap_or([0.0, 1.0, 0.0]));
+
+    let mut gl = GL::new(light_dir, width, height);
+    gl.viewport(
+        width as i32 / 8,
+        height as i32 / 8,
+        width as i32 * 3 / 4,
+        height as i32 * 3 / 4,
+    );
+    scene
+        .camera_options()
+        .resolve(&bounds, eye, center, up, gl.aspect())
+        .apply(&mut gl);
+    gl.lights = scene.lights(light_dir);
+
+    let mut hdr: HdrImage = ImageBuffer::from_pixel(width, height, Rgba([0.0, 0.0, 0.0, 1.0]));
+    let mut culler = Culler::new(&gl, Culling::default());
+    let (zbuf, gbuffer) = match scene.pipeline {
+        Pipeline::Forward => {
+            let mut zbuf = gl.depth_buffer();
+            for (model, instances) in groups.iter() {
+                let mut shader = SceneShader::new(Rc::new(&gl), Rc::new(&**model));
+                gl.draw_instanced(
+                    model,
+                    instances,
+                    &mut shader,
+                    &mut hdr,
+                    &mut zbuf,
+                    &mut culler,
+                );
+            }
+            // only the occlusion needs the surfaces
+            let gbuffer = post.ssao.is_some().then(|| {
+                let mut culler = Culler::new(&gl, Culling::default());
+                let gbuffer = geometry_pass(&gl, &groups, &mut culler);
+                gl.count_culled(&culler.stats);
+                gbuffer
+            });
+            (zbuf, gbuffer)
+        }
+        Pipeline::Deferred => {
+            let gbuffer = geometry_pass(&gl, &groups, &mut culler);
+            gl.lighting_pass(&gbuffer, &mut hdr);
+            (gbuffer.depth.clone(), Some(gbuffer))
+        }
+    };
+    if let (Some(ssao), Some(gbuffer)) = (&post.ssao, &gbuffer) {
+        let ao = ssao.ambient_occlusion(&gl, gbuffer);
+        ssao.composite(&mut hdr, &ao);
+    }
+
+    gl.count_culled(&culler.stats);
+    Frame::new(&gl, &post, hdr, zbuf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipelines_match() {
+        let json = |pipeline: &str| {
+            format!(
+                r#"{{
+                    "meshes": [
+                        {{"path": "obj/african_head/african_head.obj"}},
+                        {{"path": "obj/african_head/african_head.obj",
+                          "transform": {{"translation": [1.5, 0, -1], "scale": [0.5, 0.5, 0.5]}},
+                          "material": {{"albedo": [1, 0.7, 0.3], "emission": [0, 0, 0.5]}}}}
+                    ],
+                    "lights": [
+                        {{"type": "ambient", "color": [0.05, 0.05, 0.05]}},
+                        {{"type": "point", "position": [0, 1, 1], "color": [2, 2, 2], "range": 3}}
+                    ],
+                    "pipeline": "{}",
+                    "settings": {{"width": 96, "height": 64}}
+                }}"#,
+                pipeline
+            )
+        };
+        let forward = scene_render(&Scene::from_json(&json("forward")).unwrap()).image;
+        let deferred = scene_render(&Scene::from_json(&json("deferred")).unwrap()).image;
+        assert_eq!((forward.width(), forward.height()), (96, 64));
+        assert!(forward.pixels().any(|p| p[0] > 0));
+        assert_eq!(forward, deferred);
+    }
+
+    #[test]
+    fn test_tiny() {
+        let scene = Scene::from_json(
+            r#"{
+                "meshes": [{"path": "obj/african_head/african_head.obj"}],
+                "settings": {"width": 1, "height": 1, "ssao": {}}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(scene_render(&scene).image.dimensions(), (1, 1));
+    }
+}
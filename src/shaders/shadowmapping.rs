@@ -1,31 +1,34 @@
-use std::{cmp, io::Cursor, rc::Rc};
+use std::{cmp, rc::Rc};
 
-use image::{ImageBuffer, Rgba, RgbaImage};
+use image::{ImageBuffer, Rgba};
 
 use crate::{
+    assets,
+    camera::CameraOptions,
     geometry::{embed, proj, Vec2f, Vec3f, Vec4f},
-    gl::{IShader, GL},
-    matrix::Mat,
+    gl::{IIndexedShader, IShader, GL},
+    hdr::{srgb_to_linear, HdrImage},
+    matrix::{Mat3f, Mat4f, Mat_},
     model::Model,
-    util::{maxf, minf, RgbaImageExt},
+    post::PostProcess,
+    shaders::{Frame, RenderOptions},
+    util::maxf,
 };
 
-const DEPTH: f64 = 255.0;
-
 pub struct DepthShader<'a> {
     gl: Rc<&'a GL>,
-    varying_tri: Mat<f64>, // <3, 3>
+    varying_tri: Mat3f,
     model: Rc<&'a Model>,
 }
 
 #[derive(Debug)]
 pub struct Pass2Shader<'a> {
     gl: Rc<&'a GL>,
-    varying_uv: Mat<f64>,       // <2, 3>
-    varying_tri: Mat<f64>,      // <3, 3>
-    uniform_m: Mat<f64>,        // <4, 4>, Projection * ModelView
-    uniform_m_it: Mat<f64>,     // <4, 4>, (Projection * ModelView).invert_transpose()
-    uniform_m_shadow: Mat<f64>, // <4, 4>
+    varying_uv: Mat_<f64, 2, 3>,
+    varying_tri: Mat3f,
+    uniform_m: Mat4f,    // Projection * ModelView
+    uniform_m_it: Mat4f, // (Projection * ModelView).invert_transpose()
+    uniform_m_shadow: Mat4f,
     shadown_buffer: &'a mut Vec<u8>,
     model: Rc<&'a Model>,
 }
@@ -34,7 +37,7 @@ impl<'a> DepthShader<'a> {
     pub fn new(gl: Rc<&'a GL>, model: Rc<&'a Model>) -> Self {
         Self {
             gl,
-            varying_tri: Mat::new(3, 3),
+            varying_tri: Mat3f::new(),
             model,
         }
     }
@@ -43,22 +46,16 @@ impl<'a> DepthShader<'a> {
 impl<'a> IShader for DepthShader<'a> {
     fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f {
         let gl_vertex = embed::<_, 4, 3>(self.model.vert_by(iface, nthvert), 1.0);
-        let r = Vec4f::from_vec(
-            &(&(&self.gl.viewport * &self.gl.projection) * &self.gl.model_view) * &gl_vertex,
-        );
-        self.varying_tri.set_col(nthvert, &(r / r[3]).to_vec());
+        let r = self.gl.viewport * self.gl.projection * self.gl.model_view * gl_vertex;
+        self.varying_tri
+            .set_col(nthvert, &proj::<_, 3, 4>(&(r / r[3])));
         r
     }
 
-    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<u8> {
-        let p = &self.varying_tri * &bc;
-        let intensity = p[2] / DEPTH;
-        Rgba([
-            (255.0 * intensity) as u8,
-            (255.0 * intensity) as u8,
-            (255.0 * intensity) as u8,
-            255,
-        ])
+    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<f32> {
+        let p = self.varying_tri * bc;
+        let intensity = p[2] as f32;
+        Rgba([intensity, intensity, intensity, 1.0])
     }
 }
 
@@ -66,34 +63,41 @@ impl<'a> Pass2Shader<'a> {
     pub fn new(gl: Rc<&'a GL>, model: Rc<&'a Model>, shadow_buffer: &'a mut Vec<u8>) -> Self {
         Self {
             gl,
-            varying_uv: Mat::new(2, 3),
-            varying_tri: Mat::identity(3),
-            uniform_m: Mat::identity(4),
-            uniform_m_it: Mat::identity(4),
-            uniform_m_shadow: Mat::identity(4),
+            varying_uv: Mat_::new(),
+            varying_tri: Mat3f::identity(),
+            uniform_m: Mat4f::identity(),
+            uniform_m_it: Mat4f::identity(),
+            uniform_m_shadow: Mat4f::identity(),
             shadown_buffer: shadow_buffer,
             model,
         }
     }
 }
 
+impl<'a> IIndexedShader for Pass2Shader<'a> {
+    type Varying = (Vec2f, Vec3f); // uv, screen position
+
+    fn vertex_indexed(&mut self, ivert: usize) -> (Vec4f, (Vec2f, Vec3f)) {
+        let vertex = self.model.vertex(ivert);
+        let gl_vertex = embed::<_, 4, 3>(&vertex.position, 1.0);
+        let r = self.gl.viewport * self.gl.projection * self.gl.model_view * gl_vertex;
+        (r, (vertex.uv, proj::<_, 3, 4>(&(r / r[3]))))
+    }
+
+    fn load_varying(&mut self, nthvert: usize, (uv, screen): &(Vec2f, Vec3f)) {
+        self.varying_uv.set_col(nthvert, uv);
+        self.varying_tri.set_col(nthvert, screen);
+    }
+}
+
 impl<'a> IShader for Pass2Shader<'a> {
     fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f {
-        self.varying_uv
-            .set_col(nthvert, &self.model.uv(iface, nthvert).to_vec());
-        let gl_vertex = embed::<_, 4, 3>(self.model.vert_by(iface, nthvert), 1.0);
-        let r = Vec4f::from_vec(
-            &(&(&self.gl.viewport * &self.gl.projection) * &self.gl.model_view) * &gl_vertex,
-        );
-        self.varying_tri.set_col(nthvert, &(r / r[3]).to_vec()); // 4 -> 3 proj
-        r
+        let model = *self.model;
+        self.vertex_by_face(model, iface, nthvert)
     }
 
-    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<u8> {
-        let mut sb_p = Vec4f::from_vec(
-            &self.uniform_m_shadow
-                * &embed::<f64, 4, 3>(&Vec3f::from_vec(&self.varying_tri * &bc), 1.0),
-        );
+    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<f32> {
+        let mut sb_p = self.uniform_m_shadow * embed::<f64, 4, 3>(&(self.varying_tri * bc), 1.0);
         sb_p = sb_p / sb_p[3];
         let idx = (sb_p[0] + sb_p[1] * self.gl.width as f64) as usize;
         let shadow = 0.3
@@ -104,61 +108,63 @@ impl<'a> IShader for Pass2Shader<'a> {
                     0.0
                 };
 
-        let uv = &self.varying_uv * &bc;
-        let _n = &self.uniform_m
-            * &embed::<f64, 4, 3>(&self.model.norm_by(Vec2f::from([uv[0], uv[1]])), 1.0);
-        let n = Vec3f::from_vec(_n).normalize();
-        let _l = &self.uniform_m_it * &embed::<f64, 4, 3>(&self.gl.light_dir, 1.0);
-        let l = Vec3f::from_vec(_l).normalize();
+        let uv = self.varying_uv * bc;
+        let _n = self.uniform_m
+            * embed::<f64, 4, 3>(&self.model.norm_by(Vec2f::from([uv[0], uv[1]])), 1.0);
+        let n = proj::<_, 3, 4>(&_n).normalize();
+        let _l = self.uniform_m_it * embed::<f64, 4, 3>(&self.gl.light_dir, 1.0);
+        let l = proj::<_, 3, 4>(&_l).normalize();
         let r = (n * ((n.dot(&l)) * 2.0) - l).normalize(); // 注意n*l是dot, 不是*
         let spec = f64::powf(maxf(r[2], 0.0), self.model.specular(uv[0], uv[1]));
         let diff = maxf(0.0, n.dot(&l));
         let color = self.model.diffuse(uv[0], uv[1]);
-        let mut rs = [0; 3];
-        for i in 0..3 {
-            rs[i] = minf(
-                20.0 + (color[i] as f64 * shadow * (1.2 * diff + 0.6 * spec)),
-                255.0,
-            ) as u8;
-        }
-        Rgba([rs[0], rs[1], rs[2], 255])
+        let light = (shadow * (1.2 * diff + 0.6 * spec)) as f32;
+        let ambient = srgb_to_linear(20);
+        let glow = self.model.glow(uv[0], uv[1]);
+        Rgba([
+            ambient + color[0] * light + glow[0],
+            ambient + color[1] * light + glow[1],
+            ambient + color[2] * light + glow[2],
+            1.0,
+        ])
     }
 }
 
-pub fn shadow_mapping_render() -> Vec<u8> {
-    const W: u32 = 800;
-    const H: u32 = 800;
-    let model = Model::new("obj/diablo3_pose/diablo3_pose.obj");
+pub fn shadow_mapping_render(
+    post: PostProcess,
+    camera: CameraOptions,
+    options: &RenderOptions,
+) -> Frame {
+    let (width, height) = (options.width, options.height);
+    let model = assets::model(options.model_or("obj/diablo3_pose/diablo3_pose.obj"));
 
-    let light_dir = Vec3f::from([1.0, 1.0, 1.0]).normalize();
+    let light_dir = options.light_dir;
 
     let eye = Vec3f::from([1.0, 1.0, 3.0]);
     let center = Vec3f::from([0.0, 0.0, 0.0]);
     let up = Vec3f::from([0.0, 1.0, 0.0]);
 
-    let mut shadowbuffer: Vec<u8> = vec![0; (W * H) as usize];
+    let mut shadowbuffer: Vec<u8> = vec![0; (width * height) as usize];
 
-    let mut gl = GL::new(light_dir, W, H);
-    gl.lookat(eye, center, up);
+    let mut gl = GL::new(light_dir, width, height);
     gl.viewport(
-        W as i32 / 8,
-        H as i32 / 8,
-        W as i32 * 3 / 4,
-        H as i32 * 3 / 4,
+        width as i32 / 8,
+        height as i32 / 8,
+        width as i32 * 3 / 4,
+        height as i32 * 3 / 4,
     );
-    gl.projection(-1.0 / (eye - center).norm());
+    camera
+        .resolve(&model.bbox(), eye, center, up, gl.aspect())
+        .apply(&mut gl);
 
     let rcgl = Rc::new(&gl);
 
-    let mut shader = DepthShader::new(Rc::clone(&rcgl), Rc::new(&model));
+    let mut shader = DepthShader::new(Rc::clone(&rcgl), Rc::new(&*model));
 
-    println!("ModelView:\n {:}", &gl.model_view);
-    println!("Viewport:\n {:}", &gl.viewport);
-    println!("Projection:\n {:}", &gl.projection);
-    println!(
-        "Z:\n {:}",
-        &(&gl.viewport * &gl.projection) * &gl.model_view
-    );
+    log::debug!("ModelView:\n {:}", &gl.model_view);
+    log::debug!("Viewport:\n {:}", &gl.viewport);
+    log::debug!("Projection:\n {:}", &gl.projection);
+    log::debug!("Z:\n {:}", gl.viewport * gl.projection * gl.model_view);
 
     for i in 0..model.nfaces() {
         let mut screen_coords: [Vec4f; 3] = [Vec4f::from([0.0, 0.0, 0.0, 0.0]); 3];
@@ -169,22 +175,15 @@ pub fn shadow_mapping_render() -> Vec<u8> {
     }
 
     // 2-pass
-    let mut img: RgbaImage = ImageBuffer::from_pixel(W, H, Rgba([0, 0, 0, 255]));
-    let mut zbuf = vec![f64::MIN; (W * H) as usize];
+    let mut hdr: HdrImage = ImageBuffer::from_pixel(width, height, Rgba([0.0, 0.0, 0.0, 1.0]));
+    let mut zbuf = gl.depth_buffer();
 
-    let mut p2shader = Pass2Shader::new(Rc::clone(&rcgl), Rc::new(&model), &mut shadowbuffer);
+    let mut p2shader = Pass2Shader::new(Rc::clone(&rcgl), Rc::new(&*model), &mut shadowbuffer);
 
-    for i in 0..model.nfaces() {
-        let mut screen_coords: [Vec4f; 3] = [Vec4f::from([0.0, 0.0, 0.0, 0.0]); 3];
-        for j in 0..3 {
-            screen_coords[j] = p2shader.vertex(i, j);
-        }
-        gl.triangle(screen_coords, &mut p2shader, &mut img, &mut zbuf);
-    }
+    gl.draw_indexed(&model, &mut p2shader, &mut hdr, &mut zbuf);
 
-    let mut bs: Vec<u8> = Vec::new();
-    img.flip_vertical();
-    img.write_to(&mut Cursor::new(&mut bs), image::ImageOutputFormat::Png)
-        .unwrap();
-    bs
+    if let Some(ssao) = &post.ssao {
+        ssao.apply(&gl, &model, &mut hdr);
+    }
+    Frame::new(&gl, &post, hdr, zbuf)
 }
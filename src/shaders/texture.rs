@@ -1,20 +1,25 @@
-use std::{io::Cursor, rc::Rc};
+use std::rc::Rc;
 
-use image::{ImageBuffer, Rgba, RgbaImage};
+use image::{ImageBuffer, Rgba};
 
 use crate::{
+    assets,
+    camera::CameraOptions,
     geometry::{embed, Vec2f, Vec3f, Vec4f},
-    gl::{IShader, GL},
-    matrix::Mat,
+    gl::{IIndexedShader, IShader, GL},
+    hdr::HdrImage,
+    matrix::Mat_,
     model::Model,
-    util::{maxf, RgbaImageExt},
+    post::PostProcess,
+    shaders::{Frame, RenderOptions},
+    util::maxf,
 };
 
 #[derive(Debug)]
 pub struct TextureShader<'a> {
     gl: Rc<&'a GL>,
     varying_intensity: Vec3f,
-    varying_uv: Mat<f64>, // <2, 3>
+    varying_uv: Mat_<f64, 2, 3>,
     model: Rc<&'a Model>,
 }
 
@@ -23,82 +28,86 @@ impl<'a> TextureShader<'a> {
         Self {
             gl,
             varying_intensity: Vec3f::from([0.0, 0.0, 0.0]),
-            varying_uv: Mat::new(2, 3),
+            varying_uv: Mat_::new(),
             model,
         }
     }
 }
 
+impl<'a> IIndexedShader for TextureShader<'a> {
+    type Varying = (f64, Vec2f); // intensity, uv
+
+    fn vertex_indexed(&mut self, ivert: usize) -> (Vec4f, (f64, Vec2f)) {
+        let vertex = self.model.vertex(ivert);
+        let intensity = maxf(0.0, vertex.normal.dot(&self.gl.light_dir));
+        let gl_vertex = embed::<_, 4, 3>(&vertex.position, 1.0);
+        (
+            self.gl.viewport * self.gl.projection * self.gl.model_view * gl_vertex,
+            (intensity, vertex.uv),
+        )
+    }
+
+    fn load_varying(&mut self, nthvert: usize, (intensity, uv): &(f64, Vec2f)) {
+        self.varying_intensity[nthvert] = *intensity;
+        self.varying_uv.set_col(nthvert, uv);
+    }
+}
+
 impl<'a> IShader for TextureShader<'a> {
     fn vertex(&mut self, iface: usize, nthvert: usize) -> Vec4f {
-        self.varying_intensity[nthvert] =
-            maxf(0.0, self.model.norm(iface, nthvert).dot(&self.gl.light_dir));
-        self.varying_uv
-            .set_col(nthvert, &self.model.uv(iface, nthvert).to_vec());
-        let gl_vertex = embed::<_, 4, 3>(self.model.vert_by(iface, nthvert), 1.0);
-        let r = &(&(&self.gl.viewport * &self.gl.projection) * &self.gl.model_view) * &gl_vertex;
-        Vec4f::from_vec(r)
+        let model = *self.model;
+        self.vertex_by_face(model, iface, nthvert)
     }
 
-    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<u8> {
-        let intensity = self.varying_intensity.dot(&bc);
-        let uv = &self.varying_uv * &bc;
+    fn fragment(&mut self, bc: Vec3f, gl_fragcoord: Vec3f) -> Rgba<f32> {
+        let intensity = self.varying_intensity.dot(&bc) as f32;
+        let uv = self.varying_uv * bc;
         let color = self.model.diffuse(uv[0], uv[1]);
-        let color = Rgba([
-            (color[0] as f64 * intensity) as u8,
-            (color[1] as f64 * intensity) as u8,
-            (color[2] as f64 * intensity) as u8,
-            255,
-        ]);
-        color
+        let glow = self.model.glow(uv[0], uv[1]);
+        Rgba([
+            color[0] * intensity + glow[0],
+            color[1] * intensity + glow[1],
+            color[2] * intensity + glow[2],
+            1.0,
+        ])
     }
 }
 
-pub fn texture_render() -> Vec<u8> {
-    const W: u32 = 800;
-    const H: u32 = 800;
-    let mut img: RgbaImage = ImageBuffer::from_pixel(W, H, Rgba([0, 0, 0, 255]));
-    let model = Model::new("obj/african_head/african_head.obj");
+pub fn texture_render(post: PostProcess, camera: CameraOptions, options: &RenderOptions) -> Frame {
+    let (width, height) = (options.width, options.height);
+    let mut hdr: HdrImage = ImageBuffer::from_pixel(width, height, Rgba([0.0, 0.0, 0.0, 1.0]));
+    let model = assets::model(options.model_or("obj/african_head/african_head.obj"));
 
-    let light_dir = Vec3f::from([1.0, 1.0, 1.0]).normalize();
+    let light_dir = options.light_dir;
 
     let eye = Vec3f::from([1.0, 1.0, 3.0]);
     let center = Vec3f::from([0.0, 0.0, 0.0]);
     let up = Vec3f::from([0.0, 1.0, 0.0]);
-    let mut zbuf = vec![f64::MIN; (W * H) as usize];
 
-    let mut gl = GL::new(light_dir, W, H);
-    gl.lookat(eye, center, up);
+    let mut gl = GL::new(light_dir, width, height);
     gl.viewport(
-        W as i32 / 8,
-        H as i32 / 8,
-        W as i32 * 3 / 4,
-        H as i32 * 3 / 4,
+        width as i32 / 8,
+        height as i32 / 8,
+        width as i32 * 3 / 4,
+        height as i32 * 3 / 4,
     );
-    gl.projection(-1.0 / (eye - center).norm());
+    camera
+        .resolve(&model.bbox(), eye, center, up, gl.aspect())
+        .apply(&mut gl);
+    let mut zbuf = gl.depth_buffer();
 
     let rcgl = Rc::new(&gl);
 
-    let mut shader = TextureShader::new(Rc::clone(&rcgl), Rc::new(&model));
+    let mut shader = TextureShader::new(Rc::clone(&rcgl), Rc::new(&*model));
 
-    println!("ModelView:\n {:}", &gl.model_view);
-    println!("Viewport:\n {:}", &gl.viewport);
-    println!("Projection:\n {:}", &gl.projection);
-    println!(
-        "Z:\n {:}",
-        &(&gl.viewport * &gl.projection) * &gl.model_view
-    );
+    log::debug!("ModelView:\n {:}", &gl.model_view);
+    log::debug!("Viewport:\n {:}", &gl.viewport);
+    log::debug!("Projection:\n {:}", &gl.projection);
+    log::debug!("Z:\n {:}", gl.viewport * gl.projection * gl.model_view);
 
-    for i in 0..model.nfaces() {
-        let mut screen_coords: [Vec4f; 3] = [Vec4f::from([0.0, 0.0, 0.0, 0.0]); 3];
-        for j in 0..3 {
-            screen_coords[j] = shader.vertex(i, j);
-        }
-        gl.triangle(screen_coords, &mut shader, &mut img, &mut zbuf);
+    gl.draw_indexed(&model, &mut shader, &mut hdr, &mut zbuf);
+    if let Some(ssao) = &post.ssao {
+        ssao.apply(&gl, &model, &mut hdr);
     }
-    let mut bs: Vec<u8> = Vec::new();
-    img.flip_vertical();
-    img.write_to(&mut Cursor::new(&mut bs), image::ImageOutputFormat::Png)
-        .unwrap();
-    bs
+    Frame::new(&gl, &post, hdr, zbuf)
 }
@@ -0,0 +1,249 @@
+use std::ops::Mul;
+
+use crate::{geometry::Vec3f, matrix::Mat4f, quat::Quat};
+
+/// Scale, then rotate, then translate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub translation: Vec3f,
+    pub rotation: Quat,
+    pub scale: Vec3f,
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        translation: Vec3f::ZERO,
+        rotation: Quat::IDENTITY,
+        scale: Vec3f {
+            data: [1.0, 1.0, 1.0],
+        },
+    };
+
+    pub fn from_translation(translation: Vec3f) -> Self {
+        Self {
+            translation,
+            ..Self::IDENTITY
+        }
+    }
+
+    pub fn from_rotation(rotation: Quat) -> Self {
+        Self {
+            rotation,
+            ..Self::IDENTITY
+        }
+    }
+
+    pub fn from_scale(scale: f64) -> Self {
+        Self {
+            scale: Vec3f::from([scale; 3]),
+            ..Self::IDENTITY
+        }
+    }
+
+    pub fn transform_point(&self, p: Vec3f) -> Vec3f {
+        self.rotation.rotate(p * self.scale) + self.translation
+    }
+
+    pub fn transform_vector(&self, v: Vec3f) -> Vec3f {
+        self.rotation.rotate(v * self.scale)
+    }
+
+    /// Homogeneous 4x4 matrix, `T * R * S`.
+    pub fn to_mat(self) -> Mat4f {
+        let mut m = self.rotation.to_mat();
+        for i in 0..3 {
+            for j in 0..3 {
+                m[i][j] *= self.scale[j];
+            }
+            m[i][3] = self.translation[i];
+        }
+        m
+    }
+
+    /// Exact for uniform scale, a non-uniform scale under a rotation has no TRS inverse.
+    pub fn inverse(&self) -> Self {
+        let rotation = self.rotation.inverse();
+        let scale = Vec3f::from([1.0; 3]) / self.scale;
+        Self {
+            translation: rotation.rotate(self.translation * -1.0) * scale,
+            rotation,
+            scale,
+        }
+    }
+
+    /// Per component interpolation, for keyframes.
+    pub fn lerp(&self, other: &Transform, t: f64) -> Self {
+        Self {
+            translation: self.translation * (1.0 - t) + other.translation * t,
+            rotation: self.rotation.slerp(&other.rotation, t),
+            scale: self.scale * (1.0 - t) + other.scale * t,
+        }
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::IDENTITY
+    }
+}
+
+/// `parent * child` applies `child` first. Like `inverse`, the scale part is only exact when
+/// the parent's scale is uniform; `Hierarchy` composes matrices to stay exact.
+impl Mul for Transform {
+    type Output = Transform;
+
+    fn mul(self, rhs: Transform) -> Self::Output {
+        Transform {
+            translation: self.transform_point(rhs.translation),
+            rotation: (self.rotation * rhs.rotation).normalize(),
+            scale: self.scale * rhs.scale,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub name: String,
+    /// Relative to the parent, or to the world for roots
+    pub local: Transform,
+    pub parent: Option<NodeId>,
+}
+
+/// Tree of transforms. Nodes are stored in insertion order and a parent always exists
+/// before its children, so world matrices resolve in one pass.
+#[derive(Debug, Clone, Default)]
+pub struct Hierarchy {
+    nodes: Vec<Node>,
+}
+
+impl Hierarchy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, name: &str, local: Transform, parent: Option<NodeId>) -> NodeId {
+        self.nodes.push(Node {
+            name: name.to_string(),
+            local,
+            parent,
+        });
+        NodeId(self.nodes.len() - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0]
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        &mut self.nodes[id.0]
+    }
+
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.nodes.iter().position(|n| n.name == name).map(NodeId)
+    }
+
+    pub fn children(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        (id.0 + 1..self.nodes.len())
+            .filter(move |&i| self.nodes[i].parent == Some(id))
+            .map(NodeId)
+    }
+
+    /// Local to world matrix of a single node.
+    pub fn world_matrix(&self, id: NodeId) -> Mat4f {
+        let node = self.node(id);
+        match node.parent {
+            Some(parent) => self.world_matrix(parent) * node.local.to_mat(),
+            None => node.local.to_mat(),
+        }
+    }
+
+    /// Local to world matrices of all nodes, indexed like the nodes.
+    pub fn world_matrices(&self) -> Vec<Mat4f> {
+        let mut world: Vec<Mat4f> = Vec::with_capacity(self.nodes.len());
+        for node in self.nodes.iter() {
+            let m = match node.parent {
+                Some(parent) => world[parent.0] * node.local.to_mat(),
+                None => node.local.to_mat(),
+            };
+            world.push(m);
+        }
+        world
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::geometry::{embed, Vec4f};
+
+    fn assert_close(a: Vec3f, b: Vec3f) {
+        assert!((a - b).norm() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    fn apply(m: &Mat4f, p: Vec3f) -> Vec3f {
+        let v = m * &embed::<_, 4, 3>(&p, 1.0);
+        Vec3f::from([v[0], v[1], v[2]])
+    }
+
+    fn sample() -> Transform {
+        Transform {
+            translation: Vec3f::from([1.0, 2.0, 3.0]),
+            rotation: Quat::from_euler(0.4, 1.1, -0.3),
+            scale: Vec3f::from([2.0; 3]),
+        }
+    }
+
+    #[test]
+    fn test_transform() {
+        let t = sample();
+        let p = Vec3f::from([0.5, -1.0, 2.0]);
+        assert_close(apply(&t.to_mat(), p), t.transform_point(p));
+        assert_close(t.inverse().transform_point(t.transform_point(p)), p);
+
+        let other = Transform::from_rotation(Quat::from_euler(PI / 3.0, 0.0, 0.2));
+        assert_close(
+            (t * other).transform_point(p),
+            t.transform_point(other.transform_point(p)),
+        );
+        assert_eq!(t.lerp(&other, 0.0).translation, t.translation);
+    }
+
+    #[test]
+    fn test_hierarchy() {
+        let mut scene = Hierarchy::new();
+        let root = scene.add("root", Transform::from_scale(2.0), None);
+        let arm = scene.add(
+            "arm",
+            Transform::from_rotation(Quat::from_axis_angle(
+                Vec3f::from([0.0, 0.0, 1.0]),
+                PI / 2.0,
+            )),
+            Some(root),
+        );
+        let hand = scene.add(
+            "hand",
+            Transform::from_translation(Vec3f::from([1.0, 0.0, 0.0])),
+            Some(arm),
+        );
+        assert_eq!(scene.find("hand"), Some(hand));
+        assert_eq!(scene.children(root).collect::<Vec<_>>(), vec![arm]);
+
+        // the hand sits one unit along the arm, rotated to +y and scaled by the root
+        let world = scene.world_matrices();
+        assert_close(apply(&world[2], Vec3f::ZERO), Vec3f::from([0.0, 2.0, 0.0]));
+        assert_eq!(world[2], scene.world_matrix(hand));
+    }
+}
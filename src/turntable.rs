@@ -0,0 +1,238 @@
+use std::f64::consts::PI;
+
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    imageops, Delay, Frame, RgbaImage,
+};
+
+use crate::{
+    camera::CameraOptions,
+    post::PostProcess,
+    shaders::{RenderFn, RenderOptions},
+    util::RgbaImageExt,
+};
+
+/// Largest `frames * width * height` rendered by one turntable, the frames are all held in
+/// memory and a strip copies them once more.
+pub const MAX_PIXELS: u64 = 80_000_000;
+
+/// How the frames of a turntable are packed into one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gif,
+    Apng,
+    /// All frames side by side in a single PNG
+    Strip,
+}
+
+impl Encoding {
+    /// The names of `from_name`.
+    pub const NAMES: [&'static str; 3] = ["gif", "apng", "strip"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gif" => Some(Encoding::Gif),
+            "apng" => Some(Encoding::Apng),
+            "strip" => Some(Encoding::Strip),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Gif => "image/gif",
+            Encoding::Apng => "image/apng",
+            Encoding::Strip => "image/png",
+        }
+    }
+}
+
+/// A full turn around the model in `frames` equal azimuth steps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Turntable {
+    pub frames: usize,
+    /// Display time of each frame in milliseconds
+    pub delay_ms: u32,
+    /// Used when the camera options do not set an elevation, in radians
+    pub elevation: f64,
+}
+
+impl Default for Turntable {
+    fn default() -> Self {
+        Self {
+            frames: 24,
+            delay_ms: 80,
+            elevation: f64::to_radians(15.0),
+        }
+    }
+}
+
+impl Turntable {
+    /// Camera options of frame `i`, the turn starts at the requested azimuth.
+    pub fn frame_camera(&self, camera: CameraOptions, i: usize) -> CameraOptions {
+        self.camera_at(camera, 2.0 * PI * i as f64 / self.frames as f64)
+    }
+
+    /// Camera options turned by `angle` radians from the requested azimuth.
+    pub fn camera_at(&self, camera: CameraOptions, angle: f64) -> CameraOptions {
+        CameraOptions {
+            azimuth: Some(camera.azimuth.unwrap_or(0.0) + angle),
+            elevation: Some(camera.elevation.unwrap_or(self.elevation)),
+            ..camera
+        }
+    }
+
+    /// Whether the frames of `options` fit in `MAX_PIXELS`.
+    pub fn check(&self, options: &RenderOptions) -> Result<(), String> {
+        let pixels = self.frames as u64 * options.width as u64 * options.height as u64;
+        if pixels > MAX_PIXELS {
+            return Err(format!(
+                "{} frames of {}x{} are {} pixels, at most {} are rendered",
+                self.frames, options.width, options.height, pixels, MAX_PIXELS
+            ));
+        }
+        Ok(())
+    }
+
+    /// The frames one after the other, on the thread of the caller.
+    pub fn render(
+        &self,
+        render: RenderFn,
+        post: PostProcess,
+        camera: CameraOptions,
+        options: &RenderOptions,
+    ) -> Vec<RgbaImage> {
+        (0..self.frames)
+            .map(|i| render(post, self.frame_camera(camera, i), options).image)
+            .collect()
+    }
+
+    pub fn encode(&self, frames: Vec<RgbaImage>, encoding: Encoding) -> Vec<u8> {
+        let mut bs: Vec<u8> = Vec::new();
+        match encoding {
+            Encoding::Strip => {
+                let (w, h) = frames[0].dimensions();
+                let mut strip = RgbaImage::new(w * frames.len() as u32, h);
+                for (i, img) in frames.iter().enumerate() {
+                    imageops::replace(&mut strip, img, (w * i as u32) as i64, 0);
+                }
+                bs = strip.to_png();
+            }
+            Encoding::Gif => {
+                let mut encoder = GifEncoder::new_with_speed(&mut bs, 10);
+                encoder.set_repeat(Repeat::Infinite).unwrap();
+                let delay = Delay::from_numer_denom_ms(self.delay_ms, 1);
+                encoder
+                    .encode_frames(
+                        frames
+                            .into_iter()
+                            .map(|img| Frame::from_parts(img, 0, 0, delay)),
+                    )
+                    .unwrap();
+            }
+            Encoding::Apng => {
+                let (w, h) = frames[0].dimensions();
+                let mut encoder = png::Encoder::new(&mut bs, w, h);
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_animated(frames.len() as u32, 0).unwrap();
+                let delay = u16::try_from(self.delay_ms).unwrap_or(u16::MAX);
+                encoder.set_frame_delay(delay, 1000).unwrap();
+                let mut writer = encoder.write_header().unwrap();
+                for img in frames.iter() {
+                    writer.write_image_data(img.as_raw()).unwrap();
+                }
+                writer.finish().unwrap();
+            }
+        }
+        bs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames() -> Vec<RgbaImage> {
+        (0..3u8)
+            .map(|i| RgbaImage::from_pixel(4, 2, image::Rgba([i * 100, 0, 0, 255])))
+            .collect()
+    }
+
+    #[test]
+    fn test_frame_camera() {
+        let turntable = Turntable {
+            frames: 4,
+            ..Turntable::default()
+        };
+        let camera = turntable.frame_camera(CameraOptions::default(), 1);
+        assert_eq!(camera.azimuth, Some(PI / 2.0));
+        assert_eq!(camera.elevation, Some(turntable.elevation));
+    }
+
+    #[test]
+    fn test_render() {
+        let turntable = Turntable {
+            frames: 2,
+            ..Turntable::default()
+        };
+        let options = RenderOptions {
+            width: 1,
+            height: 1,
+            ..RenderOptions::default()
+        };
+        let render = crate::shaders::by_name("texture").unwrap();
+        let frames = turntable.render(
+            render,
+            PostProcess::default(),
+            CameraOptions::default(),
+            &options,
+        );
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|f| f.dimensions() == (1, 1)));
+        assert!(Encoding::NAMES
+            .iter()
+            .all(|n| Encoding::from_name(n).is_some()));
+    }
+
+    #[test]
+    fn test_encode() {
+        let turntable = Turntable::default();
+        let gif = turntable.encode(frames(), Encoding::Gif);
+        assert_eq!(&gif[..6], b"GIF89a");
+
+        let apng = turntable.encode(frames(), Encoding::Apng);
+        let decoder = png::Decoder::new(apng.as_slice());
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().animation_control().unwrap().num_frames, 3);
+
+        let strip = image::load_from_memory(&turntable.encode(frames(), Encoding::Strip)).unwrap();
+        assert_eq!((strip.width(), strip.height()), (12, 2));
+        // APNG delays are 16 bit
+        let slow = Turntable {
+            delay_ms: 70000,
+            ..turntable
+        };
+        let apng = slow.encode(frames(), Encoding::Apng);
+        let mut reader = png::Decoder::new(apng.as_slice()).read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut buf).unwrap();
+        let control = reader.info().frame_control().unwrap();
+        assert_eq!((control.delay_num, control.delay_den), (u16::MAX, 1000));
+    }
+
+    #[test]
+    fn test_check() {
+        let mut turntable = Turntable {
+            frames: 120,
+            ..Turntable::default()
+        };
+        let mut options = RenderOptions::default();
+        assert!(turntable.check(&options).is_ok());
+        options.width = 4096;
+        options.height = 4096;
+        assert!(turntable.check(&options).is_err());
+        turntable.frames = 4;
+        assert!(turntable.check(&options).is_ok());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fmt::Debug, ops::Mul};
+use std::{fmt::Debug, io::Cursor, ops::Mul};
 
 use image::{Rgb, Rgba, RgbaImage};
 use num_traits::{Float, NumCast, NumOps, One, Zero};
@@ -39,6 +39,7 @@ pub fn splitext(filename: &str) -> (String, String) {
 pub trait RgbaImageExt {
     fn flip_horizontal(&mut self);
     fn flip_vertical(&mut self);
+    fn to_png(&self) -> Vec<u8>;
 }
 
 impl RgbaImageExt for RgbaImage {
@@ -75,6 +76,13 @@ impl RgbaImageExt for RgbaImage {
             }
         }
     }
+
+    fn to_png(&self) -> Vec<u8> {
+        let mut bs: Vec<u8> = Vec::new();
+        self.write_to(&mut Cursor::new(&mut bs), image::ImageOutputFormat::Png)
+            .unwrap();
+        bs
+    }
 }
 
 #[cfg(test)]
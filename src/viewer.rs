@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+
+use image::{GenericImageView, RgbaImage};
+use serde_json::Value;
+
+use crate::{
+    params::{camera_options, output, post_process, render_options},
+    shaders,
+};
+
+/// Edge of the tiles compared between two full frames.
+pub const TILE: u32 = 64;
+/// Previews are rendered this many times smaller in each direction.
+pub const PREVIEW_SCALE: u32 = 4;
+/// Smallest preview edge, smaller frames are sent at their full size only.
+pub const MIN_PREVIEW: u32 = 16;
+/// Largest width and height of a frame, the header holds them in a `u16`.
+pub const MAX_SIZE: u32 = u16::MAX as u32;
+
+/// A cheap preview sent right after a change, or the frame at the requested size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Preview = 0,
+    Full = 1,
+}
+
+/// One connection of `/viewer/ws`. The settings are the query parameters of `/shaders/:name`
+/// plus `shader`, changed by the JSON messages of the client, see `update`.
+///
+/// Frames are binary messages, little endian: `kind: u8` (0 key frame, 1 delta),
+/// `level: u8` (see `Level`), `width: u16` and `height: u16` of the full frame, then
+/// - key frame: the encoded image, a preview is stretched to `width` x `height`
+/// - delta: `count: u16` tiles of `x: u16`, `y: u16`, `len: u32` and `len` bytes of the
+///   encoded tile, drawn at (x, y) over the previous full frame
+#[derive(Debug, Clone)]
+pub struct Viewer {
+    pub params: HashMap<String, String>,
+    /// Last full frame sent, the base of the next delta
+    last: Option<RgbaImage>,
+}
+
+impl Default for Viewer {
+    fn default() -> Self {
+        let params = [
+            ("shader", "texture"),
+            ("width", "640"),
+            ("height", "480"),
+            ("format", "jpeg"),
+            ("quality", "80"),
+        ];
+        Self {
+            params: params
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            last: None,
+        }
+    }
+}
+
+impl Viewer {
+    /// Merges a message like `{"shader": "texture", "azimuth": 30, "bloom": true}` into the
+    /// settings, `null` removes one. An invalid message changes nothing.
+    pub fn update(&mut self, message: &str) -> Result<(), String> {
+        let Value::Object(map) = serde_json::from_str(message).map_err(|e| e.to_string())? else {
+            return Err("expected a JSON object".to_string());
+        };
+        let mut params = self.params.clone();
+        for (key, value) in map {
+            let value = match value {
+                Value::Null => {
+                    params.remove(&key);
+                    continue;
+                }
+                Value::String(s) => s,
+                Value::Number(n) => n.to_string(),
+                Value::Bool(b) => (if b { "1" } else { "0" }).to_string(),
+                _ => return Err(format!("{}: expected a string, number or boolean", key)),
+            };
+            params.insert(key, value);
+        }
+
+        let shader = params.get("shader").map_or("", String::as_str);
+        if shaders::by_name(shader).is_none() {
+            return Err(format!(
+                "shader: unknown shader `{}`, expected one of {}",
+                shader,
+                shaders::names().join(", ")
+            ));
+        }
+        camera_options(&params)?;
+        post_process(&params)?;
+        if output(&params, None)
+            .map_err(|err| format!("format: {}", err))?
+            .format
+            .is_raw()
+        {
+            return Err("format: only images can be streamed".to_string());
+        }
+        self.params = params;
+        Ok(())
+    }
+
+    /// The levels to render after a change, the preview is skipped with `progressive=0` and
+    /// for frames too small to have one, see `MIN_PREVIEW`.
+    pub fn levels(&self) -> &'static [Level] {
+        let options = options(&self.params);
+        if options.width.min(options.height) < MIN_PREVIEW * PREVIEW_SCALE {
+            return &[Level::Full];
+        }
+        match self.params.get("progressive").map(String::as_str) {
+            Some("0" | "false") => &[Level::Full],
+            _ => &[Level::Preview, Level::Full],
+        }
+    }
+
+    /// The frame message for `img`, a delta against the last full frame when at most half
+    /// of the tiles changed.
+    pub fn encode(&mut self, img: RgbaImage, level: Level) -> Vec<u8> {
+        let output = output(&self.params, None).unwrap_or_default();
+        let options = options(&self.params);
+        let (width, height) = match level {
+            Level::Preview => (options.width, options.height),
+            Level::Full => img.dimensions(),
+        };
+        let mut message = vec![0, level as u8];
+        message.extend_from_slice(&(width as u16).to_le_bytes());
+        message.extend_from_slice(&(height as u16).to_le_bytes());
+        if level == Level::Preview {
+            message.extend(output.encode_image(&img).unwrap());
+            return message;
+        }
+
+        match self
+            .last
+            .as_ref()
+            .and_then(|last| changed_tiles(last, &img))
+        {
+            Some(tiles) => {
+                message[0] = 1;
+                message.extend_from_slice(&(tiles.len() as u16).to_le_bytes());
+                for (x, y) in tiles {
+                    let (w, h) = (TILE.min(width - x), TILE.min(height - y));
+                    let tile = output
+                        .encode_image(&img.view(x, y, w, h).to_image())
+                        .unwrap();
+                    message.extend_from_slice(&(x as u16).to_le_bytes());
+                    message.extend_from_slice(&(y as u16).to_le_bytes());
+                    message.extend_from_slice(&(tile.len() as u32).to_le_bytes());
+                    message.extend(tile);
+                }
+            }
+            None => message.extend(output.encode_image(&img).unwrap()),
+        }
+        self.last = Some(img);
+        message
+    }
+}
+
+/// Top left corners of the tiles that differ, `None` when the sizes differ or more than half
+/// of the tiles changed and a key frame is smaller, or too many for the `u16` count.
+fn changed_tiles(last: &RgbaImage, img: &RgbaImage) -> Option<Vec<(u32, u32)>> {
+    if last.dimensions() != img.dimensions() {
+        return None;
+    }
+    let (width, height) = img.dimensions();
+    let mut tiles = Vec::new();
+    let mut total = 0;
+    for y in (0..height).step_by(TILE as usize) {
+        for x in (0..width).step_by(TILE as usize) {
+            total += 1;
+            let (w, h) = (TILE.min(width - x), TILE.min(height - y));
+            let differs = (y..y + h).any(|y| {
+                let start = ((x + y * width) * 4) as usize;
+                let row = start..start + (w * 4) as usize;
+                last.as_raw()[row.clone()] != img.as_raw()[row]
+            });
+            if differs {
+                tiles.push((x, y));
+            }
+        }
+    }
+    (tiles.len() * 2 <= total && tiles.len() <= u16::MAX as usize).then_some(tiles)
+}
+
+/// The render options of the settings, at most `MAX_SIZE` in each direction.
+fn options(params: &HashMap<String, String>) -> shaders::RenderOptions {
+    let mut options = render_options(params, false);
+    options.width = options.width.min(MAX_SIZE);
+    options.height = options.height.min(MAX_SIZE);
+    options
+}
+
+/// Renders the settings of a `Viewer`, checked by `update`.
+pub fn render(params: &HashMap<String, String>, level: Level) -> RgbaImage {
+    let render = shaders::by_name(&params["shader"]).unwrap();
+    let mut options = options(params);
+    if level == Level::Preview {
+        options.width = (options.width / PREVIEW_SCALE).max(1);
+        options.height = (options.height / PREVIEW_SCALE).max(1);
+    }
+    let camera = camera_options(params).unwrap_or_default();
+    let post = post_process(params).unwrap_or_default();
+    render(post, camera, &options).image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::imageops;
+
+    #[test]
+    fn test_update() {
+        let mut viewer = Viewer::default();
+        viewer
+            .update(r#"{"shader": "gouraud", "azimuth": 30.5, "bloom": true, "quality": null}"#)
+            .unwrap();
+        assert_eq!(viewer.params["shader"], "gouraud");
+        assert_eq!(viewer.params["azimuth"], "30.5");
+        assert_eq!(viewer.params["bloom"], "1");
+        assert!(!viewer.params.contains_key("quality"));
+
+        let params = viewer.params.clone();
+        assert!(viewer.update(r#"{"shader": "raytrace"}"#).is_err());
+        assert!(viewer.update(r#"{"format": "exr"}"#).is_err());
+        assert!(viewer.update(r#"{"view": "side"}"#).is_err());
+        assert!(viewer.update(r#"{"tonemap": "sepia"}"#).is_err());
+        assert!(viewer.update(r#"{"azimuth": [1, 2]}"#).is_err());
+        assert!(viewer.update("[]").is_err());
+        assert_eq!(viewer.params, params);
+        assert_eq!(viewer.levels(), [Level::Preview, Level::Full]);
+        viewer.update(r#"{"progressive": false}"#).unwrap();
+        assert_eq!(viewer.levels(), [Level::Full]);
+        viewer
+            .update(r#"{"progressive": true, "width": 7, "height": 480}"#)
+            .unwrap();
+        assert_eq!(viewer.levels(), [Level::Full]);
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut viewer = Viewer::default();
+        viewer
+            .update(r#"{"width": 160, "height": 96, "format": "png"}"#)
+            .unwrap();
+        let header = |message: &[u8]| {
+            let u16_at = |i: usize| u16::from_le_bytes([message[i], message[i + 1]]);
+            (message[0], message[1], u16_at(2), u16_at(4))
+        };
+
+        let preview = render(&viewer.params, Level::Preview);
+        assert_eq!(preview.dimensions(), (40, 24));
+        let message = viewer.encode(preview, Level::Preview);
+        assert_eq!(header(&message), (0, 0, 160, 96));
+
+        let full = render(&viewer.params, Level::Full);
+        let message = viewer.encode(full.clone(), Level::Full);
+        assert_eq!(header(&message), (0, 1, 160, 96));
+        let decoded = image::load_from_memory(&message[6..]).unwrap().to_rgba8();
+        assert_eq!(decoded, full);
+
+        // one changed pixel resends its tile only, drawn over the last frame it restores
+        let mut changed = full.clone();
+        changed.put_pixel(100, 70, image::Rgba([255, 0, 0, 255]));
+        let message = viewer.encode(changed.clone(), Level::Full);
+        assert_eq!(header(&message), (1, 1, 160, 96));
+        assert_eq!(u16::from_le_bytes([message[6], message[7]]), 1);
+        let (x, y) = (
+            u16::from_le_bytes([message[8], message[9]]),
+            u16::from_le_bytes([message[10], message[11]]),
+        );
+        assert_eq!((x, y), (64, 64));
+        let len = u32::from_le_bytes(message[12..16].try_into().unwrap()) as usize;
+        let tile = image::load_from_memory(&message[16..16 + len]).unwrap();
+        let mut restored = full;
+        imageops::replace(&mut restored, &tile.to_rgba8(), x as i64, y as i64);
+        assert_eq!(restored, changed);
+
+        // a new view changes most tiles
+        viewer.update(r#"{"azimuth": 90}"#).unwrap();
+        let message = viewer.encode(render(&viewer.params, Level::Full), Level::Full);
+        assert_eq!(header(&message).0, 0);
+    }
+}